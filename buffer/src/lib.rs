@@ -238,6 +238,20 @@ impl <T: AsMut<[u8]> + AsRef<[u8]>> Buffer<T> {
         }
     }
 
+    /// Keeps the first `len` readable bytes and discards the bytes written after them
+    ///
+    /// # Errors
+    ///
+    /// [`BufferError::NoData`] if `len > self.remaining_len()`
+    pub fn truncate(&mut self, len: usize) -> Result<(), BufferError> {
+        if self.remaining_len() >= len {
+            self.write_position = self.read_position + len;
+            Ok(())
+        } else {
+            Err(BufferError::NoData)
+        }
+    }
+
     /// Appends the provided slice to the buffer a a whole
     /// 
     /// # Error
@@ -508,4 +522,20 @@ mod tests {
         let res = buf.skip(5);
         assert_eq!(res, Err(BufferError::NoData));
     }
+
+    #[test]
+    fn test_truncate() {
+
+        let mut b = [0u8; 8];
+        let mut buf = Buffer::new(&mut b);
+
+        buf.write_base(&[1, 2, 3, 4, 5]).unwrap();
+        buf.skip(1).unwrap();
+
+        buf.truncate(2).unwrap();
+        assert_eq!(buf.data(), &[2, 3]);
+
+        let res = buf.truncate(3);
+        assert_eq!(res, Err(BufferError::NoData));
+    }
 }
\ No newline at end of file
@@ -1,12 +1,13 @@
 
 
-use core::mem;
+use core::{mem, net::IpAddr, pin::pin};
 
-use embassy_futures::join::join;
-use embassy_net::{dns::{DnsQueryType, DnsSocket}, tcp::TcpSocket, IpAddress, IpEndpoint, Stack};
-use embedded_io_async::{ErrorKind, ErrorType, Read, ReadReady, Write, WriteReady};
+use embassy_futures::{join::join, select::{select, Either}};
+use embassy_net::{dns::{DnsQueryType, DnsSocket}, tcp::{ConnectError, TcpSocket}, IpAddress, IpEndpoint, Stack};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_io_async::{Error as _, ErrorKind, ErrorType, Read, ReadReady, Write, WriteReady};
 
-use crate::{NetworkConnection, NetworkError, TryRead, TryWrite};
+use crate::{EndpointOrder, NetworkConnection, NetworkError, TryRead, TryWrite, END_OF_STREAM};
 
 /// struct that contains the rx buffer and tx buffer for the tcp connection
 /// 
@@ -98,70 +99,248 @@ impl <'a> EmbassyConnectionResources<'a> {
     }
 }
 
+/// Default time [`EmbassyNetworkConnection::connect`] waits for the link and the network config
+pub const DEFAULT_LINK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Settings of the tcp connection of [`EmbassyNetworkConnection`]
+///
+/// embassy-net has no setting for Nagle's algorithm, it is always enabled.
+#[derive(Debug, Clone, Copy)]
+pub struct EmbassyConnectionOptions {
+    /// How long connect waits for the link to come up and the network to be configured
+    /// (e.g. by DHCP) before failing with [`NetworkError::LinkDown`]
+    pub link_timeout: Duration,
+
+    /// How long to wait for the tcp handshake, `None` waits until the stack gives up
+    pub connect_timeout: Option<Duration>,
+
+    /// Inactivity timeout of the socket after which reads and writes fail
+    pub timeout: Option<Duration>,
+
+    /// Interval of tcp keep-alive packets, `None` disables them
+    pub keep_alive: Option<Duration>,
+
+    /// Hop limit (TTL) of the sent packets, `None` uses the default of the stack
+    pub hop_limit: Option<u8>,
+
+    /// How long the resolved addresses of a host are used for reconnects before it is resolved again
+    pub dns_cache_ttl: Duration,
+
+    /// Order in which the broker endpoints are tried
+    pub endpoint_order: EndpointOrder,
+
+    /// How long an IPv6 connect may take before IPv4 is tried in parallel,
+    /// if the connection has race resources
+    pub happy_eyeballs_delay: Duration
+}
+
+impl Default for EmbassyConnectionOptions {
+    fn default() -> Self {
+        Self {
+            link_timeout: DEFAULT_LINK_TIMEOUT,
+            connect_timeout: Some(Duration::from_secs(10)),
+            timeout: None,
+            keep_alive: None,
+            hop_limit: None,
+            dns_cache_ttl: Duration::from_secs(300),
+            endpoint_order: EndpointOrder::InOrder,
+            happy_eyeballs_delay: Duration::from_millis(250)
+        }
+    }
+}
+
+impl EmbassyConnectionOptions {
+    pub fn with_link_timeout(mut self, link_timeout: Duration) -> Self {
+        self.link_timeout = link_timeout;
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, connect_timeout: Option<Duration>) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_keep_alive(mut self, keep_alive: Option<Duration>) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn with_hop_limit(mut self, hop_limit: Option<u8>) -> Self {
+        self.hop_limit = hop_limit;
+        self
+    }
+
+    pub fn with_dns_cache_ttl(mut self, dns_cache_ttl: Duration) -> Self {
+        self.dns_cache_ttl = dns_cache_ttl;
+        self
+    }
+
+    pub fn with_endpoint_order(mut self, endpoint_order: EndpointOrder) -> Self {
+        self.endpoint_order = endpoint_order;
+        self
+    }
+
+    pub fn with_happy_eyeballs_delay(mut self, happy_eyeballs_delay: Duration) -> Self {
+        self.happy_eyeballs_delay = happy_eyeballs_delay;
+        self
+    }
+}
+
+/// Maximum number of broker endpoints of an [`EmbassyNetworkConnection`]
+pub const MAX_ENDPOINTS: usize = 4;
+
+/// Maximum number of resolved addresses tried per endpoint
+pub const MAX_ADDRESSES: usize = 8;
+
+type Addresses = heapless::Vec<IpAddress, MAX_ADDRESSES>;
+
+struct Endpoint<'a> {
+    host: &'a str,
+    port: u16,
+
+    /// Resolved addresses of the host and when they expire
+    cached: Option<(Addresses, Instant)>
+}
+
+impl <'a> Endpoint<'a> {
+    /// The cached addresses if they have not expired at `now`
+    fn cached_addresses(&self, now: Instant) -> Option<&Addresses> {
+        self.cached.as_ref()
+            .filter(|(_, expires)| now < *expires)
+            .map(|(addrs, _)| addrs)
+    }
+}
+
+/// The buffers a socket is created with
+#[derive(Clone, Copy)]
+enum SocketBuffers {
+    Main,
+    Race
+}
+
 pub struct EmbassyNetworkConnection<'a> {
     socket: Option<TcpSocket<'a>>,
     stack: Stack<'a>,
     resources: EmbassyConnectionResources<'a>,
-    host: &'a str,
-    port: u16
+    race_resources: Option<EmbassyConnectionResources<'a>>,
+    endpoints: heapless::Vec<Endpoint<'a>, MAX_ENDPOINTS>,
+    options: EmbassyConnectionOptions,
+
+    next_endpoint: usize,
+    connected: Option<(usize, IpEndpoint)>
 }
 
 impl <'a> EmbassyNetworkConnection<'a> {
 
     pub fn new(host: &'a str, port: u16, stack: Stack<'a>, resources: EmbassyConnectionResources<'a>) -> Self {
+        let mut endpoints = heapless::Vec::new();
+        // Cannot fail, MAX_ENDPOINTS > 0
+        let _ = endpoints.push(Endpoint { host, port, cached: None });
+
         Self {
             socket: None,
             stack, resources, 
-            host, port
+            race_resources: None,
+            endpoints,
+            options: EmbassyConnectionOptions::default(),
+            next_endpoint: 0,
+            connected: None
+        }
+    }
+
+    /// Adds a broker endpoint which is tried if the previous ones fail.
+    /// At most [`MAX_ENDPOINTS`] are used, further ones are ignored.
+    pub fn with_endpoint(mut self, host: &'a str, port: u16) -> Self {
+        if self.endpoints.push(Endpoint { host, port, cached: None }).is_err() {
+            warn!("ignoring endpoint {}:{}, too many endpoints", host, port);
+        }
+        self
+    }
+
+    /// Second rx and tx buffer to race IPv6 and IPv4 connects (happy eyeballs).
+    /// Without them the resolved addresses are tried one after another.
+    pub fn with_race_resources(mut self, race_resources: EmbassyConnectionResources<'a>) -> Self {
+        self.race_resources = Some(race_resources);
+        self
+    }
+
+    pub fn with_options(mut self, options: EmbassyConnectionOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Sets [`EmbassyConnectionOptions::link_timeout`]
+    pub fn with_link_timeout(mut self, link_timeout: Duration) -> Self {
+        self.options.link_timeout = link_timeout;
+        self
+    }
+
+    /// Host and address of the current connection
+    pub fn connected_endpoint(&self) -> Option<(&'a str, IpEndpoint)> {
+        self.connected.map(|(index, endpoint)| (self.endpoints[index].host, endpoint))
+    }
+
+    async fn wait_link_up(&self) -> Result<(), NetworkError> {
+        if self.stack.is_link_up() && self.stack.is_config_up() {
+            return Ok(());
         }
+
+        info!("waiting for network link and config");
+        let wait = async {
+            self.stack.wait_link_up().await;
+            self.stack.wait_config_up().await;
+        };
+
+        with_timeout(self.options.link_timeout, wait).await
+            .map_err(|_| {
+                warn!("network link or config not up after {} ms", self.options.link_timeout.as_millis());
+                NetworkError::LinkDown
+            })
     }
 
-    async fn dns_resolve(&self, hostname: &str) -> Result<IpAddress, NetworkError> {
+    /// Whether the stack has an IPv6 config to connect to IPv6 addresses
+    fn has_ipv6(&self) -> bool {
+        self.stack.config_v6().is_some()
+    }
+
+    /// Resolves all addresses of `hostname`, alternating IPv6 and IPv4 starting with IPv6.
+    /// IPv6 addresses are only queried if the stack has an IPv6 config.
+    async fn dns_resolve(&self, hostname: &str) -> Result<Addresses, NetworkError> {
         let dns_client = DnsSocket::new(self.stack);
 
-        let ip_v6_future = async {
-            let result = dns_client.query(hostname, DnsQueryType::Aaaa).await;
-
-            match result {
-                Ok(addrs) => {
-                    if let Some(addr) = addrs.into_iter().next() {
-                        info!("dns aaaa: {} -> {}", hostname, addr);
-                        Ok(Some(addr))
-                    } else {
-                        info!("dns aaaa: {} -> nothing", hostname);
-                        Ok(None)
-                    }
-                },
-                Err(embassy_net::dns::Error::InvalidName) => {
-                    info!("dns aaaa: {} -> invalid name", hostname);
-                    Ok(None)
-                },
-                Err(e) => Err(e)
+        let query = |qtype, name| {
+            let dns_client = &dns_client;
+            async move {
+                match dns_client.query(hostname, qtype).await {
+                    Ok(addrs) => {
+                        for addr in addrs.iter() {
+                            info!("dns {}: {} -> {}", name, hostname, addr);
+                        }
+                        Ok(addrs)
+                    },
+                    Err(embassy_net::dns::Error::InvalidName) => {
+                        info!("dns {}: {} -> invalid name", name, hostname);
+                        Ok(heapless::Vec::new())
+                    },
+                    Err(e) => Err(e)
+                }
             }
         };
 
-        let ip_v4_future = async {
-            let result = dns_client.query(hostname, DnsQueryType::A).await;
-
-            match result {
-                Ok(addrs) => {
-                    if let Some(addr) = addrs.into_iter().next() {
-                        info!("dns a: {} -> {}", hostname, addr);
-                        Ok(Some(addr))
-                    } else {
-                        info!("dns a: {} -> nothing", hostname);
-                        Ok(None)
-                    }
-                }
-                Err(embassy_net::dns::Error::InvalidName) => {
-                    info!("dns a: {} -> invalid name", hostname);
-                    Ok(None)
-                },
-                Err(e) => Err(e)
+        let query_v6 = async {
+            if self.has_ipv6() {
+                query(DnsQueryType::Aaaa, "aaaa").await
+            } else {
+                Ok(heapless::Vec::new())
             }
         };
-        
-        let (r_v4, r_v6) = join(ip_v4_future, ip_v6_future).await;
+
+        let (r_v4, r_v6) = join(query(DnsQueryType::A, "a"), query_v6).await;
         let r_v4 = r_v4.map_err(|e| {
             error!("DNS A request failed: {}", e);
             NetworkError::DnsFailed
@@ -172,38 +351,235 @@ impl <'a> EmbassyNetworkConnection<'a> {
             NetworkError::DnsFailed
         })?;
 
-        r_v4.or(r_v6).ok_or(NetworkError::HostNotFound)
+        let mut addrs = Addresses::new();
+        let mut v4 = r_v4.iter();
+        let mut v6 = r_v6.iter();
+        loop {
+            let (a, b) = (v6.next(), v4.next());
+            if a.is_none() && b.is_none() {
+                break;
+            }
+            for addr in a.into_iter().chain(b) {
+                let _ = addrs.push(*addr);
+            }
+        }
+
+        if addrs.is_empty() {
+            return Err(NetworkError::HostNotFound);
+        }
+        Ok(addrs)
+    }
+
+    /// Returns the literal address of the host, the cached addresses or resolves the host
+    async fn resolve(&mut self, index: usize) -> Result<Addresses, NetworkError> {
+        let endpoint = &self.endpoints[index];
+        let host = endpoint.host;
+
+        if let Some(addr) = parse_ip_address(host) {
+            let mut addrs = Addresses::new();
+            let _ = addrs.push(addr);
+            return Ok(addrs);
+        }
+
+        if let Some(addrs) = endpoint.cached_addresses(Instant::now()) {
+            trace!("using cached addresses of {}", host);
+            return Ok(addrs.clone());
+        }
+
+        let addrs = self.dns_resolve(host).await?;
+        self.endpoints[index].cached = Some((addrs.clone(), Instant::now() + self.options.dns_cache_ttl));
+        Ok(addrs)
+    }
+
+    /// Creates a socket with the options
+    ///
+    /// # Safety
+    ///
+    /// No other socket with the same buffers may exist.
+    unsafe fn new_socket(&mut self, buffers: SocketBuffers) -> TcpSocket<'a> {
+        let resources = match buffers {
+            SocketBuffers::Main => &mut self.resources,
+            // Only used if there are race resources
+            SocketBuffers::Race => self.race_resources.as_mut().unwrap()
+        };
+        let ( rx_buffer, tx_buffer ) = resources.unwrap_unsafe();
+
+        let mut socket = TcpSocket::new(self.stack, rx_buffer, tx_buffer);
+        socket.set_timeout(self.options.timeout);
+        socket.set_keep_alive(self.options.keep_alive);
+        socket.set_hop_limit(self.options.hop_limit);
+        socket
+    }
+
+    async fn connect_socket(socket: &mut TcpSocket<'a>, endpoint: IpEndpoint, connect_timeout: Option<Duration>) -> Result<(), ConnectError> {
+        info!("connecting to {}...", endpoint);
+        let result = match connect_timeout {
+            Some(connect_timeout) => with_timeout(connect_timeout, socket.connect(endpoint)).await
+                .unwrap_or(Err(ConnectError::TimedOut)),
+            None => socket.connect(endpoint).await
+        };
+
+        if let Err(e) = result {
+            warn!("tcp connection to {} failed: {}", endpoint, e);
+        }
+        result
+    }
+
+    /// Connects to `first`, and to `second` if `first` failed or did not succeed within the happy eyeballs delay.
+    /// The first successful socket is kept.
+    async fn race(&mut self, first: IpEndpoint, second: IpEndpoint) -> Result<IpEndpoint, ConnectError> {
+        // The previous sockets were dropped before
+        let mut first_socket = unsafe { self.new_socket(SocketBuffers::Main) };
+        let mut second_socket = unsafe { self.new_socket(SocketBuffers::Race) };
+
+        let connect_timeout = self.options.connect_timeout;
+        let delay = self.options.happy_eyeballs_delay;
+
+        let first_won = {
+            let mut first_future = pin!(Self::connect_socket(&mut first_socket, first, connect_timeout));
+
+            match select(first_future.as_mut(), Timer::after(delay)).await {
+                Either::First(Ok(())) => true,
+                // No need to wait for the delay
+                Either::First(Err(_)) => Self::connect_socket(&mut second_socket, second, connect_timeout).await
+                    .map(|()| false)?,
+                Either::Second(()) => {
+                    let mut second_future = pin!(Self::connect_socket(&mut second_socket, second, connect_timeout));
+
+                    match select(first_future.as_mut(), second_future.as_mut()).await {
+                        Either::First(Ok(())) => true,
+                        Either::Second(Ok(())) => false,
+                        Either::First(Err(_)) => second_future.await.map(|()| false)?,
+                        Either::Second(Err(_)) => first_future.await.map(|()| true)?
+                    }
+                }
+            }
+        };
+
+        if first_won {
+            self.socket = Some(first_socket);
+            Ok(first)
+        } else {
+            self.socket = Some(second_socket);
+            Ok(second)
+        }
     }
 
-    async fn connect_intern(&mut self, rx_buffer: &'a mut [u8], tx_buffer: &'a mut [u8]) -> Result<(), NetworkError> {
-        let addr = self.dns_resolve(&self.host).await?;
+    /// Tries the addresses of the endpoint, racing IPv6 and IPv4 if there are race resources.
+    /// IPv6 addresses are skipped if the stack has no IPv6 config.
+    async fn connect_addresses(&mut self, addrs: &Addresses, port: u16) -> Result<IpEndpoint, NetworkError> {
+        let mut result = Err(NetworkError::HostNotFound);
+
+        let has_ipv6 = self.has_ipv6();
+        let addrs: Addresses = addrs.iter()
+            .copied()
+            .filter(|addr| {
+                if is_ipv6(addr) && !has_ipv6 {
+                    info!("skipping {}, no IPv6 config", addr);
+                    return false;
+                }
+                true
+            })
+            .collect();
+
+        let mut i = 0;
+        while i < addrs.len() {
+            let first = IpEndpoint::new(addrs[i], port);
+            let second = addrs.get(i + 1)
+                .filter(|addr| is_ipv6(addr) != is_ipv6(&addrs[i]))
+                .map(|addr| IpEndpoint::new(*addr, port));
+
+            let attempt = match second {
+                Some(second) if self.race_resources.is_some() => {
+                    i += 2;
+                    self.race(first, second).await
+                },
+                _ => {
+                    i += 1;
+                    // The previous socket was dropped before
+                    let mut socket = unsafe { self.new_socket(SocketBuffers::Main) };
+                    let attempt = Self::connect_socket(&mut socket, first, self.options.connect_timeout).await;
+                    attempt.map(|()| {
+                        self.socket = Some(socket);
+                        first
+                    })
+                }
+            };
+
+            match attempt {
+                Ok(endpoint) => return Ok(endpoint),
+                Err(e) => result = Err(NetworkError::from(e))
+            }
+        }
+
+        result
+    }
 
+    async fn connect_intern(&mut self) -> Result<(), NetworkError> {
         if let Some(mut socket) = self.socket.take() {
             trace!("closing existing socket");
             socket.close();
         }
+        self.connected = None;
 
-        let mut socket = TcpSocket::new(self.stack, rx_buffer, tx_buffer);
-        // socket.set_keep_alive(Some(Duration::from_secs(10)));
+        self.wait_link_up().await?;
 
-        let endpoint = IpEndpoint{
-            addr,
-            port: self.port
+        let start = match self.options.endpoint_order {
+            EndpointOrder::InOrder => 0,
+            EndpointOrder::RoundRobin => self.next_endpoint,
         };
-        
-        info!("connecting...");
-        socket.connect(endpoint).await.map_err(|e| {
-            error!("tcp connection failed: {}", e);
-            NetworkError::from(e)
-        })?;
 
-        self.socket = Some(socket);
-        
-        Ok(())
+        let mut result = Err(NetworkError::HostNotFound);
+        for i in 0..self.endpoints.len() {
+            let index = (start + i) % self.endpoints.len();
+
+            let addrs = match self.resolve(index).await {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    result = Err(e);
+                    continue;
+                }
+            };
+
+            match self.connect_addresses(&addrs, self.endpoints[index].port).await {
+                Ok(endpoint) => {
+                    info!("connected to {} ({})", self.endpoints[index].host, endpoint);
+                    self.connected = Some((index, endpoint));
+                    self.next_endpoint = (index + 1) % self.endpoints.len();
+                    return Ok(());
+                },
+                Err(e) => {
+                    error!("tcp connection to {} failed: {}", self.endpoints[index].host, e);
+                    // The host may have moved to other addresses
+                    self.endpoints[index].cached = None;
+                    result = Err(e);
+                }
+            }
+        }
+
+        result
     }
 
 }
 
+fn is_ipv6(addr: &IpAddress) -> bool {
+    matches!(addr, IpAddress::Ipv6(_))
+}
+
+/// Parses `host` if it is a literal IPv4 or IPv6 address
+fn parse_ip_address(host: &str) -> Option<IpAddress> {
+    match host.parse::<IpAddr>().ok()? {
+        IpAddr::V4(addr) => {
+            let [a, b, c, d] = addr.octets();
+            Some(IpAddress::v4(a, b, c, d))
+        },
+        IpAddr::V6(addr) => {
+            let [a, b, c, d, e, f, g, h] = addr.segments();
+            Some(IpAddress::v6(a, b, c, d, e, f, g, h))
+        }
+    }
+}
+
 impl <'a> Unpin for EmbassyNetworkConnection<'a> {}
 
 impl <'a> ErrorType for EmbassyNetworkConnection<'a> {
@@ -214,7 +590,7 @@ impl <'a> Write for EmbassyNetworkConnection<'a> {
     async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
         let socket = self.socket.as_mut().ok_or(ErrorKind::ConnectionAborted)?;
         socket.write(buf).await
-            .map_err(|_| ErrorKind::ConnectionReset)
+            .map_err(|e| e.kind())
     }
 }
 
@@ -222,17 +598,21 @@ impl <'a> Read for EmbassyNetworkConnection<'a> {
     async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
         let socket = self.socket.as_mut().ok_or(ErrorKind::ConnectionAborted)?;
         socket.read(buf).await
-            .map_err(|_| ErrorKind::ConnectionReset)
+            .map_err(|e| e.kind())
     }
 }
 
 impl <'a> TryRead for EmbassyNetworkConnection<'a> {
     async fn try_read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
         let socket = self.socket.as_mut().ok_or(ErrorKind::ConnectionAborted)?;
-        if socket.read_ready().map_err(|_| ErrorKind::ConnectionReset)? {
-            self.read(buf).await
-        } else {
-            Ok(0)
+        if ! socket.read_ready().map_err(|_| ErrorKind::ConnectionReset)? {
+            return Ok(0);
+        }
+
+        match self.read(buf).await? {
+            // The socket is ready because the stream ended
+            0 if ! buf.is_empty() => Err(END_OF_STREAM),
+            n => Ok(n)
         }
     }
 }
@@ -250,9 +630,45 @@ impl <'a> TryWrite for EmbassyNetworkConnection<'a> {
 
 impl <'a> NetworkConnection for EmbassyNetworkConnection<'a> {
     async fn connect(&mut self) -> Result<(), crate::NetworkError> {
-        let ( rx_buffer, tx_buffer ) = unsafe {
-            self.resources.unwrap_unsafe()
-        };
-        self.connect_intern(rx_buffer, tx_buffer).await
+        self.connect_intern().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_net::IpAddress;
+    use embassy_time::{Duration, Instant};
+
+    use super::{parse_ip_address, Addresses, Endpoint};
+
+    #[test]
+    fn test_parse_ip_address() {
+        assert_eq!(parse_ip_address("192.168.1.10"), Some(IpAddress::v4(192, 168, 1, 10)));
+        assert_eq!(parse_ip_address("2001:db8::1"), Some(IpAddress::v6(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)));
+        assert_eq!(parse_ip_address("::1"), Some(IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 1)));
+
+        assert_eq!(parse_ip_address("broker.example.com"), None);
+        assert_eq!(parse_ip_address("localhost"), None);
+        assert_eq!(parse_ip_address("192.168.1"), None);
+        assert_eq!(parse_ip_address("[::1]"), None);
+    }
+
+    #[test]
+    fn test_dns_cache() {
+        let mut addrs = Addresses::new();
+        addrs.push(IpAddress::v4(10, 0, 0, 1)).unwrap();
+
+        let resolved = Instant::from_secs(100);
+        let expires = resolved + Duration::from_secs(300);
+        let mut endpoint = Endpoint { host: "broker.example.com", port: 1883, cached: None };
+        assert_eq!(endpoint.cached_addresses(resolved), None);
+
+        endpoint.cached = Some((addrs.clone(), expires));
+        assert_eq!(endpoint.cached_addresses(resolved), Some(&addrs));
+        assert_eq!(endpoint.cached_addresses(expires - Duration::from_millis(1)), Some(&addrs));
+
+        // Expired addresses are resolved again
+        assert_eq!(endpoint.cached_addresses(expires), None);
+        assert_eq!(endpoint.cached_addresses(expires + Duration::from_secs(1)), None);
     }
 }
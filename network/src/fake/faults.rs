@@ -0,0 +1,566 @@
+//! Fault injection for any [`NetworkConnection`]
+//!
+//! [`FaultyConnection`] wraps a connection and injects the faults of a [`FaultSource`]:
+//! a [`FaultScript`] for exact scenarios or [`RandomFaults`] which are reproducible by their seed.
+
+use core::{future::{pending, Future}, time::Duration};
+
+use embedded_io_async::{Error, ErrorKind, ErrorType, Read, Write};
+
+use crate::{Debug2Format, NetworkConnection, NetworkError, NetworkOperation, TryRead, TryWrite};
+
+/// Fault injected into one operation of a [`FaultyConnection`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// `connect()` fails with the error, ignored for reads and writes
+    ConnectFailure(NetworkError),
+
+    /// The peer closes the connection: reads return the end of the stream
+    /// and writes fail with [`ErrorKind::BrokenPipe`] until the next connect
+    Close,
+
+    /// Reads and writes fail with [`ErrorKind::ConnectionReset`] until the next connect
+    Reset,
+
+    /// The read or write transfers at most the given number of bytes, but at least one
+    Short(usize),
+
+    /// The operation is delayed, `try_read` and `try_write` transfer nothing instead
+    Latency(Duration),
+
+    /// The byte at `offset` modulo the transferred length is xor-ed with `mask`
+    Corrupt { offset: usize, mask: u8 },
+
+    /// The link stops transferring data: connect, reads and writes never complete
+    /// and `try_read` / `try_write` transfer nothing until the next connect
+    Stall
+}
+
+/// Decides which fault is injected into an operation
+///
+/// Implemented for closures taking the operation.
+pub trait FaultSource {
+    fn next_fault(&mut self, operation: NetworkOperation) -> Option<Fault>;
+}
+
+impl <F> FaultSource for F where F: FnMut(NetworkOperation) -> Option<Fault> {
+    fn next_fault(&mut self, operation: NetworkOperation) -> Option<Fault> {
+        self(operation)
+    }
+}
+
+/// Waits for the duration of a [`Fault::Latency`]
+///
+/// Implemented for closures returning a future, e.g. `|d: Duration| tokio::time::sleep(d)`.
+pub trait FaultDelay {
+    fn delay(&mut self, duration: Duration) -> impl Future<Output = ()>;
+}
+
+impl <F, Fut> FaultDelay for F where F: FnMut(Duration) -> Fut, Fut: Future<Output = ()> {
+    fn delay(&mut self, duration: Duration) -> impl Future<Output = ()> {
+        self(duration)
+    }
+}
+
+/// Step of a [`FaultScript`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultStep {
+    pub operation: NetworkOperation,
+
+    /// Number of operations of this kind which pass before the fault is injected
+    pub after: usize,
+
+    pub fault: Fault
+}
+
+impl FaultStep {
+    pub const fn new(operation: NetworkOperation, after: usize, fault: Fault) -> Self {
+        Self {
+            operation,
+            after,
+            fault
+        }
+    }
+}
+
+/// Injects the faults of the steps one after the other
+///
+/// Operations of another kind than the one of the current step pass.
+pub struct FaultScript<'a> {
+    steps: &'a [FaultStep],
+    passed: usize
+}
+
+impl <'a> FaultScript<'a> {
+    pub fn new(steps: &'a [FaultStep]) -> Self {
+        Self {
+            steps,
+            passed: 0
+        }
+    }
+
+    /// All faults are injected
+    pub fn is_done(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+impl <'a> FaultSource for FaultScript<'a> {
+    fn next_fault(&mut self, operation: NetworkOperation) -> Option<Fault> {
+        let (step, steps) = self.steps.split_first()?;
+        if step.operation != operation {
+            return None;
+        }
+
+        if self.passed < step.after {
+            self.passed += 1;
+            return None;
+        }
+
+        self.steps = steps;
+        self.passed = 0;
+        Some(step.fault.clone())
+    }
+}
+
+/// Injects faults at random with probabilities in per mille
+///
+/// The same seed yields the same faults for the same sequence of operations.
+pub struct RandomFaults {
+    state: u64,
+    connect_failures: u16,
+    closes: u16,
+    resets: u16,
+    short_transfers: u16,
+    latency: u16,
+    max_latency: Duration,
+    corruptions: u16,
+    stalls: u16
+}
+
+impl RandomFaults {
+    pub fn new(seed: u64) -> Self {
+        // xorshift must not start with 0
+        let state = match seed ^ 0x9E37_79B9_7F4A_7C15 {
+            0 => 0x2545_F491_4F6C_DD1D,
+            state => state
+        };
+
+        Self {
+            state,
+            connect_failures: 0,
+            closes: 0,
+            resets: 0,
+            short_transfers: 0,
+            latency: 0,
+            max_latency: Duration::ZERO,
+            corruptions: 0,
+            stalls: 0
+        }
+    }
+
+    /// Connects fail with [`NetworkError::ConnectionRefused`]
+    pub fn with_connect_failures(self, per_mille: u16) -> Self {
+        Self { connect_failures: per_mille, ..self }
+    }
+
+    pub fn with_closes(self, per_mille: u16) -> Self {
+        Self { closes: per_mille, ..self }
+    }
+
+    pub fn with_resets(self, per_mille: u16) -> Self {
+        Self { resets: per_mille, ..self }
+    }
+
+    /// Reads and writes transfer 1 to 8 bytes
+    pub fn with_short_transfers(self, per_mille: u16) -> Self {
+        Self { short_transfers: per_mille, ..self }
+    }
+
+    /// Connects, reads and writes are delayed up to `max_latency`
+    pub fn with_latency(self, per_mille: u16, max_latency: Duration) -> Self {
+        Self { latency: per_mille, max_latency, ..self }
+    }
+
+    /// A single bit of a read or write is flipped
+    pub fn with_corruptions(self, per_mille: u16) -> Self {
+        Self { corruptions: per_mille, ..self }
+    }
+
+    pub fn with_stalls(self, per_mille: u16) -> Self {
+        Self { stalls: per_mille, ..self }
+    }
+
+    fn next(&mut self) -> u64 {
+        // xorshift64*
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+impl FaultSource for RandomFaults {
+    fn next_fault(&mut self, operation: NetworkOperation) -> Option<Fault> {
+        if operation == NetworkOperation::Dns {
+            return None;
+        }
+
+        let mut roll = (self.next() % 1000) as u16;
+        let mut hit = |per_mille: u16| {
+            if roll < per_mille {
+                true
+            } else {
+                roll -= per_mille;
+                false
+            }
+        };
+
+        if operation == NetworkOperation::Connect {
+            if hit(self.connect_failures) {
+                return Some(Fault::ConnectFailure(NetworkError::ConnectionRefused));
+            }
+        } else {
+            if hit(self.closes) {
+                return Some(Fault::Close);
+            }
+            if hit(self.resets) {
+                return Some(Fault::Reset);
+            }
+            if hit(self.short_transfers) {
+                return Some(Fault::Short(1 + (self.next() % 8) as usize));
+            }
+            if hit(self.corruptions) {
+                let offset = self.next() as usize;
+                let mask = 1 << (self.next() % 8);
+                return Some(Fault::Corrupt { offset, mask });
+            }
+        }
+
+        if hit(self.latency) {
+            let max_latency = self.max_latency.as_millis() as u64;
+            return Some(Fault::Latency(Duration::from_millis(self.next() % (max_latency + 1))));
+        }
+        if hit(self.stalls) {
+            return Some(Fault::Stall);
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LinkState {
+    Up,
+    Disconnected,
+    Closed,
+    Reset,
+    Stalled
+}
+
+/// How a read or write passes to the inner connection
+#[derive(Clone, Copy)]
+enum Transfer {
+    Full,
+    Short(usize),
+    Corrupt(usize, u8),
+    Nothing
+}
+
+/// Connection which injects the faults of `faults` into the `inner` connection
+///
+/// Faults which last until the next connect are not passed to the inner connection,
+/// it is only connected again.
+pub struct FaultyConnection<C: NetworkConnection, S: FaultSource, D: FaultDelay> {
+    inner: C,
+    faults: S,
+    delay: D,
+    state: LinkState
+}
+
+impl <C: NetworkConnection, S: FaultSource, D: FaultDelay> FaultyConnection<C, S, D> {
+    pub fn new(inner: C, faults: S, delay: D) -> Self {
+        Self {
+            inner,
+            faults,
+            delay,
+            state: LinkState::Up
+        }
+    }
+
+    pub fn faults(&self) -> &S {
+        &self.faults
+    }
+
+    /// Injects the next fault and applies the state of the link
+    async fn prepare(&mut self, operation: NetworkOperation, blocking: bool) -> Result<Transfer, ErrorKind> {
+        if self.state == LinkState::Up {
+            let fault = self.faults.next_fault(operation);
+            if let Some(fault) = &fault {
+                info!("injecting fault into {}: {}", operation, Debug2Format(fault));
+            }
+
+            match fault {
+                Some(Fault::Close) => self.state = LinkState::Closed,
+                Some(Fault::Reset) => self.state = LinkState::Reset,
+                Some(Fault::Stall) => self.state = LinkState::Stalled,
+                Some(Fault::Latency(duration)) if blocking => self.delay.delay(duration).await,
+                Some(Fault::Latency(_)) => return Ok(Transfer::Nothing),
+                Some(Fault::Short(n)) => return Ok(Transfer::Short(n)),
+                Some(Fault::Corrupt { offset, mask }) => return Ok(Transfer::Corrupt(offset, mask)),
+                Some(Fault::ConnectFailure(_)) | None => {}
+            }
+        }
+
+        match self.state {
+            LinkState::Up => Ok(Transfer::Full),
+            LinkState::Disconnected => Err(ErrorKind::NotConnected),
+            LinkState::Closed if operation == NetworkOperation::Read => Ok(Transfer::Nothing),
+            LinkState::Closed => Err(ErrorKind::BrokenPipe),
+            LinkState::Reset => Err(ErrorKind::ConnectionReset),
+            LinkState::Stalled if blocking => pending().await,
+            LinkState::Stalled => Ok(Transfer::Nothing)
+        }
+    }
+
+    async fn read_faulty(&mut self, buf: &mut [u8], blocking: bool) -> Result<usize, ErrorKind> {
+        let transfer = self.prepare(NetworkOperation::Read, blocking).await?;
+        let len = match transfer {
+            Transfer::Nothing => return Ok(0),
+            Transfer::Short(n) => n.max(1).min(buf.len()),
+            _ => buf.len()
+        };
+
+        let n = if blocking {
+            self.inner.read(&mut buf[..len]).await
+        } else {
+            self.inner.try_read(&mut buf[..len]).await
+        }.map_err(|e| e.kind())?;
+
+        if let Transfer::Corrupt(offset, mask) = transfer {
+            if n > 0 {
+                buf[offset % n] ^= mask;
+            }
+        }
+        Ok(n)
+    }
+
+    async fn write_inner(&mut self, buf: &[u8], blocking: bool) -> Result<usize, ErrorKind> {
+        if blocking {
+            self.inner.write(buf).await
+        } else {
+            self.inner.try_write(buf).await
+        }.map_err(|e| e.kind())
+    }
+
+    async fn write_faulty(&mut self, buf: &[u8], blocking: bool) -> Result<usize, ErrorKind> {
+        match self.prepare(NetworkOperation::Write, blocking).await? {
+            Transfer::Nothing => Ok(0),
+            Transfer::Full => self.write_inner(buf, blocking).await,
+            Transfer::Short(n) => self.write_inner(&buf[..n.max(1).min(buf.len())], blocking).await,
+            Transfer::Corrupt(_, _) if buf.is_empty() => Ok(0),
+            Transfer::Corrupt(offset, mask) => {
+                // The corrupted byte is written on its own, the write is short then
+                let offset = offset % buf.len();
+                let n = self.write_inner(&buf[..offset], blocking).await?;
+                if n < offset {
+                    return Ok(n);
+                }
+                let n = self.write_inner(&[buf[offset] ^ mask], blocking).await?;
+                Ok(offset + n)
+            }
+        }
+    }
+}
+
+impl <C: NetworkConnection, S: FaultSource, D: FaultDelay> ErrorType for FaultyConnection<C, S, D> {
+    type Error = ErrorKind;
+}
+
+impl <C: NetworkConnection, S: FaultSource, D: FaultDelay> Read for FaultyConnection<C, S, D> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.read_faulty(buf, true).await
+    }
+}
+
+impl <C: NetworkConnection, S: FaultSource, D: FaultDelay> TryRead for FaultyConnection<C, S, D> {
+    async fn try_read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.read_faulty(buf, false).await
+    }
+}
+
+impl <C: NetworkConnection, S: FaultSource, D: FaultDelay> Write for FaultyConnection<C, S, D> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.write_faulty(buf, true).await
+    }
+}
+
+impl <C: NetworkConnection, S: FaultSource, D: FaultDelay> TryWrite for FaultyConnection<C, S, D> {
+    async fn try_write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.write_faulty(buf, false).await
+    }
+}
+
+impl <C: NetworkConnection, S: FaultSource, D: FaultDelay> NetworkConnection for FaultyConnection<C, S, D> {
+    async fn connect(&mut self) -> Result<(), NetworkError> {
+        let fault = self.faults.next_fault(NetworkOperation::Connect);
+        if let Some(fault) = &fault {
+            info!("injecting fault into connect: {}", Debug2Format(fault));
+        }
+
+        match fault {
+            Some(Fault::ConnectFailure(e)) => {
+                self.state = LinkState::Disconnected;
+                return Err(e);
+            },
+            Some(Fault::Latency(duration)) => self.delay.delay(duration).await,
+            Some(Fault::Stall) => {
+                self.state = LinkState::Disconnected;
+                pending::<()>().await;
+            },
+            _ => {}
+        }
+
+        self.inner.connect().await?;
+        self.state = LinkState::Up;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::time::Duration;
+
+    use buffer::new_stack_buffer;
+    use embedded_io_async::{ErrorKind, Read, Write};
+
+    use crate::{fake::{new_connection, ConnectionRessources}, NetwordSendReceive, NetworkConnection, NetworkError, NetworkOperation, TryRead, TryWrite};
+
+    use super::{Fault, FaultScript, FaultSource, FaultStep, FaultyConnection, RandomFaults};
+
+    #[tokio::test]
+    async fn test_script() {
+        let resources = ConnectionRessources::<32>::new();
+        let (client, mut server) = new_connection(&resources);
+
+        let steps = [
+            FaultStep::new(NetworkOperation::Connect, 0, Fault::ConnectFailure(NetworkError::ConnectionRefused)),
+            FaultStep::new(NetworkOperation::Read, 0, Fault::Short(2)),
+            FaultStep::new(NetworkOperation::Read, 0, Fault::Corrupt { offset: 1, mask: 0x20 }),
+            FaultStep::new(NetworkOperation::Write, 1, Fault::Short(1)),
+            FaultStep::new(NetworkOperation::Read, 0, Fault::Close),
+            FaultStep::new(NetworkOperation::Connect, 0, Fault::Latency(Duration::from_millis(10))),
+        ];
+        let mut connection = FaultyConnection::new(client, FaultScript::new(&steps), |d: Duration| tokio::time::sleep(d));
+
+        assert_eq!(connection.connect().await, Err(NetworkError::ConnectionRefused));
+        assert_eq!(connection.read(&mut [0; 4]).await, Err(ErrorKind::NotConnected));
+        connection.connect().await.unwrap();
+
+        server.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(connection.read(&mut buf).await, Ok(2));
+        assert_eq!(&buf[..2], b"he");
+        assert_eq!(connection.read(&mut buf).await, Ok(3));
+        assert_eq!(&buf[..3], b"lLo");
+
+        assert_eq!(connection.write(b"ab").await, Ok(2));
+        assert_eq!(connection.write(b"cd").await, Ok(1));
+        let mut received = [0; 3];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"abc");
+
+        // The close is reported as end of stream
+        let mut recv_buffer = new_stack_buffer::<8>();
+        assert_eq!(connection.receive(&mut recv_buffer).await, Err(NetworkError::Closed(NetworkOperation::Read)));
+        assert_eq!(connection.write(b"ef").await, Err(ErrorKind::BrokenPipe));
+
+        let start = tokio::time::Instant::now();
+        connection.connect().await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        assert!(connection.faults().is_done());
+
+        server.write_all(b"x").await.unwrap();
+        assert_eq!(connection.read(&mut buf).await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn test_stall_and_reset() {
+        let resources = ConnectionRessources::<32>::new();
+        let (client, mut server) = new_connection(&resources);
+
+        let mut faults = [Fault::Stall, Fault::Reset].into_iter();
+        let source = move |operation: NetworkOperation| {
+            if operation == NetworkOperation::Write {
+                faults.next()
+            } else {
+                None
+            }
+        };
+        let mut connection = FaultyConnection::new(client, source, |d: Duration| tokio::time::sleep(d));
+        connection.connect().await.unwrap();
+
+        // Nothing passes the stalled link, not even received data
+        let result = tokio::time::timeout(Duration::from_millis(50), connection.write(b"a")).await;
+        assert!(result.is_err());
+        server.write_all(b"b").await.unwrap();
+        assert_eq!(connection.try_write(b"a").await, Ok(0));
+        assert_eq!(connection.try_read(&mut [0; 4]).await, Ok(0));
+
+        connection.connect().await.unwrap();
+        assert_eq!(connection.write(b"c").await, Err(ErrorKind::ConnectionReset));
+        assert_eq!(connection.read(&mut [0; 4]).await, Err(ErrorKind::ConnectionReset));
+
+        connection.connect().await.unwrap();
+        assert_eq!(connection.write(b"d").await, Ok(1));
+        let mut buf = [0; 4];
+        assert_eq!(connection.read(&mut buf).await, Ok(1));
+        assert_eq!(buf[0], b'b');
+    }
+
+    #[test]
+    fn test_random_faults() {
+        let operations = [NetworkOperation::Connect, NetworkOperation::Read, NetworkOperation::Write];
+        let new_faults = |seed| RandomFaults::new(seed)
+            .with_connect_failures(200)
+            .with_resets(50)
+            .with_short_transfers(100)
+            .with_latency(100, Duration::from_millis(20))
+            .with_corruptions(50);
+
+        let mut faults = new_faults(7);
+        let mut same_faults = new_faults(7);
+        let mut other_faults = new_faults(8);
+        let mut differs = false;
+        let mut injected = 0;
+        for i in 0..1000 {
+            let operation = operations[i % operations.len()];
+            let fault = faults.next_fault(operation);
+            assert_eq!(fault, same_faults.next_fault(operation));
+            differs |= fault != other_faults.next_fault(operation);
+
+            match &fault {
+                Some(Fault::ConnectFailure(_)) => assert_eq!(operation, NetworkOperation::Connect),
+                Some(Fault::Reset | Fault::Corrupt { .. }) => assert_ne!(operation, NetworkOperation::Connect),
+                Some(Fault::Short(n)) => assert!((1..=8).contains(n)),
+                Some(Fault::Latency(d)) => assert!(*d <= Duration::from_millis(20)),
+                Some(fault) => panic!("fault {:?} is disabled", fault),
+                None => {}
+            }
+            injected += fault.is_some() as usize;
+        }
+
+        assert!(differs);
+        assert!((150..450).contains(&injected), "{} faults injected", injected);
+
+        let mut no_faults = RandomFaults::new(7);
+        assert!((0..1000).all(|i| no_faults.next_fault(operations[i % operations.len()]).is_none()));
+
+        // The seed which would start xorshift with 0 still injects faults
+        let mut faults = new_faults(0x9E37_79B9_7F4A_7C15);
+        assert!((0..1000).any(|i| faults.next_fault(operations[i % operations.len()]).is_some()));
+    }
+}
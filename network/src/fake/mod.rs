@@ -7,4 +7,7 @@ mod stream;
 pub use stream::*;
 
 mod connection;
-pub use connection::*;
\ No newline at end of file
+pub use connection::*;
+
+mod faults;
+pub use faults::*;
@@ -3,7 +3,7 @@
 use core::future::Future;
 
 use buffer::{Buffer, BufferReader, BufferWriter, ReadWrite};
-use embedded_io_async::{ErrorType, Read, ReadReady, Write, WriteReady};
+use embedded_io_async::{Error as _, ErrorKind, ErrorType, Read, ReadReady, Write, WriteReady};
 use thiserror::Error;
 
 
@@ -19,8 +19,53 @@ pub mod std;
 
 pub mod fake;
 
+pub mod stream;
+
+#[cfg(all(feature = "std", unix))]
+pub mod unix;
+
+pub mod tls;
+
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+#[cfg(feature = "proxy")]
+pub mod proxy;
+
 pub mod mqtt;
 
+/// Order in which a connection with several broker endpoints tries them on connect
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EndpointOrder {
+    /// Always start with the first endpoint
+    #[default]
+    InOrder,
+    /// Start with the endpoint after the one of the previous connection
+    RoundRobin
+}
+
+/// The network operation which failed
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NetworkOperation {
+    Connect,
+    Read,
+    Write,
+    Dns
+}
+
+impl core::fmt::Display for NetworkOperation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            NetworkOperation::Connect => f.write_str("connect"),
+            NetworkOperation::Read => f.write_str("read"),
+            NetworkOperation::Write => f.write_str("write"),
+            NetworkOperation::Dns => f.write_str("dns"),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Error)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum NetworkError {
@@ -34,11 +79,67 @@ pub enum NetworkError {
     #[error("sending / retrieving from network failed")]
     ConnectionFailed,
 
+    #[error("connection refused by host")]
+    ConnectionRefused,
+
+    #[error("network operation timed out")]
+    Timeout,
+
+    #[error("network link is down or not configured")]
+    LinkDown,
+
+    #[error("TLS handshake or configuration failed")]
+    TlsFailed,
+
+    #[error("WebSocket upgrade failed")]
+    WebSocketFailed,
+
+    #[error("proxy handshake failed")]
+    ProxyFailed,
+
+    /// The connection failed with the error kind of the underlying stream
+    #[error("network {0} failed: {1:?}")]
+    Io(NetworkOperation, ErrorKind),
+
+    /// The peer closed the connection (end of stream) instead of resetting it
+    #[error("connection closed by peer during {0}")]
+    Closed(NetworkOperation),
+
     #[cfg(feature = "embassy")]
     #[error("failed to connect to tcp endpoint")]
     ConnectError(embassy_net::tcp::ConnectError),
 }
 
+impl NetworkError {
+    /// The operation which failed, `None` if it is unknown
+    pub fn operation(&self) -> Option<NetworkOperation> {
+        match self {
+            NetworkError::HostNotFound | NetworkError::DnsFailed => Some(NetworkOperation::Dns),
+            NetworkError::ConnectionFailed => None,
+            NetworkError::ConnectionRefused | NetworkError::Timeout | NetworkError::LinkDown => Some(NetworkOperation::Connect),
+            NetworkError::TlsFailed | NetworkError::WebSocketFailed | NetworkError::ProxyFailed => Some(NetworkOperation::Connect),
+            NetworkError::Io(operation, _) | NetworkError::Closed(operation) => Some(*operation),
+            #[cfg(feature = "embassy")]
+            NetworkError::ConnectError(_) => Some(NetworkOperation::Connect),
+        }
+    }
+
+    /// The error kind of the underlying stream, `None` if the error has no stream error
+    pub fn kind(&self) -> Option<ErrorKind> {
+        match self {
+            NetworkError::Io(_, kind) => Some(*kind),
+            NetworkError::ConnectionRefused => Some(ErrorKind::ConnectionRefused),
+            NetworkError::Timeout => Some(ErrorKind::TimedOut),
+            _ => None
+        }
+    }
+
+    /// Whether the peer closed the connection cleanly
+    pub fn is_closed(&self) -> bool {
+        matches!(self, NetworkError::Closed(_))
+    }
+}
+
 #[cfg(feature = "embassy")]
 impl From<embassy_net::tcp::ConnectError> for NetworkError {
     fn from(value: embassy_net::tcp::ConnectError) -> Self {
@@ -48,16 +149,25 @@ impl From<embassy_net::tcp::ConnectError> for NetworkError {
 
 
 
+/// Error kind of [`TryRead::try_read`] at the end of the stream
+pub const END_OF_STREAM: ErrorKind = ErrorKind::BrokenPipe;
+
+/// Reads without blocking, `Ok(0)` if no data is available.
+/// The end of the stream is an error of kind [`END_OF_STREAM`].
 pub trait TryRead: ErrorType {
     fn try_read(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize, Self::Error>>;
 }
 
-impl <T> TryRead for T where T: Read + ReadReady{
+impl <T> TryRead for T where T: Read + ReadReady, T::Error: From<ErrorKind> {
     async fn try_read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        if self.read_ready()? {
-            self.read(buf).await
-        } else {
-            Ok(0)
+        if ! self.read_ready()? {
+            return Ok(0);
+        }
+
+        match self.read(buf).await? {
+            // The stream is ready because it ended
+            0 if ! buf.is_empty() => Err(END_OF_STREAM.into()),
+            n => Ok(n)
         }
     }
 }
@@ -98,8 +208,15 @@ impl <C> NetwordSendReceive for C where C: NetworkConnection {
     async fn send_all(&mut self, buffer: &mut impl BufferReader) -> Result<(), NetworkError> {
         self.write_all(buffer)
             .await.map_err(|e| {
-                error!("error sending to network: {}", Debug2Format(e));
-                NetworkError::ConnectionFailed
+                error!("error sending to network: {}", Debug2Format(&e));
+                NetworkError::Io(NetworkOperation::Write, e.kind())
+            })?;
+
+        // Connections like TLS may keep written data until they are flushed
+        self.flush()
+            .await.map_err(|e| {
+                error!("error flushing to network: {}", Debug2Format(&e));
+                NetworkError::Io(NetworkOperation::Write, e.kind())
             })?;
 
         Ok(())
@@ -110,8 +227,8 @@ impl <C> NetwordSendReceive for C where C: NetworkConnection {
         let reader = buf.create_reader();
         let result = self.write(&reader[..]).await
             .map_err(|e| {
-                error!("error sending to network: {}", Debug2Format(e));
-                NetworkError::ConnectionFailed
+                error!("error sending to network: {}", Debug2Format(&e));
+                NetworkError::Io(NetworkOperation::Write, e.kind())
             });
         match result {
             Ok(n) => {
@@ -130,9 +247,9 @@ impl <C> NetwordSendReceive for C where C: NetworkConnection {
         let reader = buf.create_reader();
         let result = self.try_write(&reader[..]).await
             .map_err(|e| {
-                error!("error try_sending to network: {}", Debug2Format(e));
-                NetworkError::ConnectionFailed}
-            );
+                error!("error try_sending to network: {}", Debug2Format(&e));
+                NetworkError::Io(NetworkOperation::Write, e.kind())
+            });
         match result {
             Ok(n) => {
                 reader.add_bytes_read(n);
@@ -155,11 +272,15 @@ impl <C> NetwordSendReceive for C where C: NetworkConnection {
 
         let result = self.read(&mut writer).await
             .map_err(|e| {
-                error!("error receive from network: {}", Debug2Format(e));
-                NetworkError::ConnectionFailed
+                error!("error receive from network: {}", Debug2Format(&e));
+                NetworkError::Io(NetworkOperation::Read, e.kind())
             });
 
         match result {
+            Ok(0) if ! writer.is_empty() => {
+                warn!("connection closed by peer");
+                Err(NetworkError::Closed(NetworkOperation::Read))
+            },
             Ok(n) => {
                 // Error is unwrapped because read() should ensure that not too many bytes are written
                 writer.commit(n).unwrap();
@@ -183,8 +304,13 @@ impl <C> NetwordSendReceive for C where C: NetworkConnection {
 
         let result = self.try_read(&mut writer).await
             .map_err(|e| {
-                error!("error try_receive from network: {}", Debug2Format(e));
-                NetworkError::ConnectionFailed
+                if e.kind() == END_OF_STREAM {
+                    warn!("connection closed by peer");
+                    return NetworkError::Closed(NetworkOperation::Read);
+                }
+
+                error!("error try_receive from network: {}", Debug2Format(&e));
+                NetworkError::Io(NetworkOperation::Read, e.kind())
             });
 
         match result {
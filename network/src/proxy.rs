@@ -0,0 +1,442 @@
+//! Tunnels through HTTP CONNECT and SOCKS5 proxies for any [`NetworkConnection`] to the proxy
+
+use core::net::IpAddr;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use embedded_io_async::{Error, ErrorType, Read, ReadExactError, Write};
+
+use crate::{Debug2Format, NetworkConnection, NetworkError, NetworkOperation, TryRead, TryWrite};
+
+/// Maximum size of the HTTP response to the CONNECT request
+const MAX_RESPONSE_SIZE: usize = 1024;
+
+/// Maximum length of `username:password` for basic auth
+const MAX_CREDENTIALS_SIZE: usize = 255;
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_AUTH_NONE: u8 = 0x00;
+const SOCKS_AUTH_PASSWORD: u8 = 0x02;
+const SOCKS_AUTH_REJECTED: u8 = 0xFF;
+const SOCKS_COMMAND_CONNECT: u8 = 0x01;
+const SOCKS_ADDRESS_IPV4: u8 = 0x01;
+const SOCKS_ADDRESS_DOMAIN: u8 = 0x03;
+const SOCKS_ADDRESS_IPV6: u8 = 0x04;
+
+/// Username and password for the proxy
+#[derive(Debug, Clone, Copy)]
+pub struct ProxyCredentials<'a> {
+    pub username: &'a str,
+    pub password: &'a str
+}
+
+/// Writes all of `buf` to the proxy
+async fn write_proxy<C: NetworkConnection>(inner: &mut C, buf: &[u8]) -> Result<(), NetworkError> {
+    inner.write_all(buf).await
+        .map_err(|e| {
+            error!("error writing to proxy: {}", e.kind());
+            NetworkError::Io(NetworkOperation::Connect, e.kind())
+        })
+}
+
+/// Reads exactly `buf.len()` bytes from the proxy
+async fn read_proxy<C: NetworkConnection>(inner: &mut C, buf: &mut [u8]) -> Result<(), NetworkError> {
+    inner.read_exact(buf).await
+        .map_err(|e| {
+            error!("error reading from proxy: {}", Debug2Format(&e));
+            match e {
+                ReadExactError::UnexpectedEof => NetworkError::Closed(NetworkOperation::Connect),
+                ReadExactError::Other(e) => NetworkError::Io(NetworkOperation::Connect, e.kind())
+            }
+        })
+}
+
+/// Connection to `host`:`port` through an HTTP proxy, `inner` connects to the proxy
+pub struct HttpConnectProxy<'a, C: NetworkConnection> {
+    inner: C,
+    host: &'a str,
+    port: u16,
+    credentials: Option<ProxyCredentials<'a>>
+}
+
+impl <'a, C: NetworkConnection> HttpConnectProxy<'a, C> {
+    pub fn new(inner: C, host: &'a str, port: u16) -> Self {
+        Self {
+            inner,
+            host,
+            port,
+            credentials: None
+        }
+    }
+
+    /// Authenticates with basic auth
+    pub fn with_credentials(mut self, username: &'a str, password: &'a str) -> Self {
+        self.credentials = Some(ProxyCredentials { username, password });
+        self
+    }
+
+    async fn write_authority(&mut self) -> Result<(), NetworkError> {
+        // IPv6 addresses are enclosed in brackets
+        let ipv6 = self.host.contains(':');
+        if ipv6 {
+            write_proxy(&mut self.inner, b"[").await?;
+        }
+        write_proxy(&mut self.inner, self.host.as_bytes()).await?;
+        if ipv6 {
+            write_proxy(&mut self.inner, b"]").await?;
+        }
+
+        let mut port = [0; 6];
+        write_proxy(&mut self.inner, format_port(self.port, &mut port)).await
+    }
+
+    async fn handshake(&mut self) -> Result<(), NetworkError> {
+        write_proxy(&mut self.inner, b"CONNECT ").await?;
+        self.write_authority().await?;
+        write_proxy(&mut self.inner, b" HTTP/1.1\r\nHost: ").await?;
+        self.write_authority().await?;
+
+        if let Some(credentials) = self.credentials {
+            let mut plain = [0; MAX_CREDENTIALS_SIZE];
+            let len = credentials.username.len() + 1 + credentials.password.len();
+            if len > plain.len() {
+                error!("proxy credentials too long");
+                return Err(NetworkError::ProxyFailed);
+            }
+            plain[..credentials.username.len()].copy_from_slice(credentials.username.as_bytes());
+            plain[credentials.username.len()] = b':';
+            plain[credentials.username.len() + 1..len].copy_from_slice(credentials.password.as_bytes());
+
+            let mut encoded = [0; MAX_CREDENTIALS_SIZE.div_ceil(3) * 4];
+            // Cannot fail, the buffer fits the encoded maximum
+            let encoded_len = STANDARD.encode_slice(&plain[..len], &mut encoded).unwrap();
+
+            write_proxy(&mut self.inner, b"\r\nProxy-Authorization: Basic ").await?;
+            write_proxy(&mut self.inner, &encoded[..encoded_len]).await?;
+        }
+        write_proxy(&mut self.inner, b"\r\n\r\n").await?;
+
+        // Read byte by byte to not consume data of the broker
+        let mut response = [0; MAX_RESPONSE_SIZE];
+        let mut len = 0;
+        while ! response[..len].ends_with(b"\r\n\r\n") {
+            if len == response.len() {
+                error!("proxy response too large");
+                return Err(NetworkError::ProxyFailed);
+            }
+
+            read_proxy(&mut self.inner, &mut response[len..len + 1]).await?;
+            len += 1;
+        }
+
+        let status = response.split(|b| *b == b'\r').next().unwrap_or(&[]);
+        let status = core::str::from_utf8(status).unwrap_or("");
+        let code = status.split(' ').nth(1);
+        if ! status.starts_with("HTTP/1.") || code != Some("200") {
+            error!("proxy rejected CONNECT: {}", status);
+            return Err(NetworkError::ProxyFailed);
+        }
+
+        Ok(())
+    }
+}
+
+/// Formats `port` as decimal into `buf`
+fn format_port(port: u16, buf: &mut [u8; 6]) -> &[u8] {
+    buf[0] = b':';
+    let mut digits = [0; 5];
+    let mut n = port;
+    let mut len = 0;
+    loop {
+        digits[len] = b'0' + (n % 10) as u8;
+        n /= 10;
+        len += 1;
+        if n == 0 {
+            break;
+        }
+    }
+
+    for i in 0..len {
+        buf[1 + i] = digits[len - 1 - i];
+    }
+    &buf[..1 + len]
+}
+
+/// Connection to `host`:`port` through a SOCKS5 proxy, `inner` connects to the proxy
+pub struct Socks5Proxy<'a, C: NetworkConnection> {
+    inner: C,
+    host: &'a str,
+    port: u16,
+    credentials: Option<ProxyCredentials<'a>>
+}
+
+impl <'a, C: NetworkConnection> Socks5Proxy<'a, C> {
+    pub fn new(inner: C, host: &'a str, port: u16) -> Self {
+        Self {
+            inner,
+            host,
+            port,
+            credentials: None
+        }
+    }
+
+    /// Authenticates with username and password (RFC 1929)
+    pub fn with_credentials(mut self, username: &'a str, password: &'a str) -> Self {
+        self.credentials = Some(ProxyCredentials { username, password });
+        self
+    }
+
+    async fn authenticate(&mut self) -> Result<(), NetworkError> {
+        if self.credentials.is_some() {
+            write_proxy(&mut self.inner, &[SOCKS_VERSION, 2, SOCKS_AUTH_NONE, SOCKS_AUTH_PASSWORD]).await?;
+        } else {
+            write_proxy(&mut self.inner, &[SOCKS_VERSION, 1, SOCKS_AUTH_NONE]).await?;
+        }
+
+        let mut reply = [0; 2];
+        read_proxy(&mut self.inner, &mut reply).await?;
+
+        match (reply, self.credentials) {
+            ([SOCKS_VERSION, SOCKS_AUTH_NONE], _) => Ok(()),
+            ([SOCKS_VERSION, SOCKS_AUTH_PASSWORD], Some(credentials)) => {
+                let username = credentials.username.as_bytes();
+                let password = credentials.password.as_bytes();
+                if username.len() > 255 || password.len() > 255 {
+                    error!("SOCKS5 credentials too long");
+                    return Err(NetworkError::ProxyFailed);
+                }
+
+                write_proxy(&mut self.inner, &[1, username.len() as u8]).await?;
+                write_proxy(&mut self.inner, username).await?;
+                write_proxy(&mut self.inner, &[password.len() as u8]).await?;
+                write_proxy(&mut self.inner, password).await?;
+
+                let mut reply = [0; 2];
+                read_proxy(&mut self.inner, &mut reply).await?;
+                if reply[1] != 0 {
+                    error!("SOCKS5 proxy rejected the credentials");
+                    return Err(NetworkError::ProxyFailed);
+                }
+                Ok(())
+            },
+            ([SOCKS_VERSION, SOCKS_AUTH_REJECTED], _) => {
+                error!("SOCKS5 proxy accepts none of the authentication methods");
+                Err(NetworkError::ProxyFailed)
+            },
+            (reply, _) => {
+                error!("invalid SOCKS5 method selection: {}", reply);
+                Err(NetworkError::ProxyFailed)
+            }
+        }
+    }
+
+    async fn handshake(&mut self) -> Result<(), NetworkError> {
+        self.authenticate().await?;
+
+        write_proxy(&mut self.inner, &[SOCKS_VERSION, SOCKS_COMMAND_CONNECT, 0]).await?;
+        match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(addr)) => {
+                write_proxy(&mut self.inner, &[SOCKS_ADDRESS_IPV4]).await?;
+                write_proxy(&mut self.inner, &addr.octets()).await?;
+            },
+            Ok(IpAddr::V6(addr)) => {
+                write_proxy(&mut self.inner, &[SOCKS_ADDRESS_IPV6]).await?;
+                write_proxy(&mut self.inner, &addr.octets()).await?;
+            },
+            Err(_) => {
+                if self.host.len() > 255 {
+                    error!("host name too long for SOCKS5");
+                    return Err(NetworkError::ProxyFailed);
+                }
+                write_proxy(&mut self.inner, &[SOCKS_ADDRESS_DOMAIN, self.host.len() as u8]).await?;
+                write_proxy(&mut self.inner, self.host.as_bytes()).await?;
+            }
+        }
+        write_proxy(&mut self.inner, &self.port.to_be_bytes()).await?;
+
+        let mut reply = [0; 4];
+        read_proxy(&mut self.inner, &mut reply).await?;
+        if reply[0] != SOCKS_VERSION || reply[1] != 0 {
+            error!("SOCKS5 proxy rejected CONNECT with reply {}", reply[1]);
+            return Err(NetworkError::ProxyFailed);
+        }
+
+        // Skip the bound address and port
+        let address_len = match reply[3] {
+            SOCKS_ADDRESS_IPV4 => 4,
+            SOCKS_ADDRESS_IPV6 => 16,
+            SOCKS_ADDRESS_DOMAIN => {
+                let mut len = [0];
+                read_proxy(&mut self.inner, &mut len).await?;
+                len[0] as usize
+            },
+            address_type => {
+                error!("invalid SOCKS5 address type {}", address_type);
+                return Err(NetworkError::ProxyFailed);
+            }
+        };
+        let mut bound = [0; 255 + 2];
+        read_proxy(&mut self.inner, &mut bound[..address_len + 2]).await
+    }
+}
+
+macro_rules! impl_proxy_connection {
+    ($proxy:ident) => {
+        impl <'a, C: NetworkConnection> ErrorType for $proxy<'a, C> {
+            type Error = C::Error;
+        }
+
+        impl <'a, C: NetworkConnection> Read for $proxy<'a, C> {
+            async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+                self.inner.read(buf).await
+            }
+        }
+
+        impl <'a, C: NetworkConnection> TryRead for $proxy<'a, C> {
+            async fn try_read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+                self.inner.try_read(buf).await
+            }
+        }
+
+        impl <'a, C: NetworkConnection> Write for $proxy<'a, C> {
+            async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+                self.inner.write(buf).await
+            }
+
+            async fn flush(&mut self) -> Result<(), Self::Error> {
+                self.inner.flush().await
+            }
+        }
+
+        impl <'a, C: NetworkConnection> TryWrite for $proxy<'a, C> {
+            async fn try_write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+                self.inner.try_write(buf).await
+            }
+        }
+
+        impl <'a, C: NetworkConnection> NetworkConnection for $proxy<'a, C> {
+            async fn connect(&mut self) -> Result<(), NetworkError> {
+                self.inner.connect().await?;
+                self.handshake().await
+            }
+        }
+    };
+}
+
+impl_proxy_connection!(HttpConnectProxy);
+impl_proxy_connection!(Socks5Proxy);
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{net::SocketAddr, string::String, vec::Vec};
+
+    use embedded_io_async::{Read, Write};
+    use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}};
+
+    use crate::{std::StdNetworkConnection, NetworkConnection, NetworkError};
+
+    use super::{HttpConnectProxy, Socks5Proxy};
+
+    async fn echo(mut socket: impl AsyncReadExt + AsyncWriteExt + Unpin) {
+        let mut buf = [0; 64];
+        let n = socket.read(&mut buf).await.unwrap();
+        socket.write_all(&buf[..n]).await.unwrap();
+    }
+
+    /// HTTP proxy which expects a CONNECT to broker:1883 and then echoes instead of tunnelling
+    async fn start_http_proxy() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut socket = BufReader::new(socket);
+
+                let mut request = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    socket.read_line(&mut line).await.unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    request.push(line);
+                }
+
+                assert_eq!(request[0], "CONNECT broker:1883 HTTP/1.1\r\n");
+                // base64 of user:secret
+                if ! request.contains(&"Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n".into()) {
+                    socket.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await.unwrap();
+                    continue;
+                }
+
+                socket.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.unwrap();
+                echo(socket).await;
+            }
+        });
+
+        addr
+    }
+
+    /// SOCKS5 proxy which expects a CONNECT to broker:1883 and then echoes instead of tunnelling
+    async fn start_socks5_proxy() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut socket, _): (TcpStream, _) = listener.accept().await.unwrap();
+
+            let mut greeting = [0; 4];
+            socket.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 2, 0, 2]);
+            socket.write_all(&[5, 2]).await.unwrap();
+
+            let mut auth = [0; 13];
+            socket.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x06secret");
+            socket.write_all(&[1, 0]).await.unwrap();
+
+            let mut request = [0; 13];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"\x05\x01\x00\x03\x06broker\x07\x5b");
+            socket.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x07, 0x5b]).await.unwrap();
+
+            echo(socket).await;
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let proxy_addr = start_http_proxy().await;
+
+        let mut connection = HttpConnectProxy::new(StdNetworkConnection::new(proxy_addr), "broker", 1883)
+            .with_credentials("user", "secret");
+        connection.connect().await.unwrap();
+
+        connection.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        connection.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let mut connection = HttpConnectProxy::new(StdNetworkConnection::new(proxy_addr), "broker", 1883)
+            .with_credentials("user", "wrong");
+        assert_eq!(connection.connect().await, Err(NetworkError::ProxyFailed));
+    }
+
+    #[tokio::test]
+    async fn test_socks5() {
+        let proxy_addr = start_socks5_proxy().await;
+
+        let mut connection = Socks5Proxy::new(StdNetworkConnection::new(proxy_addr), "broker", 1883)
+            .with_credentials("user", "secret");
+        connection.connect().await.unwrap();
+
+        connection.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        connection.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
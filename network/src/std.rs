@@ -5,25 +5,184 @@ extern crate std;
 
 use embedded_io_async::Write;
 use embedded_io_async::{ErrorKind, ErrorType, Read};
-use tokio::net::{TcpStream, ToSocketAddrs};
+use std::{io, net::{IpAddr, SocketAddr}, string::String, time::Duration, vec, vec::Vec};
+
+use tokio::net::{lookup_host, TcpSocket, TcpStream, ToSocketAddrs};
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 
-use crate::Debug2Format;
+use crate::{Debug2Format, EndpointOrder, END_OF_STREAM};
+
+use super::{NetworkError, NetworkOperation};
 
-use super::NetworkError;
+/// Settings of the tcp connection of [`StdNetworkConnection`]
+#[derive(Debug, Clone, Default)]
+struct StdConnectionOptions {
+    connect_timeout: Option<Duration>,
+    nodelay: bool,
+    keep_alive: Option<Duration>,
+    bind_address: Option<IpAddr>,
+    bind_device: Option<String>
+}
 
 pub struct StdNetworkConnection<T: ToSocketAddrs> {
     stream: Option<TcpStream>,
-    addr: T
+    endpoints: Vec<T>,
+    order: EndpointOrder,
+    options: StdConnectionOptions,
+    next_endpoint: usize,
+    connected: Option<SocketAddr>
 }
 
 impl <T: ToSocketAddrs> StdNetworkConnection<T> {
     pub fn new(addr: T) -> Self {
+        Self::with_endpoints(vec![addr])
+    }
+
+    /// Creates a connection which tries the `endpoints` and all their resolved addresses
+    /// until one accepts the connection
+    pub fn with_endpoints(endpoints: impl IntoIterator<Item = T>) -> Self {
         Self {
             stream: None,
-            addr
+            endpoints: endpoints.into_iter().collect(),
+            order: EndpointOrder::default(),
+            options: StdConnectionOptions::default(),
+            next_endpoint: 0,
+            connected: None
+        }
+    }
+
+    pub fn builder(addr: T) -> StdNetworkConnectionBuilder<T> {
+        Self::new(addr).into_builder()
+    }
+
+    /// Builder to set socket options of a connection with several endpoints
+    pub fn into_builder(self) -> StdNetworkConnectionBuilder<T> {
+        StdNetworkConnectionBuilder {
+            connection: self
+        }
+    }
+
+    pub fn with_endpoint_order(mut self, order: EndpointOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// The address of the current connection
+    pub fn connected_endpoint(&self) -> Option<SocketAddr> {
+        self.connected
+    }
+
+    /// Tries all resolved addresses of the endpoint
+    async fn connect_endpoint(&self, endpoint: &T) -> Result<(TcpStream, SocketAddr), NetworkError> {
+        let addrs = lookup_host(endpoint).await
+            .map_err(|e| {
+                warn!("cannot resolve endpoint: {}", Debug2Format(e));
+                NetworkError::DnsFailed
+            })?;
+
+        let mut result = Err(NetworkError::HostNotFound);
+        for addr in addrs {
+            match self.connect_addr(addr).await {
+                Ok(stream) => return Ok((stream, addr)),
+                Err(e) => {
+                    warn!("connecting to {} failed: {}", addr, &e);
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    async fn connect_addr(&self, addr: SocketAddr) -> Result<TcpStream, NetworkError> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4(),
+            SocketAddr::V6(_) => TcpSocket::new_v6(),
+        }.map_err(connect_error)?;
+
+        if let Some(bind_address) = self.options.bind_address {
+            socket.bind(SocketAddr::new(bind_address, 0))
+                .map_err(connect_error)?;
+        }
+
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(device) = &self.options.bind_device {
+            socket.bind_device(Some(device.as_bytes()))
+                .map_err(connect_error)?;
         }
+
+        socket.set_nodelay(self.options.nodelay)
+            .map_err(connect_error)?;
+
+        if let Some(keep_alive) = self.options.keep_alive {
+            let keep_alive = socket2::TcpKeepalive::new().with_time(keep_alive);
+            socket2::SockRef::from(&socket).set_tcp_keepalive(&keep_alive)
+                .map_err(connect_error)?;
+        }
+
+        let stream = match self.options.connect_timeout {
+            Some(connect_timeout) => tokio::time::timeout(connect_timeout, socket.connect(addr)).await
+                .map_err(|_| NetworkError::Timeout)?,
+            None => socket.connect(addr).await
+        };
+
+        stream.map_err(connect_error)
+    }
+}
+
+fn connect_error(e: io::Error) -> NetworkError {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => NetworkError::ConnectionRefused,
+        io::ErrorKind::TimedOut => NetworkError::Timeout,
+        _ => {
+            error!("tcp connect failed: {}", Debug2Format(&e));
+            NetworkError::Io(NetworkOperation::Connect, e.kind().into())
+        }
+    }
+}
+
+/// Builder of a [`StdNetworkConnection`] with socket options
+///
+/// The endpoints are set on the connection, see [`StdNetworkConnection::with_endpoints`].
+pub struct StdNetworkConnectionBuilder<T: ToSocketAddrs> {
+    connection: StdNetworkConnection<T>
+}
+
+impl <T: ToSocketAddrs> StdNetworkConnectionBuilder<T> {
+    /// Connecting to an address fails with [`NetworkError::Timeout`] after `connect_timeout`
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connection.options.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Sets `TCP_NODELAY` to send small writes immediately
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.connection.options.nodelay = nodelay;
+        self
+    }
+
+    /// Enables tcp keep-alive packets after `idle` time without traffic
+    pub fn keep_alive(mut self, idle: Duration) -> Self {
+        self.connection.options.keep_alive = Some(idle);
+        self
+    }
+
+    /// Local address the socket is bound to.
+    /// Only endpoint addresses of the same IP version can be reached then.
+    pub fn bind_address(mut self, bind_address: IpAddr) -> Self {
+        self.connection.options.bind_address = Some(bind_address);
+        self
+    }
+
+    /// Network interface the socket is bound to, e.g. `eth0`
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    pub fn bind_device(mut self, device: &str) -> Self {
+        self.connection.options.bind_device = Some(device.into());
+        self
+    }
+
+    pub fn build(self) -> StdNetworkConnection<T> {
+        self.connection
     }
 }
 
@@ -39,21 +198,19 @@ impl <T: ToSocketAddrs> super::TryRead for StdNetworkConnection<T> {
             .try_read(buf);
 
         match result {
-            Ok(n) => {
-                if n == 0 && ! buf.is_empty(){
-                    warn!("std net try_read: read 0 bytes");
-                    Err(ErrorKind::ConnectionReset)
-                } else {
-                    Ok(n)
-                }
+            // The socket was ready because the stream ended
+            Ok(0) if ! buf.is_empty() => {
+                trace!("try_read: end of stream");
+                Err(END_OF_STREAM)
             },
+            Ok(n) => Ok(n),
             Err(e) => {
                 if e.kind() == tokio::io::ErrorKind::WouldBlock {
                     trace!("try_read: network would block");
                     Ok(0) 
                 } else { 
-                    error!("error try_reading from std net: {}", Debug2Format(e));
-                    Err(ErrorKind::Other) 
+                    error!("error try_reading from std net: {}", Debug2Format(&e));
+                    Err(e.kind().into())
                 }
             }
         }
@@ -79,8 +236,8 @@ impl <T: ToSocketAddrs> super::TryWrite for StdNetworkConnection<T> {
                     trace!("try_write: network would block");
                     Ok(0) 
                 } else { 
-                    error!("error try_writing to std net: {:?}", Debug2Format(e));
-                    Err(ErrorKind::Other) 
+                    error!("error try_writing to std net: {:?}", Debug2Format(&e));
+                    Err(e.kind().into())
                 }
             }
         }
@@ -91,20 +248,19 @@ impl <T: ToSocketAddrs> Read for StdNetworkConnection<T> {
     async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
         self.stream.as_ref().ok_or(ErrorKind::Other)?.readable().await
         .map_err(|e| {
-            error!("error waiting for std net to be readable: {}", Debug2Format(e));
-            ErrorKind::Other
+            error!("error waiting for std net to be readable: {}", Debug2Format(&e));
+            ErrorKind::from(e.kind())
         })?;
 
         let n = self.stream.as_mut().ok_or(ErrorKind::Other)?
             .read(buf).await
             .map_err(|e| {
-                error!("error reading from std net: {}", Debug2Format(e));
-                ErrorKind::Other
+                error!("error reading from std net: {}", Debug2Format(&e));
+                ErrorKind::from(e.kind())
             })?;
 
         if n == 0 && ! buf.is_empty(){
-            warn!("std net read: read 0 bytes");
-            return Err(ErrorKind::ConnectionReset);
+            trace!("std net read: end of stream");
         }
         Ok(n)
     }
@@ -115,8 +271,8 @@ impl <T: ToSocketAddrs> Write for StdNetworkConnection<T> {
         let n = self.stream.as_mut().ok_or(ErrorKind::Other)?
             .write(buf).await
             .map_err(|e| {
-                error!("error writing to std net: {}", Debug2Format(e));
-                ErrorKind::Other
+                error!("error writing to std net: {}", Debug2Format(&e));
+                ErrorKind::from(e.kind())
             })?;
 
         if n == 0 && ! buf.is_empty(){
@@ -129,9 +285,160 @@ impl <T: ToSocketAddrs> Write for StdNetworkConnection<T> {
 
 impl <T: ToSocketAddrs> super::NetworkConnection for StdNetworkConnection<T> {
     async fn connect(&mut self) -> Result<(), NetworkError> {
-        let stream: TcpStream = TcpStream::connect(&self.addr).await
-            .map_err(|_| NetworkError::ConnectionFailed)?;
-        self.stream = Some(stream);
-        Ok(())
+        self.stream = None;
+        self.connected = None;
+
+        let start = match self.order {
+            EndpointOrder::InOrder => 0,
+            EndpointOrder::RoundRobin => self.next_endpoint,
+        };
+
+        let mut result = Err(NetworkError::HostNotFound);
+        for i in 0..self.endpoints.len() {
+            let index = (start + i) % self.endpoints.len();
+
+            match self.connect_endpoint(&self.endpoints[index]).await {
+                Ok((stream, addr)) => {
+                    info!("connected to {}", addr);
+                    self.stream = Some(stream);
+                    self.connected = Some(addr);
+                    self.next_endpoint = (index + 1) % self.endpoints.len();
+                    return Ok(());
+                },
+                Err(e) => result = Err(e),
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{io::Write, net::{Ipv4Addr, SocketAddr, TcpListener}, time::Duration};
+
+    use buffer::new_stack_buffer;
+    use embedded_io_async::ErrorKind;
+
+    use crate::{EndpointOrder, NetwordSendReceive, NetworkConnection, NetworkError, NetworkOperation};
+
+    use super::StdNetworkConnection;
+
+    fn unused_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut connection = StdNetworkConnection::with_endpoints([unused_addr(), addr]);
+        connection.connect().await.unwrap();
+        assert_eq!(connection.connected_endpoint(), Some(addr));
+
+        let mut connection = StdNetworkConnection::with_endpoints([unused_addr(), unused_addr()]);
+        assert_eq!(connection.connect().await, Err(crate::NetworkError::ConnectionRefused));
+        assert_eq!(connection.connected_endpoint(), None);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_builder() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut connection = StdNetworkConnection::with_endpoints([unused_addr(), addr])
+            .with_endpoint_order(EndpointOrder::RoundRobin)
+            .into_builder()
+            .connect_timeout(Duration::from_secs(1))
+            .nodelay(true)
+            .keep_alive(Duration::from_secs(30))
+            .bind_address(Ipv4Addr::LOCALHOST.into())
+            .build();
+
+        connection.connect().await.unwrap();
+        assert_eq!(connection.connected_endpoint(), Some(addr));
+
+        let stream = connection.stream.as_ref().unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(socket2::SockRef::from(stream).keepalive().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() {
+        let listener1 = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listener2 = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr1 = listener1.local_addr().unwrap();
+        let addr2 = listener2.local_addr().unwrap();
+
+        let mut connection = StdNetworkConnection::with_endpoints([addr1, addr2])
+            .with_endpoint_order(EndpointOrder::RoundRobin);
+
+        for expected in [addr1, addr2, addr1] {
+            connection.connect().await.unwrap();
+            assert_eq!(connection.connected_endpoint(), Some(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_closed_and_reset() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut connection = StdNetworkConnection::new(listener.local_addr().unwrap());
+        let mut buf = new_stack_buffer::<16>();
+
+        // The peer closes the connection after sending
+        connection.connect().await.unwrap();
+        let (mut socket, _) = listener.accept().unwrap();
+        socket.write_all(b"hi").unwrap();
+        drop(socket);
+
+        assert_eq!(connection.receive(&mut buf).await, Ok(2));
+        let e = connection.receive(&mut buf).await.unwrap_err();
+        assert_eq!(e, NetworkError::Closed(NetworkOperation::Read));
+        assert!(e.is_closed());
+
+        // The peer resets the connection
+        connection.connect().await.unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        socket2::SockRef::from(&socket).set_linger(Some(Duration::ZERO)).unwrap();
+        drop(socket);
+
+        let e = connection.receive(&mut buf).await.unwrap_err();
+        assert_eq!(e, NetworkError::Io(NetworkOperation::Read, ErrorKind::ConnectionReset));
+        assert_eq!(e.operation(), Some(NetworkOperation::Read));
+        assert_eq!(e.kind(), Some(ErrorKind::ConnectionReset));
+        assert!(! e.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_try_receive_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut connection = StdNetworkConnection::new(listener.local_addr().unwrap());
+        let mut buf = new_stack_buffer::<16>();
+
+        connection.connect().await.unwrap();
+        let (mut socket, _) = listener.accept().unwrap();
+
+        // Nothing was sent yet
+        assert_eq!(connection.try_receive(&mut buf).await, Ok(0));
+
+        socket.write_all(b"hi").unwrap();
+        drop(socket);
+
+        // The data comes first, then the end of the stream instead of Ok(0)
+        let mut received = 0;
+        let mut attempts = 0;
+        let e = loop {
+            match connection.try_receive(&mut buf).await {
+                Ok(n) => received += n,
+                Err(e) => break e
+            }
+            attempts += 1;
+            assert!(attempts < 1000, "end of stream not reported");
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        };
+        assert_eq!(received, 2);
+        assert_eq!(e, NetworkError::Closed(NetworkOperation::Read));
+    }
+}
@@ -0,0 +1,102 @@
+//! [`NetworkConnection`] for any embedded-io-async stream, e.g. a modem socket over UART
+
+use core::future::Future;
+
+use embedded_io_async::{Error, ErrorKind, ErrorType, Read, ReadReady, Write, WriteReady};
+
+use crate::{NetworkConnection, NetworkError};
+
+/// Creates a fresh stream for every connect
+///
+/// Implemented for closures returning a future of the stream.
+pub trait StreamFactory {
+    type Stream: Read + Write + ReadReady + WriteReady;
+
+    fn create(&mut self) -> impl Future<Output = Result<Self::Stream, NetworkError>>;
+}
+
+impl <F, Fut, S> StreamFactory for F
+where F: FnMut() -> Fut, Fut: Future<Output = Result<S, NetworkError>>, S: Read + Write + ReadReady + WriteReady {
+    type Stream = S;
+
+    fn create(&mut self) -> impl Future<Output = Result<Self::Stream, NetworkError>> {
+        self()
+    }
+}
+
+/// Connection over the streams of a [`StreamFactory`]
+///
+/// The previous stream is dropped before a new one is created.
+pub struct StreamConnection<F: StreamFactory> {
+    factory: F,
+    stream: Option<F::Stream>
+}
+
+impl <F: StreamFactory> StreamConnection<F> {
+    pub fn new(factory: F) -> Self {
+        Self {
+            factory,
+            stream: None
+        }
+    }
+
+    fn stream(&mut self) -> Result<&mut F::Stream, ErrorKind> {
+        self.stream.as_mut().ok_or(ErrorKind::NotConnected)
+    }
+}
+
+impl <F: StreamFactory> ErrorType for StreamConnection<F> {
+    type Error = ErrorKind;
+}
+
+impl <F: StreamFactory> Read for StreamConnection<F> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let n = self.stream()?.read(buf).await
+            .map_err(|e| e.kind())?;
+
+        if n == 0 && ! buf.is_empty() {
+            trace!("stream read: end of stream");
+        }
+        Ok(n)
+    }
+}
+
+impl <F: StreamFactory> ReadReady for StreamConnection<F> {
+    fn read_ready(&mut self) -> Result<bool, Self::Error> {
+        self.stream()?.read_ready()
+            .map_err(|e| e.kind())
+    }
+}
+
+impl <F: StreamFactory> Write for StreamConnection<F> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let n = self.stream()?.write(buf).await
+            .map_err(|e| e.kind())?;
+
+        if n == 0 && ! buf.is_empty() {
+            warn!("stream write: 0 written bytes");
+            return Err(ErrorKind::ConnectionReset);
+        }
+        Ok(n)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.stream()?.flush().await
+            .map_err(|e| e.kind())
+    }
+}
+
+impl <F: StreamFactory> WriteReady for StreamConnection<F> {
+    fn write_ready(&mut self) -> Result<bool, Self::Error> {
+        self.stream()?.write_ready()
+            .map_err(|e| e.kind())
+    }
+}
+
+impl <F: StreamFactory> NetworkConnection for StreamConnection<F> {
+    async fn connect(&mut self) -> Result<(), NetworkError> {
+        self.stream = None;
+        self.stream = Some(self.factory.create().await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,770 @@
+use core::{marker::PhantomData, mem};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use embedded_io_async::{Error, ErrorKind, ErrorType, Read, Write};
+use embedded_tls::{Aes128GcmSha256, Certificate, TlsConnection, TlsContext, TlsError, TlsVerifier, TLS_RECORD_OVERHEAD};
+use rand_core::{CryptoRng, RngCore};
+
+use crate::{Debug2Format, NetworkConnection, NetworkError, TryRead, TryWrite, END_OF_STREAM};
+
+use super::TlsConfig;
+
+/// Size of a TLS record header
+const RECORD_HEADER_SIZE: usize = 5;
+
+/// Smallest write buffer which leaves room for some plaintext in every record
+const MIN_WRITE_BUFFER_SIZE: usize = 1 + 2 * (TLS_RECORD_OVERHEAD + 64);
+
+/// struct that contains the pointers to the TLS record buffers
+///
+/// The read buffer must hold a complete record, use 16640 bytes if the
+/// broker may send records of maximum size.
+/// Half of the write buffer holds the record being written, the other half keeps
+/// encrypted records until they are sent.
+pub struct EmbeddedTlsResources<'a> {
+    read_record_buffer: &'a mut [u8],
+    write_record_buffer: &'a mut [u8]
+}
+
+impl <'a> EmbeddedTlsResources<'a> {
+
+    pub fn new(read_record_buffer: &'a mut [u8], write_record_buffer: &'a mut [u8]) -> Self {
+        Self {
+            read_record_buffer,
+            write_record_buffer
+        }
+    }
+
+    fn unwrap(&mut self) -> (&mut [u8], &mut [u8]) {
+        (self.read_record_buffer, self.write_record_buffer)
+    }
+
+    /// unwrap the record buffers with the lifetime of the resources
+    ///
+    /// # Safety
+    ///
+    /// The buffers must not be used by more than one [`TlsConnection`] at a time.
+    unsafe fn unwrap_unsafe(&mut self) -> (&'a mut [u8], &'a mut [u8]) {
+        let ( read_record_buffer, write_record_buffer ) = self.unwrap();
+
+        (
+            mem::transmute::<&mut [u8], &'a mut [u8]>(read_record_buffer),
+            mem::transmute::<&mut [u8], &'a mut [u8]>(write_record_buffer)
+        )
+    }
+}
+
+/// State shared by a [`Session`] and its [`Socket`]
+#[derive(Clone, Copy)]
+struct Flags<'a>(&'a AtomicU8);
+
+impl <'a> Flags<'a> {
+    /// The socket must not block
+    const TRY: u8 = 1;
+
+    /// The socket stopped an operation because it would block
+    const WOULD_BLOCK: u8 = 2;
+
+    /// The socket keeps records which are not sent yet
+    const STAGED: u8 = 4;
+
+    fn new(byte: &'a mut u8) -> Self {
+        *byte = 0;
+        // Safety: the byte is borrowed for 'a and only accessed through the atomic
+        Self(unsafe { AtomicU8::from_ptr(byte) })
+    }
+
+    fn get(self, flag: u8) -> bool {
+        self.0.load(Ordering::Relaxed) & flag != 0
+    }
+
+    fn set(self, flag: u8, value: bool) {
+        let flags = self.0.load(Ordering::Relaxed);
+        self.0.store(if value { flags | flag } else { flags & !flag }, Ordering::Relaxed);
+    }
+}
+
+/// Position in the TLS records received from the broker
+#[derive(Debug, Default)]
+struct RecordPosition {
+    header: [u8; RECORD_HEADER_SIZE],
+
+    /// Number of bytes of `header` received
+    header_received: usize,
+
+    /// Number of bytes of the record body not received yet
+    body_left: usize
+}
+
+impl RecordPosition {
+    fn on_received(&mut self, mut data: &[u8]) {
+        while ! data.is_empty() {
+            if self.body_left > 0 {
+                let n = usize::min(self.body_left, data.len());
+                self.body_left -= n;
+                data = &data[n..];
+            } else {
+                self.header[self.header_received] = data[0];
+                self.header_received += 1;
+                data = &data[1..];
+
+                if self.header_received == RECORD_HEADER_SIZE {
+                    self.body_left = u16::from_be_bytes([self.header[3], self.header[4]]) as usize;
+                    self.header_received = 0;
+                }
+            }
+        }
+    }
+
+    /// embedded-tls keeps what it read of a record header when a read fails,
+    /// but loses its place if a read of the record body fails
+    fn may_stop(&self) -> bool {
+        self.body_left == 0
+    }
+}
+
+/// Connection passed to embedded-tls
+///
+/// The records written by embedded-tls are copied and sent from here, a record is only
+/// taken as a whole, so no record embedded-tls closed is lost if an operation is dropped.
+struct Socket<'a, C: NetworkConnection> {
+    inner: C,
+    flags: Flags<'a>,
+    position: RecordPosition,
+
+    /// Records to send, `staging[sent..staged]` is not sent yet
+    staging: &'a mut [u8],
+    staged: usize,
+    sent: usize
+}
+
+impl <'a, C: NetworkConnection> Socket<'a, C> {
+
+    fn on_sent(&mut self, n: usize) {
+        self.sent += n;
+        if self.sent == self.staged {
+            self.sent = 0;
+            self.staged = 0;
+            self.flags.set(Flags::STAGED, false);
+        }
+    }
+
+    /// Sends the staged records. Cancel safe: a later call continues after the bytes sent so far.
+    async fn send_staged(&mut self) -> Result<(), ErrorKind> {
+        while self.sent < self.staged {
+            let n = self.inner.write(&self.staging[self.sent..self.staged]).await
+                .map_err(|e| e.kind())?;
+            if n == 0 {
+                return Err(ErrorKind::WriteZero);
+            }
+            self.on_sent(n);
+        }
+        Ok(())
+    }
+
+    /// Sends the staged records as far as it does not block
+    async fn try_send_staged(&mut self) -> Result<(), ErrorKind> {
+        while self.sent < self.staged {
+            let n = self.inner.try_write(&self.staging[self.sent..self.staged]).await
+                .map_err(|e| e.kind())?;
+            if n == 0 {
+                self.flags.set(Flags::WOULD_BLOCK, true);
+                break;
+            }
+            self.on_sent(n);
+        }
+        Ok(())
+    }
+
+    /// Moves the unsent records to the start of the staging buffer
+    fn compact(&mut self) {
+        self.staging.copy_within(self.sent..self.staged, 0);
+        self.staged -= self.sent;
+        self.sent = 0;
+    }
+
+    fn would_block(&self) -> ErrorKind {
+        self.flags.set(Flags::WOULD_BLOCK, true);
+        ErrorKind::Interrupted
+    }
+}
+
+impl <'a, C: NetworkConnection> ErrorType for Socket<'a, C> {
+    type Error = ErrorKind;
+}
+
+impl <'a, C: NetworkConnection> Read for Socket<'a, C> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let n = if self.flags.get(Flags::TRY) {
+            // Records which cannot be sent now do not stop the read
+            self.try_send_staged().await?;
+            self.flags.set(Flags::WOULD_BLOCK, false);
+
+            if self.position.may_stop() {
+                let n = self.inner.try_read(buf).await
+                    .map_err(|e| e.kind())?;
+                if n == 0 {
+                    return Err(self.would_block());
+                }
+                n
+            } else {
+                // The broker sends the rest of a record without delay
+                self.inner.read(buf).await
+                    .map_err(|e| e.kind())?
+            }
+        } else {
+            // The broker may wait for the records staged before
+            self.send_staged().await?;
+            self.inner.read(buf).await
+                .map_err(|e| e.kind())?
+        };
+
+        if n == 0 && ! buf.is_empty() {
+            return Err(END_OF_STREAM);
+        }
+
+        self.position.on_received(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl <'a, C: NetworkConnection> Write for Socket<'a, C> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.len() > self.staging.len() {
+            // Only handshake records are this large, a dropped handshake fails the connect anyway
+            self.send_staged().await?;
+            return self.inner.write(buf).await
+                .map_err(|e| e.kind());
+        }
+
+        if self.staging.len() - self.staged < buf.len() {
+            self.compact();
+        }
+
+        // A record dropped before it is taken was never sent and did not advance the record counter
+        while self.staging.len() - self.staged < buf.len() {
+            if self.flags.get(Flags::TRY) {
+                self.try_send_staged().await?;
+                if self.flags.get(Flags::WOULD_BLOCK) {
+                    return Err(ErrorKind::Interrupted);
+                }
+            } else {
+                let n = self.inner.write(&self.staging[self.sent..self.staged]).await
+                    .map_err(|e| e.kind())?;
+                if n == 0 {
+                    return Err(ErrorKind::WriteZero);
+                }
+                self.on_sent(n);
+            }
+            self.compact();
+        }
+
+        self.staging[self.staged..self.staged + buf.len()].copy_from_slice(buf);
+        self.staged += buf.len();
+        self.flags.set(Flags::STAGED, true);
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        if self.flags.get(Flags::TRY) {
+            return self.try_send_staged().await;
+        }
+
+        self.send_staged().await?;
+        self.inner.flush().await
+            .map_err(|e| e.kind())
+    }
+}
+
+/// Open TLS session
+struct Session<'a, C: NetworkConnection> {
+    tls: TlsConnection<'a, Socket<'a, C>, Aes128GcmSha256>,
+    flags: Flags<'a>,
+
+    /// Plaintext written to the open record
+    buffered: usize,
+
+    /// Plaintext which fits into a record without embedded-tls sending it
+    capacity: usize
+}
+
+impl <'a, C: NetworkConnection> Session<'a, C> {
+
+    fn space(&self) -> usize {
+        self.capacity - self.buffered
+    }
+
+    /// Sets the mode of the socket for the next operation
+    fn start(&self, try_mode: bool) {
+        self.flags.set(Flags::TRY, try_mode);
+        self.flags.set(Flags::WOULD_BLOCK, false);
+    }
+
+    /// Sends the open record and the records staged before.
+    /// Cancel safe: embedded-tls closes the record and the socket takes it in the first poll,
+    /// the socket keeps it until it is sent.
+    async fn flush_records(&mut self, try_mode: bool) -> Result<(), ErrorKind> {
+        self.start(try_mode);
+
+        if self.buffered == 0 {
+            if ! self.flags.get(Flags::STAGED) {
+                return Ok(());
+            }
+
+            // Only a record reaches the socket, an empty one lets it send the records staged before
+            self.tls.write(&[]).await
+                .map_err(|e| {
+                    error!("error writing to TLS connection: {}", Debug2Format(&e));
+                    ErrorKind::ConnectionReset
+                })?;
+        }
+
+        self.buffered = 0;
+        match self.tls.flush().await {
+            Ok(()) => Ok(()),
+            Err(_) if self.flags.get(Flags::WOULD_BLOCK) => Ok(()),
+            Err(e) => {
+                error!("error flushing TLS connection: {}", Debug2Format(&e));
+                Err(ErrorKind::ConnectionReset)
+            }
+        }
+    }
+
+    /// Takes as much of `buf` into the open record as fits without sending it
+    async fn buffer(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+        let n = usize::min(buf.len(), self.space());
+
+        // Completes without I/O: the record is open or empty and never becomes full
+        let n = self.tls.write(&buf[..n]).await
+            .map_err(|e| {
+                error!("error writing to TLS connection: {}", Debug2Format(&e));
+                ErrorKind::ConnectionReset
+            })?;
+
+        self.buffered += n;
+        Ok(n)
+    }
+
+    async fn read(&mut self, buf: &mut [u8], try_mode: bool) -> Result<usize, ErrorKind> {
+        self.start(try_mode);
+
+        match self.tls.read(buf).await {
+            Ok(n) => Ok(n),
+            Err(_) if self.flags.get(Flags::WOULD_BLOCK) => Ok(0),
+            // Brokers often close the connection without close_notify
+            Err(TlsError::ConnectionClosed) | Err(TlsError::Io(END_OF_STREAM)) => {
+                trace!("TLS connection closed by broker");
+                if try_mode { Err(END_OF_STREAM) } else { Ok(0) }
+            },
+            Err(e) => {
+                error!("error reading from TLS connection: {}", Debug2Format(&e));
+                Err(ErrorKind::ConnectionReset)
+            }
+        }
+    }
+}
+
+// A session cannot be boxed without an allocator
+#[allow(clippy::large_enum_variant)]
+enum State<'a, C: NetworkConnection> {
+    Disconnected(C),
+    Connected(Session<'a, C>)
+}
+
+/// TLS connection with embedded-tls over the `inner` connection
+///
+/// embedded-tls supports TLS 1.3 only. It takes exactly one CA and cannot authenticate the client.
+/// The broker certificate is verified by a new `V` for every connect, for example
+/// `embedded_tls::webpki::CertVerifier` with the `webpki` feature of embedded-tls.
+/// `rng` must be a cryptographically secure random number generator.
+///
+/// Written data is sent with the next read, write or flush. [`TryRead::try_read`] waits
+/// for the rest of a record the broker started to send.
+pub struct EmbeddedTlsConnection<'a, C, V, R>
+where C: NetworkConnection, V: for<'v> TlsVerifier<'v, Aes128GcmSha256>, R: CryptoRng + RngCore {
+    state: Option<State<'a, C>>,
+    config: TlsConfig<'a>,
+    resources: EmbeddedTlsResources<'a>,
+    rng: R,
+    verifier: PhantomData<V>
+}
+
+impl <'a, C, V, R> EmbeddedTlsConnection<'a, C, V, R>
+where C: NetworkConnection, V: for<'v> TlsVerifier<'v, Aes128GcmSha256>, R: CryptoRng + RngCore {
+
+    /// Creates the connection, fails if `config` uses what embedded-tls does not support
+    /// or the write buffer is too small
+    pub fn new(inner: C, config: TlsConfig<'a>, resources: EmbeddedTlsResources<'a>, rng: R) -> Result<Self, NetworkError> {
+        check_config(&config)?;
+
+        if resources.write_record_buffer.len() < MIN_WRITE_BUFFER_SIZE {
+            error!("TLS write record buffer too small: {} < {}", resources.write_record_buffer.len(), MIN_WRITE_BUFFER_SIZE);
+            return Err(NetworkError::TlsFailed);
+        }
+
+        Ok(Self {
+            state: Some(State::Disconnected(inner)),
+            config,
+            resources,
+            rng,
+            verifier: PhantomData
+        })
+    }
+
+    fn session(&mut self) -> Result<&mut Session<'a, C>, ErrorKind> {
+        match self.state.as_mut() {
+            Some(State::Connected(session)) => Ok(session),
+            _ => Err(ErrorKind::NotConnected)
+        }
+    }
+
+    /// Closes the TLS session if there is one and returns the inner connection
+    async fn take_inner(&mut self) -> Result<C, NetworkError> {
+        match self.state.take() {
+            Some(State::Disconnected(inner)) => Ok(inner),
+            Some(State::Connected(session)) => {
+                trace!("closing existing TLS session");
+                session.start(false);
+                Ok(close(session.tls).await)
+            },
+            None => {
+                error!("inner connection of TLS connection lost");
+                Err(NetworkError::ConnectionFailed)
+            }
+        }
+    }
+
+    async fn open(&mut self, tls: &mut TlsConnection<'a, Socket<'a, C>, Aes128GcmSha256>) -> Result<(), NetworkError> {
+        // Checked by new()
+        let config = embedded_tls::TlsConfig::new()
+            .with_server_name(self.config.server_name)
+            .with_ca(Certificate::X509(self.config.ca_certificates[0]));
+
+        tls.open::<R, V>(TlsContext::new(&config, &mut self.rng)).await
+            .map_err(|e| {
+                error!("TLS handshake failed: {}", Debug2Format(&e));
+                NetworkError::TlsFailed
+            })
+    }
+}
+
+/// Closes the TLS session and returns the inner connection
+async fn close<'a, C: NetworkConnection>(tls: TlsConnection<'a, Socket<'a, C>, Aes128GcmSha256>) -> C {
+    match tls.close().await {
+        Ok(socket) => socket.inner,
+        Err((socket, e)) => {
+            warn!("closing TLS session failed: {}", Debug2Format(&e));
+            socket.inner
+        }
+    }
+}
+
+impl <'a, C, V, R> ErrorType for EmbeddedTlsConnection<'a, C, V, R>
+where C: NetworkConnection, V: for<'v> TlsVerifier<'v, Aes128GcmSha256>, R: CryptoRng + RngCore {
+    type Error = ErrorKind;
+}
+
+impl <'a, C, V, R> Read for EmbeddedTlsConnection<'a, C, V, R>
+where C: NetworkConnection, V: for<'v> TlsVerifier<'v, Aes128GcmSha256>, R: CryptoRng + RngCore {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let session = self.session()?;
+
+        // The broker may wait for the records written before
+        session.flush_records(false).await?;
+        session.read(buf, false).await
+    }
+}
+
+impl <'a, C, V, R> TryRead for EmbeddedTlsConnection<'a, C, V, R>
+where C: NetworkConnection, V: for<'v> TlsVerifier<'v, Aes128GcmSha256>, R: CryptoRng + RngCore {
+    async fn try_read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let session = self.session()?;
+
+        session.flush_records(true).await?;
+        session.read(buf, true).await
+    }
+}
+
+impl <'a, C, V, R> Write for EmbeddedTlsConnection<'a, C, V, R>
+where C: NetworkConnection, V: for<'v> TlsVerifier<'v, Aes128GcmSha256>, R: CryptoRng + RngCore {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let session = self.session()?;
+
+        // Nothing is awaited once the record took the plaintext. A new record is only
+        // started when the socket sent the records before, so it always takes the next one.
+        if session.buffered == 0 || session.space() == 0 {
+            session.flush_records(false).await?;
+        }
+
+        session.buffer(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.session()?.flush_records(false).await
+    }
+}
+
+impl <'a, C, V, R> TryWrite for EmbeddedTlsConnection<'a, C, V, R>
+where C: NetworkConnection, V: for<'v> TlsVerifier<'v, Aes128GcmSha256>, R: CryptoRng + RngCore {
+    async fn try_write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let session = self.session()?;
+
+        // Records which are not sent now are sent before the next read
+        if session.buffered == 0 || session.space() == 0 {
+            session.flush_records(true).await?;
+            if session.flags.get(Flags::STAGED) {
+                return Ok(0);
+            }
+        }
+
+        let n = session.buffer(buf).await?;
+        session.flush_records(true).await?;
+        Ok(n)
+    }
+}
+
+impl <'a, C, V, R> NetworkConnection for EmbeddedTlsConnection<'a, C, V, R>
+where C: NetworkConnection, V: for<'v> TlsVerifier<'v, Aes128GcmSha256>, R: CryptoRng + RngCore {
+    async fn connect(&mut self) -> Result<(), NetworkError> {
+        let mut inner = self.take_inner().await?;
+
+        if let Err(e) = inner.connect().await {
+            self.state = Some(State::Disconnected(inner));
+            return Err(e);
+        }
+
+        // The previous TlsConnection was dropped by take_inner()
+        let ( read_record_buffer, write_buffer ) = unsafe {
+            self.resources.unwrap_unsafe()
+        };
+
+        // Checked by new()
+        let ( flags, write_buffer ) = write_buffer.split_first_mut().unwrap();
+        let ( write_record_buffer, staging ) = write_buffer.split_at_mut(write_buffer.len() / 2);
+        let flags = Flags::new(flags);
+        let capacity = write_record_buffer.len() - TLS_RECORD_OVERHEAD - RECORD_HEADER_SIZE - 1;
+
+        let socket = Socket {
+            inner,
+            flags,
+            position: RecordPosition::default(),
+            staging,
+            staged: 0,
+            sent: 0
+        };
+        let mut tls = TlsConnection::new(socket, read_record_buffer, write_record_buffer);
+
+        match self.open(&mut tls).await {
+            Ok(()) => {
+                self.state = Some(State::Connected(Session {
+                    tls,
+                    flags,
+                    buffered: 0,
+                    capacity
+                }));
+                Ok(())
+            },
+            Err(e) => {
+                self.state = Some(State::Disconnected(close(tls).await));
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Rejects the settings embedded-tls does not support instead of ignoring them
+fn check_config(config: &TlsConfig<'_>) -> Result<(), NetworkError> {
+    if config.ca_certificates.len() != 1 {
+        error!("embedded-tls needs exactly one CA certificate, got {}", config.ca_certificates.len());
+        return Err(NetworkError::TlsFailed);
+    }
+
+    // embedded-tls sends the certificate but cannot prove it has the key
+    if config.client_auth.is_some() {
+        error!("embedded-tls does not support client certificates");
+        return Err(NetworkError::TlsFailed);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tls::TlsConfig, NetworkError};
+
+    use super::{check_config, RecordPosition};
+
+    #[cfg(feature = "tls-rustls")]
+    mod echo {
+        extern crate std;
+
+        use std::{net::{SocketAddr, TcpListener}, sync::Arc, thread, vec, vec::Vec};
+        use std::io::{Read as _, Write as _};
+
+        use embassy_futures::poll_once;
+        use embedded_io_async::{Read, Write};
+        use embedded_tls::NoVerify;
+        use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, IsCa, KeyPair};
+        use rustls::{pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer}, ServerConfig, ServerConnection, StreamOwned};
+
+        use crate::{std::StdNetworkConnection, tls::TlsConfig, NetworkConnection, TryRead};
+
+        use super::super::{EmbeddedTlsConnection, EmbeddedTlsResources};
+
+        /// Random numbers for the tests only
+        struct TestRng(u64);
+
+        impl rand_core::RngCore for TestRng {
+            fn next_u32(&mut self) -> u32 {
+                self.next_u64() as u32
+            }
+
+            fn next_u64(&mut self) -> u64 {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                self.0
+            }
+
+            fn fill_bytes(&mut self, dest: &mut [u8]) {
+                rand_core::impls::fill_bytes_via_next(self, dest)
+            }
+
+            fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+                self.fill_bytes(dest);
+                Ok(())
+            }
+        }
+
+        impl rand_core::CryptoRng for TestRng {}
+
+        /// Starts a TLS server which echoes everything it receives
+        fn start_echo_server(ca: &CertifiedKey) -> SocketAddr {
+            let key_pair = KeyPair::generate().unwrap();
+            let params = CertificateParams::new(vec!["localhost".into()]).unwrap();
+            let cert = params.signed_by(&key_pair, &ca.cert, &ca.key_pair).unwrap();
+
+            let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+            let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![CertificateDer::from(cert.der().to_vec())], key)
+                .unwrap();
+
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            thread::spawn(move || {
+                let (socket, _) = listener.accept().unwrap();
+                let connection = ServerConnection::new(Arc::new(config)).unwrap();
+                let mut stream = StreamOwned::new(connection, socket);
+
+                let mut buf = [0; 1024];
+                while let Ok(n) = stream.read(&mut buf) {
+                    if n == 0 || stream.write_all(&buf[..n]).and_then(|_| stream.flush()).is_err() {
+                        return;
+                    }
+                }
+            });
+
+            addr
+        }
+
+        fn create_ca() -> CertifiedKey {
+            let key_pair = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::<std::string::String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+
+            let cert = params.self_signed(&key_pair).unwrap();
+            CertifiedKey { cert, key_pair }
+        }
+
+        #[tokio::test]
+        async fn test_echo_with_dropped_operations() {
+            let ca = create_ca();
+            let addr = start_echo_server(&ca);
+
+            let ca_certificates: &[&[u8]] = &[ca.cert.der()];
+            let mut read_record_buffer = vec![0; 16640];
+            let mut write_record_buffer = vec![0; 1024];
+            let resources = EmbeddedTlsResources::new(&mut read_record_buffer, &mut write_record_buffer);
+
+            let mut connection: EmbeddedTlsConnection<'_, _, NoVerify, _> = EmbeddedTlsConnection::new(
+                StdNetworkConnection::new(addr),
+                TlsConfig::new("localhost", ca_certificates),
+                resources,
+                TestRng(0x2545f4914f6cdd1d)
+            ).unwrap();
+            connection.connect().await.unwrap();
+
+            // Nothing was sent yet
+            let mut buf = [0; 4096];
+            assert_eq!(connection.try_read(&mut buf).await, Ok(0));
+
+            // Larger than a record, with flushes dropped after their first poll
+            let message: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+            let mut written = 0;
+            while written < message.len() {
+                written += connection.write(&message[written..]).await.unwrap();
+                let _ = poll_once(connection.flush());
+            }
+            connection.flush().await.unwrap();
+
+            let mut received = vec![0; message.len()];
+            connection.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, message);
+        }
+    }
+
+    #[test]
+    fn test_check_config() {
+        let ca: &[u8] = b"ca";
+        let other_ca: &[u8] = b"other ca";
+        let client: &[u8] = b"client";
+
+        let one_ca: &[&[u8]] = &[ca];
+        assert_eq!(check_config(&TlsConfig::new("broker", one_ca)), Ok(()));
+
+        // Only the first CA would be trusted
+        let two_cas: &[&[u8]] = &[ca, other_ca];
+        assert_eq!(check_config(&TlsConfig::new("broker", two_cas)), Err(NetworkError::TlsFailed));
+
+        // Nothing could be verified
+        assert_eq!(check_config(&TlsConfig::new("broker", &[])), Err(NetworkError::TlsFailed));
+
+        // The broker would reject the client
+        let certificate: &[&[u8]] = &[client];
+        let config = TlsConfig::new("broker", one_ca).with_client_auth(certificate, b"key");
+        assert_eq!(check_config(&config), Err(NetworkError::TlsFailed));
+    }
+
+    #[test]
+    fn test_record_position() {
+        let mut position = RecordPosition::default();
+        assert!(position.may_stop());
+
+        // Part of a header
+        position.on_received(&[23, 3]);
+        assert!(position.may_stop());
+
+        // Rest of the header and part of the body
+        position.on_received(&[3, 0, 4, 1, 2]);
+        assert!(! position.may_stop());
+
+        // Rest of the body, an empty record and the header of the next record
+        position.on_received(&[3, 4, 23, 3, 3, 0, 0, 23, 3, 3, 1, 0]);
+        assert!(! position.may_stop());
+
+        position.on_received(&[0; 256]);
+        assert!(position.may_stop());
+    }
+}
@@ -0,0 +1,53 @@
+//! TLS for any [`crate::NetworkConnection`]: rustls on std, embedded-tls on no_std
+
+#[cfg(feature = "tls-rustls")]
+mod rustls_connection;
+#[cfg(feature = "tls-rustls")]
+pub use rustls_connection::RustlsConnection;
+
+#[cfg(feature = "tls-embedded")]
+mod embedded_tls_connection;
+#[cfg(feature = "tls-embedded")]
+pub use embedded_tls_connection::{EmbeddedTlsConnection, EmbeddedTlsResources};
+
+/// Settings of a TLS connection to the broker
+#[derive(Debug, Clone, Copy)]
+pub struct TlsConfig<'a> {
+    /// Name of the broker, sent as SNI and checked against the broker certificate
+    pub server_name: &'a str,
+
+    /// DER encoded certificates of the CAs which may issue the broker certificate.
+    /// No other CAs are trusted.
+    pub ca_certificates: &'a [&'a [u8]],
+
+    /// Certificate and key to authenticate the client
+    pub client_auth: Option<ClientAuth<'a>>
+}
+
+impl <'a> TlsConfig<'a> {
+    pub fn new(server_name: &'a str, ca_certificates: &'a [&'a [u8]]) -> Self {
+        Self {
+            server_name,
+            ca_certificates,
+            client_auth: None
+        }
+    }
+
+    pub fn with_client_auth(mut self, certificate_chain: &'a [&'a [u8]], private_key: &'a [u8]) -> Self {
+        self.client_auth = Some(ClientAuth {
+            certificate_chain,
+            private_key
+        });
+        self
+    }
+}
+
+/// Client certificate authentication
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAuth<'a> {
+    /// DER encoded certificates, starting with the client certificate
+    pub certificate_chain: &'a [&'a [u8]],
+
+    /// DER encoded private key of the client certificate
+    pub private_key: &'a [u8]
+}
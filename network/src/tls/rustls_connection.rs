@@ -0,0 +1,445 @@
+extern crate std;
+
+use std::{io, sync::Arc, vec::Vec};
+use std::borrow::ToOwned;
+use std::io::{Read as _, Write as _};
+
+use embedded_io_async::{Error, ErrorKind, ErrorType, Read, Write};
+use rustls::{pki_types::{CertificateDer, PrivateKeyDer, ServerName}, ClientConfig, ClientConnection, RootCertStore};
+
+use crate::{Debug2Format, NetworkConnection, NetworkError, TryRead, TryWrite, END_OF_STREAM};
+
+use super::TlsConfig;
+
+/// Size of the chunks of TLS records read from the inner connection at once
+const RECEIVE_CHUNK_SIZE: usize = 4096;
+
+/// TLS connection with rustls over the `inner` connection
+pub struct RustlsConnection<C: NetworkConnection> {
+    inner: C,
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+    tls: Option<ClientConnection>,
+
+    /// TLS records not written to the inner connection yet
+    pending: Vec<u8>,
+
+    /// Number of bytes at the start of `pending` already written
+    written: usize
+}
+
+impl <C: NetworkConnection> RustlsConnection<C> {
+
+    /// Creates the connection, fails if the certificates or the server name of `config` are invalid
+    pub fn new(inner: C, config: &TlsConfig<'_>) -> Result<Self, NetworkError> {
+        let mut roots = RootCertStore::empty();
+        for ca_certificate in config.ca_certificates {
+            roots.add(CertificateDer::from(ca_certificate.to_vec()))
+                .map_err(|e| {
+                    error!("invalid CA certificate: {}", Debug2Format(&e));
+                    NetworkError::TlsFailed
+                })?;
+        }
+
+        let builder = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| {
+                error!("cannot configure TLS versions: {}", Debug2Format(&e));
+                NetworkError::TlsFailed
+            })?
+            .with_root_certificates(roots);
+
+        let client_config = match config.client_auth {
+            Some(client_auth) => {
+                let certificate_chain = client_auth.certificate_chain.iter()
+                    .map(|certificate| CertificateDer::from(certificate.to_vec()))
+                    .collect();
+
+                let private_key = PrivateKeyDer::try_from(client_auth.private_key.to_vec())
+                    .map_err(|e| {
+                        error!("invalid client private key: {}", e);
+                        NetworkError::TlsFailed
+                    })?;
+
+                builder.with_client_auth_cert(certificate_chain, private_key)
+                    .map_err(|e| {
+                        error!("invalid client certificate: {}", Debug2Format(&e));
+                        NetworkError::TlsFailed
+                    })?
+            },
+            None => builder.with_no_client_auth(),
+        };
+
+        let server_name = ServerName::try_from(config.server_name.to_owned())
+            .map_err(|_| {
+                error!("invalid TLS server name: {}", config.server_name);
+                NetworkError::TlsFailed
+            })?;
+
+        Ok(Self {
+            inner,
+            config: Arc::new(client_config),
+            server_name,
+            tls: None,
+            pending: Vec::new(),
+            written: 0
+        })
+    }
+
+    async fn handshake(&mut self) -> Result<(), ErrorKind> {
+        while tls(&mut self.tls)?.is_handshaking() {
+            self.flush_records().await?;
+
+            let tls = tls(&mut self.tls)?;
+            if tls.is_handshaking() && tls.wants_read() && ! self.receive_records(true).await? {
+                return Err(ErrorKind::ConnectionReset);
+            }
+        }
+
+        self.flush_records().await
+    }
+
+    /// Takes the TLS records to send from rustls
+    fn take_records(&mut self) -> Result<(), ErrorKind> {
+        let tls = tls(&mut self.tls)?;
+        while tls.wants_write() {
+            tls.write_tls(&mut self.pending)
+                .map_err(|e| {
+                    error!("cannot write TLS records: {}", Debug2Format(&e));
+                    ErrorKind::Other
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Returns if TLS records wait to be written to the inner connection
+    fn has_pending(&self) -> bool {
+        self.written < self.pending.len()
+    }
+
+    /// Marks `n` more bytes of the pending TLS records as written
+    fn on_written(&mut self, n: usize) {
+        self.written += n;
+        if ! self.has_pending() {
+            self.pending.clear();
+            self.written = 0;
+        }
+    }
+
+    /// Writes all TLS records to the inner connection.
+    /// Cancel safe: a later call continues after the records written so far.
+    async fn flush_records(&mut self) -> Result<(), ErrorKind> {
+        self.take_records()?;
+
+        while self.has_pending() {
+            let n = self.inner.write(&self.pending[self.written..]).await
+                .map_err(|e| e.kind())?;
+            if n == 0 {
+                return Err(ErrorKind::WriteZero);
+            }
+            self.on_written(n);
+        }
+        Ok(())
+    }
+
+    /// Writes the TLS records to the inner connection as far as it does not block
+    async fn try_flush_records(&mut self) -> Result<(), ErrorKind> {
+        self.take_records()?;
+
+        while self.has_pending() {
+            let n = self.inner.try_write(&self.pending[self.written..]).await
+                .map_err(|e| e.kind())?;
+            if n == 0 {
+                break;
+            }
+            self.on_written(n);
+        }
+        Ok(())
+    }
+
+    /// Passes TLS records from the inner connection to rustls.
+    /// Returns false if nothing was received.
+    async fn receive_records(&mut self, blocking: bool) -> Result<bool, ErrorKind> {
+        let mut buf = [0; RECEIVE_CHUNK_SIZE];
+        let n = if blocking {
+            self.inner.read(&mut buf).await
+        } else {
+            self.inner.try_read(&mut buf).await
+        }.map_err(|e| e.kind())?;
+
+        let tls = tls(&mut self.tls)?;
+        let mut records = &buf[..n];
+        while ! records.is_empty() {
+            tls.read_tls(&mut records)
+                .map_err(|e| {
+                    error!("cannot read TLS records: {}", Debug2Format(&e));
+                    ErrorKind::Other
+                })?;
+
+            tls.process_new_packets()
+                .map_err(|e| {
+                    error!("invalid TLS records received: {}", Debug2Format(&e));
+                    ErrorKind::InvalidData
+                })?;
+        }
+
+        Ok(n > 0)
+    }
+
+    /// Reads decrypted data, returns `None` if rustls has none
+    fn read_plaintext(&mut self, buf: &mut [u8]) -> Result<Option<usize>, ErrorKind> {
+        match tls(&mut self.tls)?.reader().read(buf) {
+            Ok(0) if ! buf.is_empty() => {
+                trace!("TLS connection closed by broker");
+                Ok(Some(0))
+            },
+            Ok(n) => Ok(Some(n)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => {
+                error!("error reading from TLS connection: {}", Debug2Format(&e));
+                Err(ErrorKind::ConnectionReset)
+            }
+        }
+    }
+}
+
+fn tls(tls: &mut Option<ClientConnection>) -> Result<&mut ClientConnection, ErrorKind> {
+    tls.as_mut().ok_or(ErrorKind::NotConnected)
+}
+
+impl <C: NetworkConnection> ErrorType for RustlsConnection<C> {
+    type Error = ErrorKind;
+}
+
+impl <C: NetworkConnection> Read for RustlsConnection<C> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        // The broker may wait for records written before
+        self.flush_records().await?;
+
+        loop {
+            if let Some(n) = self.read_plaintext(buf)? {
+                return Ok(n);
+            }
+
+            if ! self.receive_records(true).await? {
+                // Brokers often close the connection without close_notify
+                warn!("inner connection of TLS connection closed");
+                return Ok(0);
+            }
+        }
+    }
+}
+
+impl <C: NetworkConnection> TryRead for RustlsConnection<C> {
+    async fn try_read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.try_flush_records().await?;
+
+        let n = match self.read_plaintext(buf)? {
+            Some(n) => n,
+            None if self.receive_records(false).await? => match self.read_plaintext(buf)? {
+                Some(n) => n,
+                None => return Ok(0)
+            },
+            None => return Ok(0)
+        };
+
+        // close_notify of the broker
+        if n == 0 && ! buf.is_empty() {
+            return Err(END_OF_STREAM);
+        }
+        Ok(n)
+    }
+}
+
+impl <C: NetworkConnection> Write for RustlsConnection<C> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        // Records of earlier writes do not pile up
+        self.flush_records().await?;
+
+        // Nothing is awaited once rustls took the plaintext, the records are
+        // written with the next read, write or flush
+        let n = tls(&mut self.tls)?.writer().write(buf)
+            .map_err(|e| {
+                error!("error writing to TLS connection: {}", Debug2Format(&e));
+                ErrorKind::Other
+            })?;
+
+        self.take_records()?;
+        Ok(n)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.flush_records().await?;
+        self.inner.flush().await
+            .map_err(|e| e.kind())
+    }
+}
+
+impl <C: NetworkConnection> TryWrite for RustlsConnection<C> {
+    async fn try_write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        // Records which are not written now are written before the next read
+        self.try_flush_records().await?;
+        if self.has_pending() {
+            return Ok(0);
+        }
+
+        let n = tls(&mut self.tls)?.writer().write(buf)
+            .map_err(|e| {
+                error!("error writing to TLS connection: {}", Debug2Format(&e));
+                ErrorKind::Other
+            })?;
+
+        self.try_flush_records().await?;
+        Ok(n)
+    }
+}
+
+impl <C: NetworkConnection> NetworkConnection for RustlsConnection<C> {
+    async fn connect(&mut self) -> Result<(), NetworkError> {
+        self.tls = None;
+        self.pending.clear();
+        self.written = 0;
+
+        self.inner.connect().await?;
+
+        let tls = ClientConnection::new(self.config.clone(), self.server_name.clone())
+            .map_err(|e| {
+                error!("cannot create TLS connection: {}", Debug2Format(&e));
+                NetworkError::TlsFailed
+            })?;
+        self.tls = Some(tls);
+
+        self.handshake().await
+            .map_err(|e| {
+                error!("TLS handshake failed: {}", e);
+                self.tls = None;
+                NetworkError::TlsFailed
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{net::{SocketAddr, TcpListener}, sync::Arc, thread, vec, vec::Vec};
+    use std::io::{Read as _, Write as _};
+
+    use embedded_io_async::{Read, Write};
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use rustls::{pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer}, server::WebPkiClientVerifier, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+
+    use crate::{std::StdNetworkConnection, tls::TlsConfig, NetworkConnection, NetworkError};
+
+    use super::RustlsConnection;
+
+    struct Certificates {
+        ca: CertifiedKey,
+        server: CertifiedKey,
+        client: CertifiedKey
+    }
+
+    fn issue(name: &str, usage: ExtendedKeyUsagePurpose, ca: &CertifiedKey) -> CertifiedKey {
+        let key_pair = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![name.into()]).unwrap();
+        params.extended_key_usages = vec![usage];
+
+        let cert = params.signed_by(&key_pair, &ca.cert, &ca.key_pair).unwrap();
+        CertifiedKey { cert, key_pair }
+    }
+
+    fn create_ca() -> CertifiedKey {
+        let key_pair = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<std::string::String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+
+        let cert = params.self_signed(&key_pair).unwrap();
+        CertifiedKey { cert, key_pair }
+    }
+
+    fn create_certificates() -> Certificates {
+        let ca = create_ca();
+        let server = issue("localhost", ExtendedKeyUsagePurpose::ServerAuth, &ca);
+        let client = issue("client", ExtendedKeyUsagePurpose::ClientAuth, &ca);
+        Certificates { ca, server, client }
+    }
+
+    /// Starts a TLS server requiring client certificates which echoes one message
+    fn start_echo_server(certificates: &Certificates) -> SocketAddr {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certificates.ca.cert.der().clone()).unwrap();
+        let client_verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+            .build()
+            .unwrap();
+
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certificates.server.key_pair.serialize_der()));
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(vec![CertificateDer::from(certificates.server.cert.der().to_vec())], key)
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let connection = ServerConnection::new(Arc::new(config)).unwrap();
+            let mut stream = StreamOwned::new(connection, socket);
+
+            let mut buf = [0; 64];
+            let n = match stream.read(&mut buf) {
+                Ok(n) => n,
+                // The client rejected the server
+                Err(_) => return,
+            };
+
+            assert_eq!(stream.conn.server_name(), Some("localhost"));
+            stream.write_all(&buf[..n]).unwrap();
+            stream.flush().unwrap();
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_echo_with_client_auth() {
+        let certificates = create_certificates();
+        let addr = start_echo_server(&certificates);
+
+        let ca_certificates: &[&[u8]] = &[certificates.ca.cert.der()];
+        let client_chain: &[&[u8]] = &[certificates.client.cert.der()];
+        let client_key = certificates.client.key_pair.serialize_der();
+        let config = TlsConfig::new("localhost", ca_certificates)
+            .with_client_auth(client_chain, &client_key);
+
+        let mut connection = RustlsConnection::new(StdNetworkConnection::new(addr), &config).unwrap();
+        connection.connect().await.unwrap();
+
+        connection.write_all(b"hello tls").await.unwrap();
+
+        let mut buf = [0; 9];
+        connection.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello tls");
+    }
+
+    #[tokio::test]
+    async fn test_reject_unknown_ca() {
+        let certificates = create_certificates();
+        let addr = start_echo_server(&certificates);
+
+        // Only the pinned CA is trusted
+        let other_ca = create_ca();
+        let ca_certificates: &[&[u8]] = &[other_ca.cert.der()];
+        let client_chain: &[&[u8]] = &[certificates.client.cert.der()];
+        let client_key = certificates.client.key_pair.serialize_der();
+        let config = TlsConfig::new("localhost", ca_certificates)
+            .with_client_auth(client_chain, &client_key);
+
+        let mut connection = RustlsConnection::new(StdNetworkConnection::new(addr), &config).unwrap();
+        assert_eq!(connection.connect().await, Err(NetworkError::TlsFailed));
+    }
+}
@@ -0,0 +1,172 @@
+extern crate std;
+
+use std::{path::Path, task::{Context, Poll, Waker}};
+
+use embedded_io_async::{ErrorKind, ErrorType, Read, ReadReady, Write, WriteReady};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+use crate::{stream::{StreamConnection, StreamFactory}, Debug2Format, NetworkError, NetworkOperation};
+
+/// Connection to a UNIX domain socket
+pub type UnixNetworkConnection<P> = StreamConnection<UnixSocketFactory<P>>;
+
+impl <P: AsRef<Path>> UnixNetworkConnection<P> {
+    pub fn unix(path: P) -> Self {
+        StreamConnection::new(UnixSocketFactory { path })
+    }
+}
+
+/// Connects to the UNIX domain socket at `path`
+pub struct UnixSocketFactory<P: AsRef<Path>> {
+    path: P
+}
+
+impl <P: AsRef<Path>> StreamFactory for UnixSocketFactory<P> {
+    type Stream = UnixSocketStream;
+
+    async fn create(&mut self) -> Result<Self::Stream, NetworkError> {
+        let stream = UnixStream::connect(&self.path).await
+            .map_err(|e| {
+                error!("cannot connect to unix socket: {}", Debug2Format(&e));
+                NetworkError::Io(NetworkOperation::Connect, e.kind().into())
+            })?;
+
+        Ok(UnixSocketStream {
+            stream,
+            peeked: None
+        })
+    }
+}
+
+/// embedded-io-async stream of a tokio [`UnixStream`]
+pub struct UnixSocketStream {
+    stream: UnixStream,
+
+    /// Byte read by [`ReadReady::read_ready`] to check for data
+    peeked: Option<u8>
+}
+
+impl ErrorType for UnixSocketStream {
+    type Error = ErrorKind;
+}
+
+impl Read for UnixSocketStream {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let Some(b) = self.peeked.take() else {
+            return self.stream.read(buf).await
+                .map_err(|e| {
+                    error!("error reading from unix socket: {}", Debug2Format(&e));
+                    ErrorKind::from(e.kind())
+                });
+        };
+
+        buf[0] = b;
+        match self.stream.try_read(&mut buf[1..]) {
+            Ok(n) => Ok(n + 1),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(1),
+            Err(e) => {
+                error!("error reading from unix socket: {}", Debug2Format(&e));
+                Err(e.kind().into())
+            }
+        }
+    }
+}
+
+impl ReadReady for UnixSocketStream {
+    fn read_ready(&mut self) -> Result<bool, Self::Error> {
+        if self.peeked.is_some() {
+            return Ok(true);
+        }
+
+        let mut b = [0];
+        match self.stream.try_read(&mut b) {
+            Ok(0) => {
+                // read() returns the end of the stream
+                Ok(true)
+            },
+            Ok(_) => {
+                self.peeked = Some(b[0]);
+                Ok(true)
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => {
+                error!("error reading from unix socket: {}", Debug2Format(&e));
+                Err(e.kind().into())
+            }
+        }
+    }
+}
+
+impl Write for UnixSocketStream {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.stream.write(buf).await
+            .map_err(|e| {
+                error!("error writing to unix socket: {}", Debug2Format(&e));
+                ErrorKind::from(e.kind())
+            })
+    }
+}
+
+impl WriteReady for UnixSocketStream {
+    fn write_ready(&mut self) -> Result<bool, Self::Error> {
+        let mut cx = Context::from_waker(Waker::noop());
+        match self.stream.poll_write_ready(&mut cx) {
+            Poll::Ready(Ok(())) => Ok(true),
+            Poll::Ready(Err(e)) => {
+                error!("error checking if unix socket is writable: {}", Debug2Format(&e));
+                Err(e.kind().into())
+            },
+            Poll::Pending => Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use embedded_io_async::{Read, Write};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::UnixListener};
+
+    use crate::{NetworkConnection, TryRead};
+
+    use super::UnixNetworkConnection;
+
+    #[tokio::test]
+    async fn test_unix_connection() {
+        let path = std::env::temp_dir().join(std::format!("network-unix-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = tokio::spawn(async move {
+            for _ in 0..2 {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 5];
+                socket.read_exact(&mut buf).await.unwrap();
+                socket.write_all(&buf).await.unwrap();
+            }
+        });
+
+        let mut connection = UnixNetworkConnection::unix(path.clone());
+
+        // Every connect uses a new stream
+        for _ in 0..2 {
+            connection.connect().await.unwrap();
+
+            let mut buf = [0; 5];
+            assert_eq!(connection.try_read(&mut buf).await, Ok(0));
+
+            connection.write_all(b"hello").await.unwrap();
+            connection.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        }
+
+        server.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
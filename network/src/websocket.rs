@@ -0,0 +1,630 @@
+//! MQTT over WebSocket (RFC 6455) for any [`NetworkConnection`], e.g. plain TCP or TLS
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use embedded_io_async::{Error, ErrorKind, ErrorType, Read, ReadExactError, Write};
+
+use crate::{NetworkConnection, NetworkError, NetworkOperation, TryRead, TryWrite, END_OF_STREAM};
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Maximum size of the HTTP response to the upgrade request
+const MAX_RESPONSE_SIZE: usize = 1024;
+
+/// Size of the buffer holding the frame being written
+const FRAME_BUFFER_SIZE: usize = 256;
+
+/// Size of the header of a masked frame with a 64 bit length
+const MAX_HEADER_SIZE: usize = 14;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Settings of the HTTP upgrade request
+#[derive(Debug, Clone, Copy)]
+pub struct WebSocketConfig<'a> {
+    /// Value of the Host header
+    pub host: &'a str,
+
+    /// Path of the request, e.g. `/mqtt`
+    pub path: &'a str
+}
+
+impl <'a> WebSocketConfig<'a> {
+    pub fn new(host: &'a str, path: &'a str) -> Self {
+        Self {
+            host,
+            path
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReadState {
+    /// Receiving the header of the next frame
+    Header,
+    /// Receiving the payload of a data frame
+    Payload(u64),
+    /// Receiving the payload of a control frame
+    Control(u8, usize)
+}
+
+/// Sends and receives the data of `inner` in binary WebSocket frames
+///
+/// `fill_random` fills a buffer from a strong random source. It provides the Sec-WebSocket-Key
+/// of every connect and a new mask for every frame (RFC 6455 §5.3, §10.3).
+///
+/// A write takes the data into a frame which is written with the next read, write or flush,
+/// so a cancelled operation never leaves a partial frame behind.
+pub struct WebSocketConnection<'a, C: NetworkConnection, F: FnMut(&mut [u8])> {
+    inner: C,
+    config: WebSocketConfig<'a>,
+    fill_random: F,
+    nonce: [u8; 16],
+
+    read_state: ReadState,
+    header: [u8; MAX_HEADER_SIZE],
+    header_len: usize,
+    control: [u8; 125],
+    control_len: usize,
+
+    /// Frame being written to the inner connection
+    frame: [u8; FRAME_BUFFER_SIZE],
+    frame_len: usize,
+    /// Number of bytes of `frame` already written
+    frame_written: usize
+}
+
+impl <'a, C: NetworkConnection, F: FnMut(&mut [u8])> WebSocketConnection<'a, C, F> {
+
+    pub fn new(inner: C, config: WebSocketConfig<'a>, fill_random: F) -> Self {
+        Self {
+            inner,
+            config,
+            fill_random,
+            nonce: [0; 16],
+            read_state: ReadState::Header,
+            header: [0; MAX_HEADER_SIZE],
+            header_len: 0,
+            control: [0; 125],
+            control_len: 0,
+            frame: [0; FRAME_BUFFER_SIZE],
+            frame_len: 0,
+            frame_written: 0
+        }
+    }
+
+    fn renew_nonce(&mut self) {
+        (self.fill_random)(&mut self.nonce);
+    }
+
+    /// The mask of a frame must not be predictable from earlier frames or the key sent in clear text
+    fn next_mask(&mut self) -> [u8; 4] {
+        let mut mask = [0; 4];
+        (self.fill_random)(&mut mask);
+        mask
+    }
+
+    async fn write_inner(&mut self, buf: &[u8]) -> Result<(), NetworkError> {
+        self.inner.write_all(buf).await
+            .map_err(|e| {
+                error!("error writing WebSocket upgrade request: {}", e.kind());
+                NetworkError::Io(NetworkOperation::Connect, e.kind())
+            })
+    }
+
+    async fn handshake(&mut self) -> Result<(), NetworkError> {
+        let mut key = [0; 24];
+        STANDARD.encode_slice(self.nonce, &mut key)
+            .map_err(|_| NetworkError::WebSocketFailed)?;
+        // base64 only yields ascii
+        let key = core::str::from_utf8(&key).unwrap();
+
+        self.write_inner(b"GET ").await?;
+        self.write_inner(self.config.path.as_bytes()).await?;
+        self.write_inner(b" HTTP/1.1\r\nHost: ").await?;
+        self.write_inner(self.config.host.as_bytes()).await?;
+        self.write_inner(b"\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: ").await?;
+        self.write_inner(key.as_bytes()).await?;
+        self.write_inner(b"\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: mqtt\r\n\r\n").await?;
+
+        // Read byte by byte to not consume frames sent directly after the response
+        let mut response = [0; MAX_RESPONSE_SIZE];
+        let mut len = 0;
+        while ! response[..len].ends_with(b"\r\n\r\n") {
+            if len == response.len() {
+                error!("WebSocket upgrade response too large");
+                return Err(NetworkError::WebSocketFailed);
+            }
+
+            self.inner.read_exact(&mut response[len..len + 1]).await
+                .map_err(|e| {
+                    error!("error reading WebSocket upgrade response: {}", crate::Debug2Format(&e));
+                    match e {
+                        ReadExactError::UnexpectedEof => NetworkError::Closed(NetworkOperation::Connect),
+                        ReadExactError::Other(e) => NetworkError::Io(NetworkOperation::Connect, e.kind())
+                    }
+                })?;
+            len += 1;
+        }
+
+        check_response(&response[..len], key)
+    }
+
+    /// Reads from the inner connection, returns 0 at the end of the stream if `blocking`
+    /// or if no data is available otherwise
+    async fn read_inner(inner: &mut C, buf: &mut [u8], blocking: bool) -> Result<usize, ErrorKind> {
+        if blocking {
+            let n = inner.read(buf).await.map_err(|e| e.kind())?;
+            if n == 0 && ! buf.is_empty() {
+                warn!("inner connection of WebSocket closed");
+            }
+            Ok(n)
+        } else {
+            inner.try_read(buf).await.map_err(|e| e.kind())
+        }
+    }
+
+    fn has_pending_frame(&self) -> bool {
+        self.frame_written < self.frame_len
+    }
+
+    /// Writes the rest of the pending frame to the inner connection.
+    /// Cancel safe: a later call continues after the bytes written so far.
+    /// Returns false if `blocking` is false and the frame could not be written completely.
+    async fn flush_frame(&mut self, blocking: bool) -> Result<bool, ErrorKind> {
+        while self.has_pending_frame() {
+            let pending = &self.frame[self.frame_written..self.frame_len];
+            let n = if blocking {
+                self.inner.write(pending).await
+            } else {
+                self.inner.try_write(pending).await
+            }.map_err(|e| e.kind())?;
+
+            if n == 0 {
+                if blocking {
+                    warn!("inner connection of WebSocket wrote nothing");
+                    return Err(ErrorKind::WriteZero);
+                }
+                return Ok(false);
+            }
+            self.frame_written += n;
+        }
+        Ok(true)
+    }
+
+    /// Masks as much of `payload` as fits into the frame buffer into a new frame.
+    /// Returns the number of payload bytes taken. There must be no pending frame.
+    fn start_frame(&mut self, opcode: u8, payload: &[u8]) -> usize {
+        let mask = self.next_mask();
+        let len = payload.len().min(FRAME_BUFFER_SIZE - MAX_HEADER_SIZE);
+
+        let mut header = [0; MAX_HEADER_SIZE];
+        let header_len = encode_header(&mut header, opcode, len, mask);
+        self.frame[..header_len].copy_from_slice(&header[..header_len]);
+
+        for (i, b) in payload[..len].iter().enumerate() {
+            self.frame[header_len + i] = b ^ mask[i % 4];
+        }
+
+        self.frame_len = header_len + len;
+        self.frame_written = 0;
+        len
+    }
+
+    /// Takes data from `payload` into a binary frame once the frame before is written
+    async fn write_frame(&mut self, payload: &[u8], blocking: bool) -> Result<usize, ErrorKind> {
+        if payload.is_empty() || ! self.flush_frame(blocking).await? {
+            return Ok(0);
+        }
+
+        // Nothing is awaited once the data is taken
+        Ok(self.start_frame(OPCODE_BINARY, payload))
+    }
+
+    /// Answers a control frame, returns false if the broker closed the WebSocket.
+    /// There must be no pending frame.
+    fn handle_control(&mut self, opcode: u8) -> bool {
+        let len = self.control_len;
+        let mut payload = [0; 125];
+        payload[..len].copy_from_slice(&self.control[..len]);
+
+        match opcode {
+            OPCODE_PING => {
+                trace!("WebSocket ping received");
+                self.start_frame(OPCODE_PONG, &payload[..len]);
+                true
+            },
+            OPCODE_PONG => true,
+            _ => {
+                warn!("WebSocket closed by broker");
+                // Echo the status code as required
+                self.start_frame(OPCODE_CLOSE, &payload[..len.min(2)]);
+                false
+            }
+        }
+    }
+
+    async fn receive(&mut self, buf: &mut [u8], blocking: bool) -> Result<usize, ErrorKind> {
+        loop {
+            // The broker may wait for the frame written before, e.g. a pong
+            self.flush_frame(blocking).await?;
+
+            match self.read_state {
+                ReadState::Header => {
+                    let needed = header_size(&self.header[..self.header_len]);
+                    if self.header_len < needed {
+                        let n = Self::read_inner(&mut self.inner, &mut self.header[self.header_len..needed], blocking).await?;
+                        if n == 0 {
+                            return Ok(0);
+                        }
+                        self.header_len += n;
+                        continue;
+                    }
+
+                    self.read_state = decode_header(&self.header[..self.header_len])?;
+                    self.header_len = 0;
+                    self.control_len = 0;
+                },
+                ReadState::Payload(0) => {
+                    self.read_state = ReadState::Header;
+                },
+                ReadState::Payload(remaining) => {
+                    if buf.is_empty() {
+                        return Ok(0);
+                    }
+
+                    let len = remaining.min(buf.len() as u64) as usize;
+                    let n = Self::read_inner(&mut self.inner, &mut buf[..len], blocking).await?;
+                    self.read_state = ReadState::Payload(remaining - n as u64);
+                    return Ok(n);
+                },
+                ReadState::Control(opcode, len) => {
+                    if self.control_len < len {
+                        let n = Self::read_inner(&mut self.inner, &mut self.control[self.control_len..len], blocking).await?;
+                        if n == 0 {
+                            return Ok(0);
+                        }
+                        self.control_len += n;
+                        continue;
+                    }
+
+                    // The answer is queued after the pending frame
+                    if ! self.flush_frame(blocking).await? {
+                        return Ok(0);
+                    }
+
+                    self.read_state = ReadState::Header;
+                    if ! self.handle_control(opcode) {
+                        // The connection is closed anyway
+                        let _ = self.flush_frame(blocking).await;
+                        return if blocking { Ok(0) } else { Err(END_OF_STREAM) };
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Checks the status, the subprotocol and the accept key of the upgrade response
+fn check_response(response: &[u8], key: &str) -> Result<(), NetworkError> {
+    let response = core::str::from_utf8(response)
+        .map_err(|_| {
+            error!("WebSocket upgrade response is not utf8");
+            NetworkError::WebSocketFailed
+        })?;
+
+    let mut lines = response.split("\r\n");
+    let status = lines.next().unwrap_or("");
+    if ! status.starts_with("HTTP/1.1 101") {
+        error!("WebSocket upgrade rejected: {}", status);
+        return Err(NetworkError::WebSocketFailed);
+    }
+
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(ACCEPT_GUID.as_bytes());
+    let mut expected_accept = [0; 28];
+    STANDARD.encode_slice(sha1.digest().bytes(), &mut expected_accept)
+        .map_err(|_| NetworkError::WebSocketFailed)?;
+
+    let mut accepted = false;
+    let mut protocol = false;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+
+        if name.trim().eq_ignore_ascii_case("sec-websocket-accept") {
+            accepted = value.as_bytes() == expected_accept;
+        } else if name.trim().eq_ignore_ascii_case("sec-websocket-protocol") {
+            protocol = value == "mqtt";
+        }
+    }
+
+    if ! accepted {
+        error!("WebSocket upgrade response has an invalid accept key");
+        return Err(NetworkError::WebSocketFailed);
+    }
+
+    if ! protocol {
+        error!("broker did not accept the mqtt WebSocket subprotocol");
+        return Err(NetworkError::WebSocketFailed);
+    }
+
+    Ok(())
+}
+
+/// Writes the header of a masked frame, returns its length
+fn encode_header(header: &mut [u8; 14], opcode: u8, len: usize, mask: [u8; 4]) -> usize {
+    header[0] = 0x80 | opcode;
+
+    let mut i = 2;
+    if len < 126 {
+        header[1] = 0x80 | len as u8;
+    } else if len <= u16::MAX as usize {
+        header[1] = 0x80 | 126;
+        header[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        i = 4;
+    } else {
+        header[1] = 0x80 | 127;
+        header[2..10].copy_from_slice(&(len as u64).to_be_bytes());
+        i = 10;
+    }
+
+    header[i..i + 4].copy_from_slice(&mask);
+    i + 4
+}
+
+/// Number of header bytes needed, given the first bytes of the header
+fn header_size(header: &[u8]) -> usize {
+    if header.len() < 2 {
+        return 2;
+    }
+
+    let mask_len = if header[1] & 0x80 != 0 { 4 } else { 0 };
+    match header[1] & 0x7F {
+        126 => 4 + mask_len,
+        127 => 10 + mask_len,
+        _ => 2 + mask_len
+    }
+}
+
+fn decode_header(header: &[u8]) -> Result<ReadState, ErrorKind> {
+    let opcode = header[0] & 0x0F;
+
+    if header[1] & 0x80 != 0 {
+        error!("received masked WebSocket frame from broker");
+        return Err(ErrorKind::InvalidData);
+    }
+
+    let len = match header[1] & 0x7F {
+        126 => u16::from_be_bytes([header[2], header[3]]) as u64,
+        127 => {
+            let mut len = [0; 8];
+            len.copy_from_slice(&header[2..10]);
+            u64::from_be_bytes(len)
+        },
+        len => len as u64
+    };
+
+    match opcode {
+        OPCODE_CONTINUATION | OPCODE_BINARY => Ok(ReadState::Payload(len)),
+        OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG if len <= 125 => Ok(ReadState::Control(opcode, len as usize)),
+        OPCODE_TEXT => {
+            error!("received text WebSocket frame, MQTT requires binary frames");
+            Err(ErrorKind::InvalidData)
+        },
+        opcode => {
+            error!("received invalid WebSocket frame with opcode {}", opcode);
+            Err(ErrorKind::InvalidData)
+        }
+    }
+}
+
+impl <'a, C: NetworkConnection, F: FnMut(&mut [u8])> ErrorType for WebSocketConnection<'a, C, F> {
+    type Error = ErrorKind;
+}
+
+impl <'a, C: NetworkConnection, F: FnMut(&mut [u8])> Read for WebSocketConnection<'a, C, F> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.receive(buf, true).await
+    }
+}
+
+impl <'a, C: NetworkConnection, F: FnMut(&mut [u8])> TryRead for WebSocketConnection<'a, C, F> {
+    async fn try_read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.receive(buf, false).await
+    }
+}
+
+impl <'a, C: NetworkConnection, F: FnMut(&mut [u8])> Write for WebSocketConnection<'a, C, F> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.write_frame(buf, true).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.flush_frame(true).await?;
+        self.inner.flush().await
+            .map_err(|e| e.kind())
+    }
+}
+
+impl <'a, C: NetworkConnection, F: FnMut(&mut [u8])> TryWrite for WebSocketConnection<'a, C, F> {
+    async fn try_write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.write_frame(buf, false).await
+    }
+}
+
+impl <'a, C: NetworkConnection, F: FnMut(&mut [u8])> NetworkConnection for WebSocketConnection<'a, C, F> {
+    async fn connect(&mut self) -> Result<(), NetworkError> {
+        self.read_state = ReadState::Header;
+        self.header_len = 0;
+        self.control_len = 0;
+        self.frame_len = 0;
+        self.frame_written = 0;
+        self.renew_nonce();
+
+        self.inner.connect().await?;
+        self.handshake().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{cell::RefCell, vec::Vec};
+
+    use embassy_futures::poll_once;
+    use embedded_io_async::{Read, Write};
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::{handshake::server::{Request, Response}, http::HeaderValue, Message};
+
+    use crate::{std::StdNetworkConnection, NetworkConnection, NetworkError, TryRead};
+
+    use super::{check_response, WebSocketConfig, WebSocketConnection};
+
+    /// Distinct bytes for the tests, a device would use its random source
+    fn counting_random() -> impl FnMut(&mut [u8]) {
+        let mut count = 0u8;
+        move |buf| {
+            for b in buf {
+                count = count.wrapping_add(1);
+                *b = count;
+            }
+        }
+    }
+
+    /// Accepts one WebSocket connection and echoes binary messages, pinging before each echo
+    #[allow(clippy::result_large_err)]
+    async fn start_echo_server(protocol: Option<&'static str>) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let callback = move |request: &Request, mut response: Response| {
+                assert_eq!(request.uri().path(), "/mqtt");
+                assert_eq!(request.headers().get("Sec-WebSocket-Protocol").unwrap(), "mqtt");
+                if let Some(protocol) = protocol {
+                    response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(protocol));
+                }
+                Ok(response)
+            };
+
+            let Ok(mut ws) = tokio_tungstenite::accept_hdr_async(socket, callback).await else {
+                return;
+            };
+
+            while let Some(Ok(message)) = ws.next().await {
+                if let Message::Binary(data) = message {
+                    ws.send(Message::Ping(Vec::from(&b"ping"[..]).into())).await.unwrap();
+                    ws.send(Message::Binary(data)).await.unwrap();
+                }
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn test_check_response() {
+        // Example of RFC 6455
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let response = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\nSec-WebSocket-Protocol: mqtt\r\n\r\n";
+        assert_eq!(check_response(response, key), Ok(()));
+
+        let response = b"HTTP/1.1 101 Switching Protocols\r\nSec-WebSocket-Accept: yCzG83fgMtlPZIbiGCBZOBn+X34=\r\n\
+            Sec-WebSocket-Protocol: mqtt\r\n\r\n";
+        assert_eq!(check_response(response, key), Err(NetworkError::WebSocketFailed));
+
+        let response = b"HTTP/1.1 400 Bad Request\r\n\r\n";
+        assert_eq!(check_response(response, key), Err(NetworkError::WebSocketFailed));
+    }
+
+    #[tokio::test]
+    async fn test_echo() {
+        let addr = start_echo_server(Some("mqtt")).await;
+
+        let config = WebSocketConfig::new("localhost", "/mqtt");
+        let mut connection = WebSocketConnection::new(StdNetworkConnection::new(addr), config, counting_random());
+        connection.connect().await.unwrap();
+
+        // Larger than 125 bytes to use the extended length
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        connection.write_all(&data).await.unwrap();
+
+        let mut received = [0; 300];
+        connection.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received[..], &data[..]);
+
+        // The ping was answered and nothing else is pending
+        assert_eq!(connection.try_read(&mut received).await, Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_reject_missing_subprotocol() {
+        let addr = start_echo_server(None).await;
+
+        let config = WebSocketConfig::new("localhost", "/mqtt");
+        let mut connection = WebSocketConnection::new(StdNetworkConnection::new(addr), config, counting_random());
+        assert_eq!(connection.connect().await, Err(NetworkError::WebSocketFailed));
+    }
+
+    #[tokio::test]
+    async fn test_random_mask_per_frame() {
+        let addr = start_echo_server(Some("mqtt")).await;
+
+        let requests = RefCell::new(Vec::new());
+        let mut random = counting_random();
+        let fill_random = |buf: &mut [u8]| {
+            requests.borrow_mut().push(buf.len());
+            random(buf);
+        };
+
+        let config = WebSocketConfig::new("localhost", "/mqtt");
+        let mut connection = WebSocketConnection::new(StdNetworkConnection::new(addr), config, fill_random);
+        connection.connect().await.unwrap();
+        assert_eq!(*requests.borrow(), [16]);
+
+        // Every frame, the pong included, takes its own mask
+        connection.write_all(b"hello").await.unwrap();
+        connection.flush().await.unwrap();
+        assert_eq!(*requests.borrow(), [16, 4]);
+
+        let mut received = [0; 5];
+        connection.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+        assert_eq!(*requests.borrow(), [16, 4, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_operations_keep_frames() {
+        let addr = start_echo_server(Some("mqtt")).await;
+
+        let config = WebSocketConfig::new("localhost", "/mqtt");
+        let mut connection = WebSocketConnection::new(StdNetworkConnection::new(addr), config, counting_random());
+        connection.connect().await.unwrap();
+
+        let data: Vec<u8> = (0..100).map(|i| i as u8).collect();
+        for chunk in data.chunks(10) {
+            // The write takes the data without waiting for the inner connection
+            assert_eq!(connection.write(chunk).await, Ok(chunk.len()));
+
+            // Cancelling reads which write the frames and answer the pings loses nothing
+            let mut received = [0; 100];
+            let _ = poll_once(connection.read(&mut received));
+        }
+
+        let mut received = [0; 100];
+        connection.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received[..], &data[..]);
+    }
+}
@@ -1,72 +1,159 @@
 
-use embassy_sync::{blocking_mutex::raw::RawMutex, channel::{Receiver, Sender}, pubsub::{PubSubChannel, WaitResult}};
+use embassy_sync::{blocking_mutex::raw::RawMutex, channel::{Receiver, Sender}, pubsub::Subscriber};
+use embassy_futures::select::{select, Either};
+use embedded_io_async::{Read, Seek};
 use mqttrs::QoS;
 
-use crate::{MqttError, MqttEvent, MqttPublish, MqttRequest, Topic, UniqueID};
+use crate::time::{self, Duration};
+use crate::completion::{EventDispatcher, Reservation};
+use crate::stream::{PayloadStream, SlicePayload, WriterPayload};
+use crate::{copy_str, MqttError, MqttEvent, MqttPublish, MqttRequest, UniqueID};
+use crate::{DEFAULT_CHANNEL_SIZE, DEFAULT_MAX_PENDING_REQUESTS, MAX_TOPIC_SIZE, MQTT_PAYLOAD_MAX_SIZE};
 
 #[derive(Clone)]
-pub struct MqttClient<'a, M: RawMutex> {
-
-    pub(super) control_reveiver: &'a PubSubChannel<M, MqttEvent, 4, 16, 8>,
-    pub(super) request_sender: Sender<'a, M, MqttRequest, 4>,
-    pub(super) received_publishes: Receiver<'a, M, MqttPublish, 4>
+pub struct MqttClient<
+    'a,
+    M: RawMutex,
+    const R: usize = DEFAULT_MAX_PENDING_REQUESTS,
+    const C: usize = DEFAULT_CHANNEL_SIZE,
+    const T: usize = MAX_TOPIC_SIZE,
+    const PL: usize = MQTT_PAYLOAD_MAX_SIZE
+> {
+
+    pub(super) events: &'a EventDispatcher<M, R, C>,
+    pub(super) payload_stream: &'a PayloadStream<M>,
+    pub(super) request_sender: Sender<'a, M, MqttRequest<T, PL>, C>,
+    pub(super) received_publishes: Receiver<'a, M, MqttPublish<T, PL>, C>
 
 }
 
-impl <'a, M: RawMutex> MqttClient<'a, M> {
+impl <'a, M: RawMutex, const R: usize, const C: usize, const T: usize, const PL: usize> MqttClient<'a, M, R, C, T, PL> {
+
+    /// Sends the request to the event loop and waits for its result
+    async fn request(&self, request: MqttRequest<T, PL>, id: UniqueID) -> Result<MqttEvent, MqttError> {
+        let reservation = self.events.completions.reserve(id).await;
+        self.send_request(reservation, request).await
+    }
+
+    /// Sends the request with the reserved result slot and waits for its result
+    async fn send_request(&self, mut reservation: Reservation<'_, M, R>, request: MqttRequest<T, PL>) -> Result<MqttEvent, MqttError> {
+        // Dropping the reservation cancels the request
+        self.request_sender.send(request).await;
+        reservation.on_sent();
+        reservation.wait().await
+    }
+
+    fn publish_result(event: MqttEvent) -> Result<(), MqttError> {
+        match event {
+            MqttEvent::PublishResult(_, result) => result,
+            event => {
+                error!("unexpected result for publish request: {}", event);
+                Err(MqttError::InternalError)
+            }
+        }
+    }
 
     pub async fn publish(&self, topic: &str, payload: &[u8], qos: QoS, retain: bool) -> Result<(), MqttError> {
 
         let id = UniqueID::new();
-        let publish = MqttPublish::new(topic, payload, qos, retain);
+        let publish = MqttPublish::new(topic, payload, qos, retain)?;
 
-        let mut subscriber = self.control_reveiver.subscriber()
-            .map_err(|e| {
-                error!("error subscribing to control receiver: {}", e);
-                MqttError::InternalError
-            })?;
+        Self::publish_result(self.request(MqttRequest::Publish(publish, id), id).await?)
+    }
 
-        self.request_sender.send(MqttRequest::Publish(publish, id)).await;
+    /// Publishes like [`Self::publish`] without copying `payload` into a [`MqttPublish`] first.
+    /// The future of the request hands the payload in small chunks to the event loop like
+    /// [`Self::publish_stream`], so it may be larger than the payload capacity and the send buffer.
+    /// `payload` stays borrowed until the broker acknowledged the publish, because
+    /// retransmissions read it again.
+    pub async fn publish_borrowed(&self, topic: &str, payload: &[u8], qos: QoS, retain: bool) -> Result<(), MqttError> {
+        self.publish_stream(topic, payload.len(), qos, retain, &mut SlicePayload::new(payload)).await
+    }
 
-        loop {
-            let msg = subscriber.next_message().await;
-            if let WaitResult::Message(msg) = msg {
-                if let MqttEvent::PublishResult(msg_id, result) = msg {
-                    if id == msg_id {
-                        return result;
-                    }
-                }
-            } else {
-                error!("error reading subscrition: lost messages");
-                return Err(MqttError::InternalError);
-            }
+    /// Publishes a payload of `len` bytes which `write_payload` writes in chunks like [`Self::publish_stream`].
+    ///
+    /// `write_payload(offset, buf)` must fill `buf` with the bytes of the payload starting at `offset`.
+    /// It is called again for every retransmission.
+    pub async fn publish_with<F>(&self, topic: &str, len: usize, qos: QoS, retain: bool, write_payload: F) -> Result<(), MqttError>
+    where F: FnMut(usize, &mut [u8]) {
+        self.publish_stream(topic, len, qos, retain, &mut WriterPayload::new(len, write_payload)).await
+    }
+
+    /// Publishes a payload of `len` bytes read from `source`. The payload is passed in small chunks
+    /// through the send buffer, so it may be larger than the send buffer.
+    ///
+    /// For QoS 1 and QoS 2 the payload is read again from the current position of `source`
+    /// if the publish is retransmitted. Only one streaming publish is in progress at a time,
+    /// further ones wait.
+    /// If `source` fails or ends before `len` bytes, the publish fails with [`MqttError::InvalidPayload`].
+    /// The connection to the broker is only reset if a part of the packet was already sent, because
+    /// the packet cannot be completed.
+    pub async fn publish_stream<S>(&self, topic: &str, len: usize, qos: QoS, retain: bool, source: &mut S) -> Result<(), MqttError>
+    where S: Read + Seek {
+        let id = UniqueID::new();
+        let publish = MqttPublish::new(topic, &[], qos, retain)?;
+
+        let _stream_lock = self.payload_stream.lock().await;
+
+        let mut reservation = self.events.completions.reserve(id).await;
+        self.request_sender.send(MqttRequest::PublishStream(publish, len, id)).await;
+        reservation.on_sent();
+
+        match select(reservation.wait(), self.payload_stream.serve(id, source, len)).await {
+            Either::First(result) => Self::publish_result(result?),
+            Either::Second(e) => Err(e),
         }
     }
 
-    pub async fn subscribe(&self, topic: &str) -> Result<(), MqttError> {
+    /// Publishes like [`Self::publish`] but fails with [`MqttError::Timeout`] if the 
+    /// result does not arrive within `timeout`.
+    /// If the publish was not written to the network yet, it is withdrawn.
+    pub async fn publish_with_timeout(&self, topic: &str, payload: &[u8], qos: QoS, retain: bool, timeout: Duration) -> Result<(), MqttError> {
+        match select(self.publish(topic, payload, qos, retain), time::sleep(timeout)).await {
+            Either::First(result) => result,
+            Either::Second(()) => {
+                warn!("publish to {} timed out", topic);
+                Err(MqttError::Timeout)
+            },
+        }
+    }
+
+    /// Adds the publish to the request queue without waiting.
+    /// Fails with [`MqttError::BufferFull`] if the queue is full.
+    /// The result is sent as [`MqttEvent::PublishResult`] with the returned id,
+    /// subscribe with [`Self::events`] before publishing to not miss it.
+    pub fn try_publish(&self, topic: &str, payload: &[u8], qos: QoS, retain: bool) -> Result<UniqueID, MqttError> {
         let id = UniqueID::new();
+        let publish = MqttPublish::new(topic, payload, qos, retain)?;
 
-        let mut subscriber = self.control_reveiver.subscriber()
-            .map_err(|e| {
-                error!("error subscribing to control receiver: {}", e);
-                MqttError::InternalError
-            })?;
+        self.request_sender.try_send(MqttRequest::Publish(publish, id))
+            .map_err(|_| MqttError::BufferFull)?;
 
-        let mut topic_owned = Topic::new();
-        topic_owned.push_str(topic).unwrap();
-        self.request_sender.send(MqttRequest::Subscribe(topic_owned, id)).await;
+        Ok(id)
+    }
 
-        loop {
-            let msg = subscriber.next_message().await;
-            if let WaitResult::Message(msg) = msg {
-                if let MqttEvent::SubscribeResult(msg_id, result) = msg {
-                    if id == msg_id {
-                        return result.map(|_| ());
-                    }
-                }
-            } else {
-                error!("error reading subscrition: lost messages");
-                return Err(MqttError::InternalError);
+    /// Adds the publish to the request queue without waiting for the result.
+    /// The result is sent as [`MqttEvent::PublishResult`] with the returned id.
+    /// Subscribe with [`Self::events`] before publishing to not miss the result.
+    pub async fn publish_detached(&self, topic: &str, payload: &[u8], qos: QoS, retain: bool) -> Result<UniqueID, MqttError> {
+        let id = UniqueID::new();
+        let publish = MqttPublish::new(topic, payload, qos, retain)?;
+
+        self.request_sender.send(MqttRequest::Publish(publish, id)).await;
+
+        Ok(id)
+    }
+
+    pub async fn subscribe(&self, topic: &str) -> Result<(), MqttError> {
+        let id = UniqueID::new();
+
+        let topic_owned = copy_str(topic, "topic")?;
+
+        match self.request(MqttRequest::Subscribe(topic_owned, id), id).await? {
+            MqttEvent::SubscribeResult(_, result) => result.map(|_| ()),
+            event => {
+                error!("unexpected result for subscribe request: {}", event);
+                Err(MqttError::InternalError)
             }
         }
     }
@@ -74,32 +161,18 @@ impl <'a, M: RawMutex> MqttClient<'a, M> {
     pub async fn unsubscribe(&self, topic: &str) -> Result<(), MqttError> {
         let id = UniqueID::new();
 
-        let mut subscriber = self.control_reveiver.subscriber()
-            .map_err(|e| {
-                error!("error subscribing to control receiver: {}", e);
-                MqttError::InternalError
-            })?;
-
-        let mut topic_owned = Topic::new();
-        topic_owned.push_str(topic).unwrap();
-        self.request_sender.send(MqttRequest::Unsubscribe(topic_owned, id)).await;
+        let topic_owned = copy_str(topic, "topic")?;
 
-        loop {
-            let msg = subscriber.next_message().await;
-            if let WaitResult::Message(msg) = msg {
-                if let MqttEvent::UnsubscribeResult(msg_id, result) = msg {
-                    if id == msg_id {
-                        return result;
-                    }
-                }
-            } else {
-                error!("error reading subscrition: lost messages");
-                return Err(MqttError::InternalError);
+        match self.request(MqttRequest::Unsubscribe(topic_owned, id), id).await? {
+            MqttEvent::UnsubscribeResult(_, result) => result,
+            event => {
+                error!("unexpected result for unsubscribe request: {}", event);
+                Err(MqttError::InternalError)
             }
         }
     }
 
-    pub async fn receive(&self) -> MqttPublish {
+    pub async fn receive(&self) -> MqttPublish<T, PL> {
         self.received_publishes.receive().await
     }
 
@@ -107,15 +180,59 @@ impl <'a, M: RawMutex> MqttClient<'a, M> {
         self.request_sender.send(MqttRequest::Disconnect).await;
     }
 
-    pub async fn on<F>(&self, matcher: F) where F: Fn(&MqttEvent) -> bool {
-        let mut sub = self.control_reveiver.subscriber().unwrap();
+    /// Disconnects after the publishes, subscribes and acknowledgements in progress are done,
+    /// waiting at most `timeout`. Requests sent meanwhile are rejected.
+    ///
+    /// Returns the number of requests abandoned at the timeout. Each of them completes with
+    /// [`MqttError::Abandoned`], detached publishes with a [`MqttEvent::PublishResult`].
+    pub async fn disconnect_graceful(&self, timeout: Duration) -> Result<usize, MqttError> {
+        let id = UniqueID::new();
+
+        match self.request(MqttRequest::DisconnectGraceful(timeout, id), id).await? {
+            MqttEvent::DisconnectResult(_, result) => result,
+            event => {
+                error!("unexpected result for disconnect request: {}", event);
+                Err(MqttError::InternalError)
+            }
+        }
+    }
+
+    /// Subscribes to the events of the event loop, request results included.
+    /// Only events sent after the call are received.
+    ///
+    /// Fails with [`MqttError::TooManyListeners`] if all subscriber slots are in use.
+    pub fn events(&self) -> Result<MqttEvents<'a, M, C>, MqttError> {
+        let subscriber = self.events.events.subscriber()
+            .map_err(|_| {
+                warn!("cannot listen to events: all subscriber slots are in use");
+                MqttError::TooManyListeners
+            })?;
+
+        Ok(MqttEvents { subscriber })
+    }
+
+    /// Waits for the next event the `matcher` returns true for and returns it.
+    /// Only events sent after the call are considered, use [`Self::events`]
+    /// to wait for the result of a request sent afterwards.
+    pub async fn on<F>(&self, matcher: F) -> Result<MqttEvent, MqttError> where F: Fn(&MqttEvent) -> bool {
+        Ok(self.events()?.on(matcher).await)
+    }
+
+}
 
+/// Subscription to the events of the event loop, see [`MqttClient::events`]
+pub struct MqttEvents<'a, M: RawMutex, const C: usize> {
+    subscriber: Subscriber<'a, M, MqttEvent, C, 16, 8>
+}
+
+impl <'a, M: RawMutex, const C: usize> MqttEvents<'a, M, C> {
+    /// Waits for the next event the `matcher` returns true for and returns it
+    pub async fn on<F>(&mut self, matcher: F) -> MqttEvent where F: Fn(&MqttEvent) -> bool {
         loop {
-            let event = sub.next_message_pure().await;
+            let event = self.subscriber.next_message_pure().await;
             if matcher(&event) {
-                break;
+                return event;
             }
         }
     }
-
 }
@@ -0,0 +1,403 @@
+use core::{cell::RefCell, future::poll_fn, task::Poll};
+
+use embassy_sync::{blocking_mutex::{raw::RawMutex, Mutex}, pubsub::PubSubChannel, waitqueue::{MultiWakerRegistration, WakerRegistration}};
+use heapless::Vec;
+
+use crate::{io::AsyncSender, MqttError, MqttEvent, UniqueID};
+
+/// Tells the event loop which requests still wait for their result
+pub(crate) trait WaitingRequests {
+    /// Returns false if the future of the request `id` was dropped
+    fn is_waiting(&self, id: UniqueID) -> bool;
+}
+
+/// A request waiting for its result
+struct Slot {
+    id: UniqueID,
+    result: Option<MqttEvent>,
+    waker: WakerRegistration,
+
+    /// Nobody waits for the result anymore
+    cancelled: bool
+}
+
+struct Inner<const N: usize> {
+    slots: Vec<Slot, N>,
+
+    /// Wakers of requests waiting for a free slot
+    free_wakers: MultiWakerRegistration<N>
+}
+
+impl <const N: usize> Inner<N> {
+    /// Removes the slot and wakes the requests waiting for a free slot
+    fn remove(&mut self, pos: usize) {
+        self.slots.swap_remove(pos);
+        self.free_wakers.wake();
+    }
+}
+
+/// Fixed size table of requests waiting for their result.
+/// A result is handed directly to the request with the same [`UniqueID`].
+pub(crate) struct CompletionTable<M: RawMutex, const N: usize> {
+    inner: Mutex<M, RefCell<Inner<N>>>
+}
+
+impl <M: RawMutex, const N: usize> CompletionTable<M, N> {
+
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new(Inner {
+                slots: Vec::new(),
+                free_wakers: MultiWakerRegistration::new()
+            }))
+        }
+    }
+
+    /// Reserves a slot for the request `id`.
+    /// Waits until a slot is free if all slots are in use.
+    pub(crate) async fn reserve(&self, id: UniqueID) -> Reservation<'_, M, N> {
+        poll_fn(|cx| {
+            self.inner.lock(|inner| {
+                let mut inner = inner.borrow_mut();
+
+                let slot = Slot {
+                    id,
+                    result: None,
+                    waker: WakerRegistration::new(),
+                    cancelled: false
+                };
+
+                match inner.slots.push(slot) {
+                    Ok(()) => Poll::Ready(Reservation { table: self, id, sent: false, done: false }),
+                    Err(_) => {
+                        inner.free_wakers.register(cx.waker());
+                        Poll::Pending
+                    }
+                }
+            })
+        }).await
+    }
+
+    /// Hands the result event to the waiting request.
+    /// Returns the event if no request is waiting for it.
+    pub(crate) fn complete(&self, event: MqttEvent) -> Result<(), MqttEvent> {
+        let id = match event.request_id() {
+            Some(id) => id,
+            None => return Err(event),
+        };
+
+        self.inner.lock(|inner| {
+            let mut inner = inner.borrow_mut();
+
+            match inner.slots.iter().position(|slot| slot.id == id) {
+                Some(pos) if inner.slots[pos].cancelled => {
+                    debug!("discarding result of cancelled request {}", id);
+                    inner.remove(pos);
+                    Ok(())
+                },
+                Some(pos) => {
+                    let slot = &mut inner.slots[pos];
+                    slot.result = Some(event);
+                    slot.waker.wake();
+                    Ok(())
+                },
+                None => Err(event),
+            }
+        })
+    }
+
+    /// Returns the ids of all cancelled requests still waiting for a result
+    pub(crate) fn cancelled_requests(&self) -> Vec<UniqueID, N> {
+        self.inner.lock(|inner| {
+            inner.borrow().slots.iter()
+                .filter(|slot| slot.cancelled)
+                .map(|slot| slot.id)
+                .collect()
+        })
+    }
+
+    /// Releases the slot of the request `id` without a result
+    pub(crate) fn release(&self, id: UniqueID) {
+        self.inner.lock(|inner| {
+            let mut inner = inner.borrow_mut();
+            if let Some(pos) = inner.slots.iter().position(|slot| slot.id == id) {
+                inner.remove(pos);
+            }
+        })
+    }
+
+    /// Called if the request future is dropped before the result arrived.
+    /// If the request was `sent` to the event loop, the slot is kept until the
+    /// request is withdrawn or its result arrives.
+    fn cancel(&self, id: UniqueID, sent: bool) {
+        self.inner.lock(|inner| {
+            let mut inner = inner.borrow_mut();
+            if let Some(pos) = inner.slots.iter().position(|slot| slot.id == id) {
+                if sent && inner.slots[pos].result.is_none() {
+                    inner.slots[pos].cancelled = true;
+                } else {
+                    inner.remove(pos);
+                }
+            }
+        })
+    }
+
+    /// Waits for the result of the request `id` and releases its slot
+    async fn wait(&self, id: UniqueID) -> Result<MqttEvent, MqttError> {
+        poll_fn(|cx| {
+            self.inner.lock(|inner| {
+                let mut inner = inner.borrow_mut();
+
+                let pos = match inner.slots.iter().position(|slot| slot.id == id) {
+                    Some(pos) => pos,
+                    None => {
+                        error!("no completion slot reserved for request {}", id);
+                        return Poll::Ready(Err(MqttError::InternalError));
+                    }
+                };
+
+                let slot = &mut inner.slots[pos];
+                match slot.result.take() {
+                    Some(event) => {
+                        inner.remove(pos);
+                        Poll::Ready(Ok(event))
+                    },
+                    None => {
+                        slot.waker.register(cx.waker());
+                        Poll::Pending
+                    }
+                }
+            })
+        }).await
+    }
+}
+
+impl <M: RawMutex, const N: usize> WaitingRequests for CompletionTable<M, N> {
+    fn is_waiting(&self, id: UniqueID) -> bool {
+        self.inner.lock(|inner| {
+            inner.borrow().slots.iter()
+                .any(|slot| slot.id == id && ! slot.cancelled)
+        })
+    }
+}
+
+/// A reserved slot of the [`CompletionTable`].
+/// Dropping the reservation before the result arrived cancels the request.
+pub(crate) struct Reservation<'a, M: RawMutex, const N: usize> {
+    table: &'a CompletionTable<M, N>,
+    id: UniqueID,
+    sent: bool,
+    done: bool
+}
+
+impl <'a, M: RawMutex, const N: usize> Reservation<'a, M, N> {
+
+    /// Must be called after the request is handed to the event loop
+    pub(crate) fn on_sent(&mut self) {
+        self.sent = true;
+    }
+
+    /// Waits for the result of the request
+    pub(crate) async fn wait(mut self) -> Result<MqttEvent, MqttError> {
+        let result = self.table.wait(self.id).await;
+        self.done = true;
+        result
+    }
+}
+
+impl <'a, M: RawMutex, const N: usize> Drop for Reservation<'a, M, N> {
+    fn drop(&mut self) {
+        if ! self.done {
+            self.table.cancel(self.id, self.sent);
+        }
+    }
+}
+
+/// Sends request results to the waiting requests and broadcasts all events,
+/// request results included, to the subscribers of the event channel.
+///
+/// Request results are broadcast without waiting, a subscriber which falls behind
+/// misses the oldest events instead of stalling the requests.
+pub(crate) struct EventDispatcher<M: RawMutex, const N: usize, const C: usize> {
+    pub(crate) completions: CompletionTable<M, N>,
+    pub(crate) events: PubSubChannel<M, MqttEvent, C, 16, 8>
+}
+
+impl <M: RawMutex, const N: usize, const C: usize> EventDispatcher<M, N, C> {
+    pub(crate) fn new() -> Self {
+        Self {
+            completions: CompletionTable::new(),
+            events: PubSubChannel::new()
+        }
+    }
+
+    /// Hands the result to the waiting request before it is broadcast
+    fn dispatch_result(&self, item: MqttEvent) {
+        let _ = self.completions.complete(item.clone());
+        self.events.immediate_publisher().publish_immediate(item);
+    }
+}
+
+impl <M: RawMutex, const N: usize, const C: usize> AsyncSender<MqttEvent> for EventDispatcher<M, N, C> {
+    async fn send(&self, item: MqttEvent) {
+        if item.request_id().is_some() {
+            self.dispatch_result(item);
+        } else {
+            self.events.send(item).await;
+        }
+    }
+
+    /// Request results are always taken
+    fn try_send(&self, item: MqttEvent) -> Result<(), MqttEvent> {
+        if item.request_id().is_some() {
+            self.dispatch_result(item);
+            Ok(())
+        } else {
+            self.events.try_send(item)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+    use mqttrs::QoS;
+
+    use crate::{io::AsyncSender, MqttError, MqttEvent, UniqueID};
+
+    use super::{CompletionTable, EventDispatcher};
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_complete_before_wait() {
+        let table = CompletionTable::<CriticalSectionRawMutex, 2>::new();
+
+        let first = table.reserve(UniqueID(1)).await;
+        let second = table.reserve(UniqueID(2)).await;
+
+        table.complete(MqttEvent::SubscribeResult(UniqueID(2), Ok(QoS::AtMostOnce))).unwrap();
+        table.complete(MqttEvent::PublishResult(UniqueID(1), Err(MqttError::Timeout))).unwrap();
+
+        assert_eq!(first.wait().await, Ok(MqttEvent::PublishResult(UniqueID(1), Err(MqttError::Timeout))));
+        assert_eq!(second.wait().await, Ok(MqttEvent::SubscribeResult(UniqueID(2), Ok(QoS::AtMostOnce))));
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_unknown_results_are_returned() {
+        let table = CompletionTable::<CriticalSectionRawMutex, 2>::new();
+
+        assert_eq!(table.complete(MqttEvent::Connected), Err(MqttEvent::Connected));
+
+        let event = MqttEvent::PublishResult(UniqueID(7), Ok(()));
+        assert_eq!(table.complete(event.clone()), Err(event));
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_more_requests_than_slots() {
+        let table = CompletionTable::<CriticalSectionRawMutex, 2>::new();
+
+        let request = |id: u64| {
+            let table = &table;
+            async move {
+                let reservation = table.reserve(UniqueID(id)).await;
+                reservation.wait().await.unwrap()
+            }
+        };
+
+        let broker = async {
+            // Complete every request as soon as it got a slot
+            let mut done = [false; 5];
+            while done.iter().any(|done| ! done) {
+                for (id, done) in done.iter_mut().enumerate() {
+                    if ! *done && table.complete(MqttEvent::PublishResult(UniqueID(id as u64), Ok(()))).is_ok() {
+                        *done = true;
+                    }
+                }
+                tokio::task::yield_now().await;
+            }
+        };
+
+        let (r0, r1, r2, r3, r4, ()) = tokio::join!(
+            request(0), request(1), request(2), request(3), request(4),
+            broker
+        );
+
+        for (id, event) in [r0, r1, r2, r3, r4].into_iter().enumerate() {
+            assert_eq!(event, MqttEvent::PublishResult(UniqueID(id as u64), Ok(())));
+        }
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_cancel() {
+        let table = CompletionTable::<CriticalSectionRawMutex, 2>::new();
+
+        // Not sent to the event loop: the slot is released immediately
+        drop(table.reserve(UniqueID(1)).await);
+        assert!(table.cancelled_requests().is_empty());
+
+        // Sent to the event loop: the result is discarded
+        let mut reservation = table.reserve(UniqueID(2)).await;
+        reservation.on_sent();
+        drop(reservation);
+        assert_eq!(&table.cancelled_requests()[..], &[UniqueID(2)]);
+
+        assert_eq!(table.complete(MqttEvent::PublishResult(UniqueID(2), Ok(()))), Ok(()));
+        assert!(table.cancelled_requests().is_empty());
+
+        // Withdrawn by the event loop
+        let mut reservation = table.reserve(UniqueID(3)).await;
+        reservation.on_sent();
+        drop(reservation);
+        table.release(UniqueID(3));
+
+        // All slots are free again
+        let _a = table.reserve(UniqueID(4)).await;
+        let _b = table.reserve(UniqueID(5)).await;
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_results_are_broadcast() {
+        let dispatcher = EventDispatcher::<CriticalSectionRawMutex, 2, 4>::new();
+        let mut subscriber = dispatcher.events.subscriber().unwrap();
+
+        let mut reservation = dispatcher.completions.reserve(UniqueID(1)).await;
+        reservation.on_sent();
+
+        let event = MqttEvent::PublishResult(UniqueID(1), Ok(()));
+        dispatcher.send(event.clone()).await;
+        dispatcher.try_send(MqttEvent::Connected).unwrap();
+
+        assert_eq!(reservation.wait().await, Ok(event.clone()));
+        assert_eq!(subscriber.next_message_pure().await, event);
+        assert_eq!(subscriber.next_message_pure().await, MqttEvent::Connected);
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_results_do_not_wait_for_subscribers() {
+        let dispatcher = EventDispatcher::<CriticalSectionRawMutex, 2, 1>::new();
+        // Never reads its events
+        let mut subscriber = dispatcher.events.subscriber().unwrap();
+
+        for id in 0..4 {
+            let mut reservation = dispatcher.completions.reserve(UniqueID(id)).await;
+            reservation.on_sent();
+
+            let event = MqttEvent::PublishResult(UniqueID(id), Ok(()));
+            if id % 2 == 0 {
+                dispatcher.send(event.clone()).await;
+            } else {
+                dispatcher.try_send(event.clone()).unwrap();
+            }
+            assert_eq!(reservation.wait().await, Ok(event));
+        }
+
+        // The subscriber missed the older results
+        assert_eq!(subscriber.try_next_message_pure(), Some(MqttEvent::PublishResult(UniqueID(3), Ok(()))));
+        assert_eq!(subscriber.try_next_message_pure(), None);
+    }
+}
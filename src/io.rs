@@ -1,13 +1,15 @@
-use core::{cell::RefCell, future::Future, pin::Pin};
+use core::{cell::{Cell, RefCell}, future::{pending, Future}, pin::{pin, Pin}};
 
 use buffer::{new_stack_buffer, Buffer, BufferReader, BufferWriter, ReadWrite};
-use embassy_futures::select::{select, select3, Either3};
+use embassy_futures::select::{select, select3, Either, Either3};
 use embassy_sync::{blocking_mutex::raw::RawMutex, channel::Channel, pubsub::PubSubChannel};
-use mqttrs::{decode_slice_with_len, Packet, QoS};
+use mqttrs::{decode_slice_with_len, Packet, Publish, QoS};
 use network::mqtt::MqttPacketError;
-use network::NetworkError;
+use network::{NetworkError, NetworkOperation};
 use network::{ mqtt::WriteMqttPacketMut, NetwordSendReceive, NetworkConnection };
-use crate::{client::MqttClient, state::State, time, ClientConfig, MqttError, MqttEvent, MqttPublish, MqttRequest};
+use embedded_io_async::Read;
+use crate::{client::MqttClient, completion::{EventDispatcher, WaitingRequests}, state::State, stream::{decode_publish_header, topic_matches, PayloadReader, PayloadStream}, time, ClientConfig, MqttError, MqttEvent, MqttPublish, MqttRequest, Topic, UniqueID};
+use crate::{DEFAULT_CHANNEL_SIZE, DEFAULT_MAX_PENDING_REQUESTS, DEFAULT_PUBLISH_QUEUE_SIZE, DEFAULT_RECEIVE_QUEUE_SIZE, DEFAULT_SUBSCRIBE_QUEUE_SIZE, MAX_TOPIC_SIZE, MQTT_PAYLOAD_MAX_SIZE};
 
 use crate::time::Duration;
 
@@ -51,36 +53,168 @@ impl <M: RawMutex, T, const N: usize> AsyncReceiver<T> for Channel<M, T, N> {
     }
 }
 
-pub struct MqttEventLoop<M: RawMutex, const B: usize> {
-    recv_buffer: RefCell<Buffer<[u8; B]>>,
+/// Handles received publishes inside the event loop, see [`MqttEventLoop::run_with_handler`].
+///
+/// The publish borrows topic and payload from the receive buffer of the event loop,
+/// so nothing is copied. The event loop does not process further packets until the
+/// returned future completes.
+pub trait PublishHandler {
+    fn on_publish(&self, publish: &Publish<'_>) -> impl Future<Output = ()>;
+
+    /// Returns false if the publish cannot be handled. It is then neither passed to
+    /// [`PublishHandler::on_publish`] nor acknowledged, so the broker delivers QoS 1 and QoS 2 publishes again.
+    fn accepts(&self, _publish: &Publish<'_>) -> bool {
+        true
+    }
+
+    /// Topic filters of the publishes passed to [`PublishHandler::on_publish_stream`] instead of
+    /// [`PublishHandler::on_publish`]. Their payload does not have to fit into the receive buffer.
+    fn stream_filters(&self) -> &[&str] {
+        &[]
+    }
+
+    /// Handles a publish matching one of the [`PublishHandler::stream_filters`] while its payload is received.
+    ///
+    /// `payload` reads the payload in chunks from the network, the part not read is discarded.
+    /// QoS 1 and QoS 2 publishes are acknowledged after the whole payload was received.
+    fn on_publish_stream(&self, _publish: &StreamedPublish<'_>, _payload: &mut impl Read<Error = MqttError>) -> impl Future<Output = ()> {
+        async {}
+    }
+}
+
+/// A received publish whose payload is streamed to [`PublishHandler::on_publish_stream`]
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StreamedPublish<'a> {
+    pub topic: &'a str,
+    pub qos: QoS,
+    pub retain: bool,
+
+    /// Length of the payload in bytes
+    pub len: usize
+}
+
+impl <F: Fn(&Publish<'_>)> PublishHandler for F {
+    async fn on_publish(&self, publish: &Publish<'_>) {
+        self(publish)
+    }
+}
+
+/// Copies the received publishes into the channel read by [`MqttClient::receive`]
+struct ClientPublishHandler<'a, M: RawMutex, const T: usize, const PL: usize, const C: usize> {
+    received_publishes: &'a Channel<M, MqttPublish<T, PL>, C>
+}
+
+impl <'a, M: RawMutex, const T: usize, const PL: usize, const C: usize> PublishHandler for ClientPublishHandler<'a, M, T, PL, C> {
+    fn accepts(&self, publish: &Publish<'_>) -> bool {
+        if publish.topic_name.len() > T {
+            warn!("Topic of received message is longer than {}: {}", T, publish.topic_name.len());
+            false
+        } else if publish.payload.len() > PL {
+            warn!("Payload of received message is longer than {}: {}", PL, publish.payload.len());
+            false
+        } else {
+            true
+        }
+    }
+
+    async fn on_publish(&self, publish: &Publish<'_>) {
+        match MqttPublish::try_from(publish) {
+            Ok(publish) => self.received_publishes.send(publish).await,
+            Err(e) => {
+                error!("could not transform &Publish<'_> to MqttPublish: {}", e);
+            },
+        }
+    }
+}
+
+/// The event loop of the client.
+/// 
+/// - `B`: size of the send buffer
+/// - `RB`: size of the receive buffer, must hold the largest packet received
+/// - `R`: number of requests of [`MqttClient`] which can wait for their result at the same time
+/// - `P`: capacity of the queue of outgoing publishes
+/// - `I`: capacity of the queue of received QoS 1 and QoS 2 publishes in progress
+/// - `S`: capacity of the queue of subscribes and unsubscribes, must hold all auto subscribes
+/// - `C`: capacity of the channels between [`MqttClient`] and the event loop
+/// - `T`: maximum length of topics, auto subscribes of the [`ClientConfig`] included
+/// - `PL`: maximum length of payloads of [`MqttPublish`]
+pub struct MqttEventLoop<
+    M: RawMutex,
+    const B: usize,
+    const RB: usize = B,
+    const R: usize = DEFAULT_MAX_PENDING_REQUESTS,
+    const P: usize = DEFAULT_PUBLISH_QUEUE_SIZE,
+    const I: usize = DEFAULT_RECEIVE_QUEUE_SIZE,
+    const S: usize = DEFAULT_SUBSCRIBE_QUEUE_SIZE,
+    const C: usize = DEFAULT_CHANNEL_SIZE,
+    const T: usize = MAX_TOPIC_SIZE,
+    const PL: usize = MQTT_PAYLOAD_MAX_SIZE
+> {
+    recv_buffer: RefCell<Buffer<[u8; RB]>>,
     send_buffer: RefCell<Buffer<[u8; B]>>,
 
-    state: State<M>,
+    state: State<M, P, I, S, T, PL>,
 
-    control_sender: PubSubChannel<M, MqttEvent, 4, 16, 8>,
-    request_receiver: Channel<M, MqttRequest, 4>,
-    received_publishes: Channel<M, MqttPublish, 4>
+    events: EventDispatcher<M, R, C>,
+    payload_stream: PayloadStream<M>,
+    request_receiver: Channel<M, MqttRequest<T, PL>, C>,
+    received_publishes: Channel<M, MqttPublish<T, PL>, C>,
+
+    /// The network job stops before it starts with the next packet
+    stopping: Cell<bool>
 }
 
-impl <M: RawMutex, const B: usize> MqttEventLoop<M, B> {
+/// Why the connection to the broker is stopped
+struct Stopped {
+    /// Request id and number of abandoned requests of a graceful disconnect
+    graceful: Option<(UniqueID, usize)>,
 
-    pub fn new(config: ClientConfig) -> Self {
-        
-        Self {
-            recv_buffer: RefCell::new(new_stack_buffer::<B>()),
+    /// The connection failed while stopping, so no DISCONNECT is sent
+    failure: Option<MqttError>
+}
+
+impl <
+    M: RawMutex,
+    const B: usize,
+    const RB: usize,
+    const R: usize,
+    const P: usize,
+    const I: usize,
+    const S: usize,
+    const C: usize,
+    const T: usize,
+    const PL: usize
+> MqttEventLoop<M, B, RB, R, P, I, S, C, T, PL> {
+
+    /// Creates the event loop for `config`.
+    ///
+    /// Fails with [`MqttError::TooManyAutoSubscribes`] if `config` has more auto subscribes
+    /// than fit into the subscribe queue of capacity `S`.
+    pub fn new(config: ClientConfig<T>) -> Result<Self, MqttError> {
+        if config.auto_subscribes.len() > S {
+            error!("{} auto subscribes do not fit into the subscribe queue of capacity {}", config.auto_subscribes.len(), S);
+            return Err(MqttError::TooManyAutoSubscribes);
+        }
+
+        Ok(Self {
+            recv_buffer: RefCell::new(new_stack_buffer::<RB>()),
             send_buffer: RefCell::new(new_stack_buffer::<B>()),
 
             state: State::new(config),
 
-            control_sender: PubSubChannel::new(),
+            events: EventDispatcher::new(),
+            payload_stream: PayloadStream::new(),
             request_receiver: Channel::new(),
-            received_publishes: Channel::new()
-        }
+            received_publishes: Channel::new(),
+            stopping: Cell::new(false)
+        })
     }
 
-    pub fn client<'a>(&'a self) -> MqttClient<'a, M> {
+    pub fn client<'a>(&'a self) -> MqttClient<'a, M, R, C, T, PL> {
         MqttClient{
-            control_reveiver: &self.control_sender,
+            events: &self.events,
+            payload_stream: &self.payload_stream,
             request_sender: self.request_receiver.sender(),
             received_publishes: self.received_publishes.receiver()
         }
@@ -104,7 +238,8 @@ impl <M: RawMutex, const B: usize> MqttEventLoop<M, B> {
 
         let mut recv_buffer = self.recv_buffer.borrow_mut();
         // Do not block for receiving if there is still something to send
-        if send_buffer.has_remaining_len() {
+        // or a complete packet is waiting in the receive buffer
+        if send_buffer.has_remaining_len() || Self::has_complete_packet(&recv_buffer) {
             let n = connection.try_receive(&mut recv_buffer).await
                 .map_err(|e| MqttError::ConnectionFailed(e))?;
             trace!("try_receive() {} bytes from network", n);
@@ -117,11 +252,18 @@ impl <M: RawMutex, const B: usize> MqttEventLoop<M, B> {
         Ok(())
     }
 
-    /// Try to read a packet from recv buffer. 
-    async fn try_package_receive(&self, send_buffer: &mut impl BufferWriter, recv_buffer: impl BufferReader) -> Result<(), MqttError> {
+    /// Checks if the receive buffer contains a packet which can be processed
+    fn has_complete_packet(recv_buffer: &Buffer<[u8; RB]>) -> bool {
+        let data = recv_buffer.data();
+        // Decoding errors are reported when the packet is processed
+        ! data.is_empty() && ! matches!(decode_slice_with_len(data), Ok(None))
+    }
+
+    /// Try to read a packet from recv buffer. Returns if a packet was processed.
+    async fn try_package_receive(&self, send_buffer: &mut impl BufferWriter, recv_buffer: impl BufferReader, publish_handler: &impl PublishHandler) -> Result<bool, MqttError> {
         if recv_buffer.is_empty() {
             trace!("try_package_receive(): recv_buffer is empty, cannot read packet");
-            return Ok(())
+            return Ok(false)
         }
         
         let packet_op = decode_slice_with_len(&recv_buffer[..])
@@ -134,40 +276,246 @@ impl <M: RawMutex, const B: usize> MqttEventLoop<M, B> {
             debug!("try_package_receive(): decoded packet from recv_buffer: len = {}, kind = {}", len, packet.get_type());
             recv_buffer.add_bytes_read(len);
             let events = 
-                self.state.process_packet(&packet, send_buffer, &self.received_publishes).await?;
+                self.state.process_packet(&packet, send_buffer, publish_handler).await?;
             
             if ! events.is_empty() {
                 for event in events {
                     debug!("try_package_receive(): processing packet -> MqttEvent: {}", &event);
-                    self.control_sender.publisher().unwrap().publish(event).await;
+                    self.events.send(event).await;
                 }
             } else {
                 trace!("try_package_receive(): packet processed, no MqttEvent");
             }
 
+            Ok(true)
         } else {
             trace!("try_package_receive(): no complete packet in recv_buffer");
+            Ok(false)
         }
+    }
 
-        Ok(())
+    /// Passes the publish at the start of the receive buffer with its payload in chunks
+    /// to the publish handler if the handler streams its topic. Returns if a publish was streamed.
+    async fn try_stream_receive<N: NetworkConnection>(&self, connection: &mut N, publish_handler: &impl PublishHandler) -> Result<bool, MqttError> {
+        let mut recv_buffer = self.recv_buffer.borrow_mut();
+
+        let (topic, qospid, retain, header_len, payload_len, deliver) = {
+            let header = match decode_publish_header(recv_buffer.data())? {
+                Some(header) => header,
+                None => return Ok(false),
+            };
+
+            let publish = &header.publish;
+            if ! publish_handler.stream_filters().iter().any(|filter| topic_matches(filter, publish.topic_name)) {
+                return Ok(false);
+            }
+
+            let mut topic = Topic::<T>::new();
+            let topic_fits = topic.push_str(publish.topic_name).is_ok();
+            if ! topic_fits {
+                warn!("Topic of received message is longer than {}: {}", T, publish.topic_name.len());
+            }
+
+            // Rejected before it is registered, so it is not acknowledged
+            let deliver = topic_fits && self.state.received_publishes.process_publish(publish);
+            (topic, publish.qospid, publish.retain, header.header_len, header.payload_len, deliver)
+        };
+
+        debug!("streaming received publish to {}: len = {}", topic.as_str(), payload_len);
+        recv_buffer.skip(header_len).unwrap();
+
+        let mut payload = PayloadReader::new(connection, &mut recv_buffer, payload_len);
+        if deliver {
+            let publish = StreamedPublish {
+                topic: &topic,
+                qos: qospid.qos(),
+                retain,
+                len: payload_len
+            };
+            publish_handler.on_publish_stream(&publish, &mut payload).await;
+        }
+
+        if let Err(e) = payload.skip_remaining().await {
+            // The broker delivers the publish again
+            if deliver {
+                self.state.received_publishes.forget_publish(qospid);
+            }
+            return Err(e);
+        }
+
+        Ok(true)
+    }
+
+    /// Writes the streamed payload of the request `id` after its header to the send buffer.
+    /// Acknowledgements are received meanwhile, other packets are processed after the payload.
+    /// Returns false if the request stopped streaming and the packet was taken back from the send buffer.
+    async fn stream_payload<N: NetworkConnection>(&self, connection: &mut N, id: UniqueID, len: usize, header_len: usize) -> Result<bool, MqttError> {
+        let generation = self.payload_stream.start();
+
+        // The packet can be taken back as long as nothing of it was sent
+        let mut queued_before = self.send_buffer.borrow().remaining_len() - header_len;
+        let mut packet_sent = false;
+
+        // The packets in front must not wait for the payload
+        if queued_before > 0 {
+            let mut send_buffer = self.send_buffer.borrow_mut();
+            let mut reader = send_buffer.create_reader_with_max(queued_before);
+            connection.send_all(&mut reader).await
+                .map_err(MqttError::ConnectionFailed)?;
+            reader.add_bytes_read(queued_before);
+            trace!("sent {} bytes queued before streamed publish {} to network", queued_before, id);
+            queued_before = 0;
+        }
+
+        let mut remaining = len;
+        while remaining > 0 {
+            let aborted = ! self.events.completions.is_waiting(id);
+
+            let next = {
+                let mut recv_buffer = self.recv_buffer.borrow_mut();
+                let receive = async {
+                    if recv_buffer.ensure_remaining_capacity() {
+                        connection.receive(&mut recv_buffer).await
+                    } else {
+                        // Resumes after the payload when the packets in front are processed
+                        pending().await
+                    }
+                };
+
+                if aborted {
+                    Either3::Second(())
+                } else {
+                    select3(self.payload_stream.receive(generation), self.payload_stream.aborted(id), receive).await
+                }
+            };
+
+            let chunk = match next {
+                Either3::First(chunk) => chunk,
+                Either3::Second(()) if packet_sent => {
+                    warn!("streaming publish {} aborted with {} bytes left: resetting connection", id, remaining);
+                    return Err(MqttError::ConnectionFailed(NetworkError::ConnectionFailed));
+                },
+                Either3::Second(()) => {
+                    let mut send_buffer = self.send_buffer.borrow_mut();
+                    let written = header_len + len - remaining;
+                    let keep = send_buffer.remaining_len() - written;
+                    send_buffer.truncate(keep).unwrap();
+                    warn!("streaming publish {} aborted with {} bytes left: packet taken back", id, remaining);
+                    return Ok(false);
+                },
+                Either3::Third(result) => {
+                    let n = result.map_err(MqttError::ConnectionFailed)?;
+                    trace!("received {} bytes while streaming publish {}", n, id);
+                    self.process_acknowledgements().await?;
+                    continue;
+                },
+            };
+
+            let mut data = &chunk.data[..chunk.data.len().min(remaining)];
+            remaining -= data.len();
+
+            while ! data.is_empty() {
+                let mut send_buffer = self.send_buffer.borrow_mut();
+                if ! send_buffer.ensure_remaining_capacity() {
+                    let n = connection.send(&mut send_buffer).await
+                        .map_err(MqttError::ConnectionFailed)?;
+                    trace!("sent {} bytes of streamed publish {} to network", n, id);
+
+                    if n > queued_before {
+                        packet_sent = true;
+                    } else {
+                        queued_before -= n;
+                    }
+                    continue;
+                }
+
+                let mut writer = send_buffer.create_writer();
+                let n = writer.len().min(data.len());
+                writer[..n].copy_from_slice(&data[..n]);
+                writer.commit(n).unwrap();
+                drop(writer);
+
+                data = &data[n..];
+            }
+        }
+
+        debug!("streamed payload of publish {} written to send buffer; len = {}", id, len);
+        Ok(true)
+    }
+
+    /// Processes the acknowledgements at the start of the receive buffer.
+    /// Stops at packets which need an answer or are passed to the publish handler.
+    async fn process_acknowledgements(&self) -> Result<(), MqttError> {
+        loop {
+            let events = {
+                let mut recv_buffer = self.recv_buffer.borrow_mut();
+                let (len, packet) = match decode_slice_with_len(recv_buffer.data()) {
+                    Ok(Some((len, packet))) if Self::is_acknowledgement(&packet) => (len, packet),
+                    _ => return Ok(()),
+                };
+
+                debug!("processing packet received while streaming: kind = {}", packet.get_type());
+                let mut send_buffer = self.send_buffer.borrow_mut();
+                let events = self.state.process_packet(&packet, &mut send_buffer.create_writer(), &|_: &Publish<'_>| {}).await?;
+                recv_buffer.skip(len).unwrap();
+                events
+            };
+
+            for event in events {
+                self.events.send(event).await;
+            }
+        }
+    }
+
+    /// Acknowledgements neither write to the send buffer nor call the publish handler
+    fn is_acknowledgement(packet: &Packet<'_>) -> bool {
+        matches!(packet, Packet::Puback(_) | Packet::Pubrel(_) | Packet::Pubcomp(_) | Packet::Suback(_) | Packet::Unsuback(_) | Packet::Pingresp)
+    }
+
+    /// Removes the requests from the queues whose futures were dropped 
+    /// before they were written to the send buffer
+    fn withdraw_cancelled_requests(&self) {
+        for id in self.events.completions.cancelled_requests() {
+            if self.state.publishes.withdraw(id) || self.state.subscribes.withdraw(id) {
+                debug!("request {} withdrawn: cancelled before it was sent", id);
+                self.events.completions.release(id);
+            }
+        }
     }
 
     /// Makes the receive / send of the network
     /// First tries to write outgoing traffic to buffer
     /// Then tries to read / write to / from the connection
     /// Then read data from receive buffer
-    async fn work_network<N: NetworkConnection>(&self, connection: &mut N) -> Result<!, MqttError> {
+    /// Returns when stopping, before the next packet is written or processed.
+    async fn work_network<N: NetworkConnection>(&self, connection: &mut N, publish_handler: &impl PublishHandler) -> Result<(), MqttError> {
         loop {
+            if self.stopping.get() {
+                debug!("network job stopped");
+                return Ok(());
+            }
+
+            self.withdraw_cancelled_requests();
+
             // Try to send packets first before blocking for network traffic
             // Send packets (Ping, Connect, Publish)
             {   
                 let mut send_buffer = self.send_buffer.borrow_mut();
                 let mut send_buffer_writer = send_buffer.create_writer();
-                self.state.send_packets(&mut send_buffer_writer, &self.control_sender)?;
+                self.state.send_packets(&mut send_buffer_writer, &self.events, &self.events.completions)?;
                 drop(send_buffer_writer);
                 trace!("after network send: send_buffer {} / {}", send_buffer.remaining_len(), send_buffer.remaining_capacity());
 
             }
+
+            // The payload of a streamed publish must follow its header
+            if let Some((id, len, header_len)) = self.state.publishes.take_stream() {
+                if self.stream_payload(connection, id, len, header_len).await? {
+                    self.state.publishes.on_stream_done(id, &self.events);
+                } else {
+                    self.state.publishes.on_stream_aborted(id, &self.events);
+                }
+            }
             
             // Send / Receive Network traffic
             // Interript this when ...
@@ -193,32 +541,46 @@ impl <M: RawMutex, const B: usize> MqttEventLoop<M, B> {
                 },
             }?;
 
-            let mut send_buffer = self.send_buffer.borrow_mut();
-            let mut recv_buffer = self.recv_buffer.borrow_mut();
-            let recv_reader = recv_buffer.create_reader();
-            let mut send_buffer_writer = send_buffer.create_writer();
+            // Process all packets in the receive buffer, not only the first one
+            loop {
+                // Publishes to streamed topics are handled before they are received completely
+                let streamed = self.try_stream_receive(connection, publish_handler).await?;
 
-            // Try to read a package from the receive buffer and write answers (e. g. acknoledgements) 
-            // to the send buffer
-            self.try_package_receive(&mut send_buffer_writer, recv_reader).await?; 
+                let mut send_buffer = self.send_buffer.borrow_mut();
+                let mut recv_buffer = self.recv_buffer.borrow_mut();
+                let recv_reader = recv_buffer.create_reader();
+                let mut send_buffer_writer = send_buffer.create_writer();
+
+                // Try to read a package from the receive buffer and write answers (e. g. acknoledgements)
+                // to the send buffer
+                let received = self.try_package_receive(&mut send_buffer_writer, recv_reader, publish_handler).await?;
+
+                trace!("after try packege_receive: recv_buffer: {} / {}", recv_buffer.remaining_len(), recv_buffer.capacity());
 
-            trace!("after try packege_receive: recv_buffer: {} / {}", recv_buffer.remaining_len(), recv_buffer.capacity());
+                if ! streamed && ! received {
+                    break;
+                }
+            }
         }
     }
 
 
     /// Receive requests from cleint.
-    /// Returns if the client sends a disconnect message
-    async fn work_request_receive(&self) -> Result<(), MqttError> {
+    /// Returns if the client sends a disconnect message, with timeout and request id of a graceful disconnect
+    async fn work_request_receive(&self) -> Result<Option<(Duration, UniqueID)>, MqttError> {
         loop {
             let req = self.request_receiver.receive().await;
-            let pid = self.state.pid_source.next_pid();
+            let pid = self.state.next_pid();
 
             match req {
                 MqttRequest::Publish(mqtt_publish, id) => {
                                 self.state.publishes.push_publish(mqtt_publish, id, pid).await;
                                 debug!("new publish request added to queue");
                             },
+                MqttRequest::PublishStream(mqtt_publish, len, id) => {
+                                self.state.publishes.push_publish_streamed(mqtt_publish, len, id, pid).await;
+                                debug!("new publish request with streamed payload added to queue");
+                            },
                 MqttRequest::Subscribe(topic, unique_id) => {
                                 const SUBSCRIBE_QOS: QoS = QoS::AtMostOnce;
                                 self.state.subscribes.push_subscribe(topic, pid, unique_id, SUBSCRIBE_QOS).await;
@@ -230,7 +592,10 @@ impl <M: RawMutex, const B: usize> MqttEventLoop<M, B> {
                             },
                 MqttRequest::Disconnect => {
                     self.state.on_requst_added.signal(0);
-                    return Ok(());
+                    return Ok(None);
+                },
+                MqttRequest::DisconnectGraceful(timeout, id) => {
+                    return Ok(Some((timeout, id)));
                 },
             }
 
@@ -253,6 +618,11 @@ impl <M: RawMutex, const B: usize> MqttEventLoop<M, B> {
                     info!("connect to broker success");
                     return Ok(())
                 },
+                Err(NetworkError::LinkDown) => {
+                    // Waiting for the link does not use up the tries
+                    warn!("network link is down, waiting before connecting");
+                    time::sleep(Duration::from_secs(3)).await;
+                },
                 Err(e) => {
                     tries += 1;
                     if tries < 5 {
@@ -267,30 +637,126 @@ impl <M: RawMutex, const B: usize> MqttEventLoop<M, B> {
         }
     }
 
-    async fn work<N: NetworkConnection>(&self, connection: &mut N) -> Result<(), MqttError> {
+    async fn work<N: NetworkConnection>(&self, connection: &mut N, publish_handler: &impl PublishHandler) -> Result<Stopped, MqttError> {
         // Reset state on new connection
         self.state.reset();
-            
-        // Poll both futures
-        // Select should never befinished because both jobs are infinite
-        let network_future = self.work_network(connection);
-        let request_future = self.work_request_receive();
-        match select(network_future, request_future).await {
-            embassy_futures::select::Either::First(net_result) => {
-                let err = net_result.unwrap_err();
-                error!("network infinite job finished: {}", &err);
-                Err(err)
-            },
-            embassy_futures::select::Either::Second(req_result) => {
-                if let Err(err) = req_result {
-                    error!("infinite request receive job finished: {}", &err);
-                    Err(err)
-                } else {
+        self.stopping.set(false);
+
+        // A connection failing while draining ends the graceful disconnect
+        let draining = Cell::new(None);
+
+        let network_future = self.work_network(connection, publish_handler);
+        let request_future = async {
+            let graceful = match self.work_request_receive().await? {
+                None => {
                     info!("disconnect request received: stopping jobs");
-                    Ok(())
-                }
+                    None
+                },
+                Some((timeout, id)) => {
+                    info!("graceful disconnect request received: waiting for requests in progress");
+                    draining.set(Some(id));
+                    self.drain(timeout).await;
+                    Some(id)
+                },
+            };
+
+            // The network job finishes the packet in progress
+            self.stopping.set(true);
+            self.state.on_requst_added.signal(0);
+            Ok::<_, MqttError>(graceful)
+        };
+
+        let mut network_future = pin!(network_future);
+        let (graceful, failure) = match select(network_future.as_mut(), request_future).await {
+            Either::First(Ok(())) => unreachable!("network job stopped without a disconnect request"),
+            Either::First(Err(err)) => match draining.get() {
+                Some(id) => (Some(id), Some(err)),
+                None => {
+                    error!("network infinite job finished: {}", &err);
+                    return Err(err);
+                },
             },
+            Either::Second(Err(err)) => {
+                error!("infinite request receive job finished: {}", &err);
+                return Err(err);
+            },
+            Either::Second(Ok(graceful)) => {
+                let failure = match select(network_future, self.reject_requests()).await {
+                    Either::First(result) => result.err(),
+                };
+                (graceful, failure)
+            },
+        };
+
+        if let Some(err) = &failure {
+            warn!("connection failed while disconnecting: {}", err);
         }
+
+        let graceful = match graceful {
+            Some(id) => Some((id, self.abandon_requests().await)),
+            None => None,
+        };
+
+        Ok(Stopped { graceful, failure })
+    }
+
+    /// Waits until no publish, subscribe or acknowledgement is in progress
+    /// or `timeout` expired. New requests are rejected meanwhile.
+    async fn drain(&self, timeout: Duration) {
+        let drained_future = async {
+            while ! self.state.is_drained() {
+                self.state.on_queues_processed.wait().await;
+            }
+        };
+
+        match select3(drained_future, time::sleep(timeout), self.reject_requests()).await {
+            Either3::First(()) => {
+                debug!("all requests done: disconnecting");
+            },
+            Either3::Second(()) => {
+                warn!("requests not done within the disconnect timeout");
+            },
+        }
+    }
+
+    /// Answers the requests of the client with [`MqttError::Abandoned`] while disconnecting
+    async fn reject_requests(&self) -> ! {
+        loop {
+            let event = match self.request_receiver.receive().await {
+                MqttRequest::Publish(_, id) |
+                    MqttRequest::PublishStream(_, _, id) => MqttEvent::PublishResult(id, Err(MqttError::Abandoned)),
+                MqttRequest::Subscribe(_, id) => MqttEvent::SubscribeResult(id, Err(MqttError::Abandoned)),
+                MqttRequest::Unsubscribe(_, id) => MqttEvent::UnsubscribeResult(id, Err(MqttError::Abandoned)),
+                MqttRequest::DisconnectGraceful(_, id) => MqttEvent::DisconnectResult(id, Ok(0)),
+                MqttRequest::Disconnect => continue,
+            };
+
+            debug!("rejecting request while disconnecting: {}", &event);
+            self.events.send(event).await;
+        }
+    }
+
+    /// Removes all publishes and subscribes in progress and sends their results.
+    /// Returns the number of requests abandoned.
+    async fn abandon_requests(&self) -> usize {
+        let publishes = self.state.publishes.abandon();
+        let subscribes = self.state.subscribes.abandon();
+
+        let mut abandoned = 0;
+        for event in publishes.into_iter().chain(subscribes) {
+            if matches!(event, MqttEvent::PublishResult(_, Err(MqttError::Abandoned)) |
+                MqttEvent::SubscribeResult(_, Err(MqttError::Abandoned)) |
+                MqttEvent::UnsubscribeResult(_, Err(MqttError::Abandoned))) {
+                abandoned += 1;
+            }
+
+            self.events.send(event).await;
+        }
+
+        if abandoned > 0 {
+            warn!("{} requests abandoned", abandoned);
+        }
+        abandoned
     }
 
     async fn disconnect<N: NetworkConnection>(&self, connection: &mut N) -> Result<(), MqttError> {
@@ -305,7 +771,7 @@ impl <M: RawMutex, const B: usize> MqttEventLoop<M, B> {
                     MqttError::BufferFull
                 },
                 MqttPacketError::CodecError => MqttError::CodecError,
-                MqttPacketError::IoError(_) => MqttError::ConnectionFailed(NetworkError::ConnectionFailed),
+                MqttPacketError::IoError(kind) => MqttError::ConnectionFailed(NetworkError::Io(NetworkOperation::Write, kind)),
                 MqttPacketError::NetworkError(network_error) => MqttError::ConnectionFailed(network_error),
             }
         })?;
@@ -322,7 +788,19 @@ impl <M: RawMutex, const B: usize> MqttEventLoop<M, B> {
 
     }
 
+    /// Runs the client until it is disconnected.
+    /// Received publishes are delivered to [`MqttClient::receive`].
     pub async fn run<N: NetworkConnection>(&self, connection: Pin<&mut N>) -> Result<(), MqttError> {
+        let publish_handler = ClientPublishHandler {
+            received_publishes: &self.received_publishes
+        };
+
+        self.run_with_handler(connection, &publish_handler).await
+    }
+
+    /// Runs the client like [`Self::run`] but hands received publishes directly from the
+    /// receive buffer to `publish_handler` instead of copying them to [`MqttClient::receive`].
+    pub async fn run_with_handler<N: NetworkConnection>(&self, connection: Pin<&mut N>, publish_handler: &impl PublishHandler) -> Result<(), MqttError> {
 
         let connection = unsafe {
             connection.get_unchecked_mut()
@@ -330,26 +808,37 @@ impl <M: RawMutex, const B: usize> MqttEventLoop<M, B> {
 
         self.connect(connection).await?;
 
-        loop {
-            let result = self.work(connection).await;
+        let stopped = loop {
+            let result = self.work(connection, publish_handler).await;
             match result {
-                Ok(()) => {
-                    break;
+                Ok(stopped) => {
+                    break stopped;
                 }
                 Err(MqttError::ConnectionFailed(e)) => {
-                    warn!("reconnecting, conection faild: {}", e);
+                    if e.is_closed() {
+                        info!("reconnecting, connection closed by broker");
+                    } else {
+                        warn!("reconnecting, conection faild: {}", e);
+                    }
                     self.connect(connection).await?;
                 }
                 Err(err) => {
                     return Err(err);
                 }
             }
-        }
+        };
 
-        self.disconnect(connection).await?;
+        let result = match stopped.failure {
+            Some(err) => Err(err),
+            None => self.disconnect(connection).await,
+        };
 
-        Ok(())
+        if let Some((id, abandoned)) = stopped.graceful {
+            let event = MqttEvent::DisconnectResult(id, result.clone().map(|()| abandoned));
+            self.events.send(event).await;
+        }
 
+        result
     }
 }
 
@@ -363,15 +852,16 @@ mod test {
     use crate::state::KEEP_ALIVE;
     use crate::time::Duration;
 
-    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+    use embassy_futures::select::{select, Either};
+    use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
     use heapless::String;
-    use mqttrs::{Connack, ConnectReturnCode, Packet, PacketType, QoS, Suback, SubscribeReturnCodes};
+    use mqttrs::{Connack, ConnectReturnCode, Packet, PacketType, Publish, QoS, QosPid, Suback, SubscribeReturnCodes};
     use crate::time;
 
     use network::{fake::{self, ConnectionRessources, ReadAtomic}, mqtt::{ReadMqttPacket, WriteMqttPacket}};
-    use crate::ClientConfig;
+    use crate::{ClientConfig, MqttError};
 
-    use super::MqttEventLoop;
+    use super::{ClientPublishHandler, MqttEventLoop, PublishHandler};
 
     fn print_packet(p: &Packet<'_>) -> std::string::String {
         match p {
@@ -392,6 +882,46 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    #[ntest::timeout(1000)]
+    async fn test_receive_topic_too_long() {
+        let publish = Publish{
+            dup: false,
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            payload: "test".as_bytes(),
+            topic_name: "test-topic"
+        };
+
+        let received_publishes = Channel::<CriticalSectionRawMutex, _, 1>::new();
+        let handler = ClientPublishHandler::<_, 4, 4, 1> { received_publishes: &received_publishes };
+        assert!(! handler.accepts(&publish));
+
+        let received_publishes = Channel::<CriticalSectionRawMutex, _, 1>::new();
+        let handler = ClientPublishHandler::<_, 10, 3, 1> { received_publishes: &received_publishes };
+        assert!(! handler.accepts(&publish));
+
+        let received_publishes = Channel::<CriticalSectionRawMutex, _, 1>::new();
+        let handler = ClientPublishHandler::<_, 10, 4, 1> { received_publishes: &received_publishes };
+        assert!(handler.accepts(&publish));
+        handler.on_publish(&publish).await;
+
+        let received = received_publishes.try_receive().expect("publish must be delivered");
+        assert_eq!(received.topic.as_str(), "test-topic");
+        assert_eq!(received.payload.data(), b"test");
+    }
+
+    #[test]
+    fn test_new_too_many_auto_subscribes() {
+        let config = ClientConfig::new_with_auto_subscribes("asjdkaljs", None, ["a", "b", "c"].into_iter(), QoS::AtLeastOnce).unwrap();
+
+        let result = MqttEventLoop::<CriticalSectionRawMutex, 1024, 1024, 2, 2, 2, 2>::new(config.clone());
+        assert!(matches!(result, Err(MqttError::TooManyAutoSubscribes)));
+
+        let result = MqttEventLoop::<CriticalSectionRawMutex, 1024, 1024, 2, 2, 2, 3>::new(config);
+        assert!(result.is_ok());
+    }
+
     #[tokio::test]
     async fn test_run() {
         time::test_time::set_default();
@@ -399,7 +929,9 @@ mod test {
         let mut config = ClientConfig{
             client_id: String::new(),
             credentials: None,
-            auto_subscribes: Vec::new()
+            auto_subscribes: Vec::new(),
+            retry_policy: Default::default(),
+            receive_maximum: crate::DEFAULT_RECEIVE_MAXIMUM
         };
 
         config.client_id.push_str("asjdkaljs").unwrap();
@@ -408,7 +940,7 @@ mod test {
 
         let (mut client, server) = fake::new_connection(&connection_resources);
 
-        let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(config);
+        let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(config).unwrap();
         let mqtt_client = event_loop.client();
 
         let runner_future = async {
@@ -461,7 +993,9 @@ mod test {
         let config = ClientConfig{
             client_id: String::new(),
             credentials: None,
-            auto_subscribes: Vec::new()
+            auto_subscribes: Vec::new(),
+            retry_policy: Default::default(),
+            receive_maximum: crate::DEFAULT_RECEIVE_MAXIMUM
         };
 
         time::test_time::set_static_now();
@@ -469,7 +1003,7 @@ mod test {
         let connection_resources = ConnectionRessources::<1024>::new();
         let (mut client, server) = fake::new_connection(&connection_resources);
 
-        let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(config);
+        let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(config).unwrap();
 
         let runner_future = async {
             let client = Pin::new(&mut client);
@@ -519,13 +1053,15 @@ mod test {
         let config = ClientConfig{
             client_id: String::new(),
             credentials: None,
-            auto_subscribes: Vec::new()
+            auto_subscribes: Vec::new(),
+            retry_policy: Default::default(),
+            receive_maximum: crate::DEFAULT_RECEIVE_MAXIMUM
         };
 
         let connection_resources = ConnectionRessources::<1024>::new();
         let (mut client, server) = fake::new_connection(&connection_resources);
 
-        let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(config);
+        let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(config).unwrap();
         let mqtt_client = event_loop.client();
 
         let runner_future = async {
@@ -557,5 +1093,84 @@ mod test {
             client_future
         };
     }
-}
 
+    /// Reports the link as down for the first connects
+    struct LinkDownConnection {
+        link_down_connects: usize,
+        connects: usize
+    }
+
+    impl embedded_io_async::ErrorType for LinkDownConnection {
+        type Error = embedded_io_async::ErrorKind;
+    }
+
+    impl embedded_io_async::Read for LinkDownConnection {
+        async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Self::Error> {
+            unreachable!()
+        }
+    }
+
+    impl embedded_io_async::ReadReady for LinkDownConnection {
+        fn read_ready(&mut self) -> Result<bool, Self::Error> {
+            unreachable!()
+        }
+    }
+
+    impl embedded_io_async::Write for LinkDownConnection {
+        async fn write(&mut self, _buf: &[u8]) -> Result<usize, Self::Error> {
+            unreachable!()
+        }
+    }
+
+    impl embedded_io_async::WriteReady for LinkDownConnection {
+        fn write_ready(&mut self) -> Result<bool, Self::Error> {
+            unreachable!()
+        }
+    }
+
+    impl network::NetworkConnection for LinkDownConnection {
+        async fn connect(&mut self) -> Result<(), network::NetworkError> {
+            self.connects += 1;
+            if self.connects <= self.link_down_connects {
+                Err(network::NetworkError::LinkDown)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(1000)]
+    async fn test_connect_waits_for_link() {
+        time::test_time::set_static_now();
+
+        let config = ClientConfig{
+            client_id: String::new(),
+            credentials: None,
+            auto_subscribes: Vec::new(),
+            retry_policy: Default::default(),
+            receive_maximum: crate::DEFAULT_RECEIVE_MAXIMUM
+        };
+        let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(config).unwrap();
+
+        // More link failures than connect tries
+        let mut connection = LinkDownConnection {
+            link_down_connects: 8,
+            connects: 0
+        };
+
+        let connect_future = event_loop.connect(&mut connection);
+        let time_future = async {
+            loop {
+                tokio::task::yield_now().await;
+                time::test_time::advance_time(Duration::from_secs(3));
+            }
+        };
+
+        match select(connect_future, time_future).await {
+            Either::First(result) => assert_eq!(result, Ok(())),
+            Either::Second(()) => unreachable!(),
+        }
+        assert_eq!(connection.connects, 9);
+    }
+}
@@ -1,6 +1,4 @@
 
-#![feature(never_type)]
-
 #![cfg_attr(not(feature = "std"), no_std)]
 
 use core::{cell::Cell, ops::Deref};
@@ -13,18 +11,24 @@ use heapless::Vec;
 
 pub use buffer::*;
 
-use mqttrs::{Pid, Publish, QosPid};
-pub use mqttrs::QoS;
+use mqttrs::{Pid, QosPid};
+pub use mqttrs::{Publish, QoS};
 
 // This must come first so the macros are visible
 pub(crate) mod fmt;
 
 pub mod io;
 pub(crate) mod state;
-use state::sub::MAX_CONCURRENT_REQUESTS;
+pub use state::retry::{RetransmitMode, RetryPolicy, MIN_RETRANSMIT_INTERVAL};
 
 pub(crate) mod time;
+/// Duration used by the API: `embassy_time::Duration` with the `embassy` feature,
+/// `std::time::Duration` with the `std` feature
+pub use time::Duration;
+
 pub mod client;
+pub(crate) mod completion;
+pub(crate) mod stream;
 
 pub(crate) mod misc;
 
@@ -82,7 +86,34 @@ pub enum MqttError {
     SubscribeOrUnsubscribeFailed,
 
     #[error("Some internal error occured")]
-    InternalError
+    InternalError,
+
+    #[error("The request was not acknowledged by the broker")]
+    Timeout,
+
+    #[error("The payload could not be written with the announced length")]
+    InvalidPayload,
+
+    #[error("The request was abandoned because the client disconnected")]
+    Abandoned,
+
+    #[error("All event listener slots are in use")]
+    TooManyListeners,
+
+    #[error("More auto subscribes than the subscribe queue can hold")]
+    TooManyAutoSubscribes,
+
+    #[error("A topic, payload or configuration value is longer than its capacity")]
+    TooLong
+}
+
+impl embedded_io_async::Error for MqttError {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        match self {
+            MqttError::ConnectionFailed(e) => e.kind().unwrap_or(embedded_io_async::ErrorKind::ConnectionReset),
+            _ => embedded_io_async::ErrorKind::Other
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -91,122 +122,160 @@ pub struct ClientCredentials {
     pub password: String<128>,
 }
 
-impl ClientCredentials {
-    pub fn new(username: &str, password: &str) -> Self {
-        let mut this = Self {
-            username: String::new(),
-            password: String::new()
-        };
+/// Copies `value` into a string of capacity `N`, fails with [`MqttError::TooLong`] if it does not fit
+pub(crate) fn copy_str<const N: usize>(value: &str, name: &str) -> Result<String<N>, MqttError> {
+    String::try_from(value)
+        .map_err(|_| {
+            warn!("{} is longer than {}: {}", name, N, value.len());
+            MqttError::TooLong
+        })
+}
 
-        this.username.push_str(username).unwrap();
-        this.password.push_str(password).unwrap();
-        this
+impl ClientCredentials {
+    /// Fails with [`MqttError::TooLong`] if `username` or `password` exceed their capacity
+    pub fn new(username: &str, password: &str) -> Result<Self, MqttError> {
+        Ok(Self {
+            username: copy_str(username, "username")?,
+            password: copy_str(password, "password")?
+        })
     }
 }
 
+/// A subscription to a topic of at most `T` bytes made after every connect
 #[derive(Debug, Clone)]
-pub struct AutoSubscribe {
-    pub topic: Topic,
+pub struct AutoSubscribe<const T: usize = MAX_TOPIC_SIZE> {
+    pub topic: Topic<T>,
     pub qos: QoS
 }
 
-impl AutoSubscribe {
-    pub fn new(topic: &str, qos: QoS) -> Self {
-        let mut this = Self {
-            topic: Topic::new(),
+impl <const T: usize> AutoSubscribe<T> {
+    /// Fails with [`MqttError::TooLong`] if `topic` is longer than `T`
+    pub fn new(topic: &str, qos: QoS) -> Result<Self, MqttError> {
+        Ok(Self {
+            topic: copy_str(topic, "auto subscribe topic")?,
             qos
-        };
-        this.topic.push_str(topic).unwrap();
-        this
+        })
     }
 }
 
+/// Configuration of the client with auto subscribe topics of at most `T` bytes
 #[derive(Clone)]
-pub struct ClientConfig {
+pub struct ClientConfig<const T: usize = MAX_TOPIC_SIZE> {
     pub client_id: String<128>,
     pub credentials: Option<ClientCredentials>,
-    pub auto_subscribes: Vec<AutoSubscribe, MAX_CONCURRENT_REQUESTS>
+    pub auto_subscribes: Vec<AutoSubscribe<T>, MAX_AUTO_SUBSCRIBES>,
+    pub retry_policy: RetryPolicy,
+
+    /// Maximum number of QoS 1 and QoS 2 publishes awaiting an acknowledgement.
+    /// Further publishes stay in the queue until acknowledgements arrive.
+    pub receive_maximum: u16
 }
 
-impl ClientConfig {
-    pub fn new(client_id: &str, credentials: Option<ClientCredentials>) -> Self {
-        let mut cid = String::new();
-        cid.push_str(client_id).unwrap();
-        Self {
-            client_id: cid,
+impl <const T: usize> ClientConfig<T> {
+    /// Fails with [`MqttError::TooLong`] if `client_id` is longer than 128 bytes
+    pub fn new(client_id: &str, credentials: Option<ClientCredentials>) -> Result<Self, MqttError> {
+        Ok(Self {
+            client_id: copy_str(client_id, "client id")?,
             credentials,
-            auto_subscribes: Vec::new()
-        }
+            auto_subscribes: Vec::new(),
+            retry_policy: RetryPolicy::default(),
+            receive_maximum: DEFAULT_RECEIVE_MAXIMUM
+        })
     }
 
-    pub fn new_with_auto_subscribes<'a>(client_id: &str, credentials: Option<ClientCredentials>, auto_subscribes: impl Iterator<Item = &'a str>, qos: QoS) -> Self {
-        let mut cid = String::new();
-        cid.push_str(client_id).unwrap();
-
-        let mut this = Self {
-            client_id: cid,
-            credentials,
-            auto_subscribes: Vec::new()
-        };
+    /// Fails with [`MqttError::TooLong`] if `client_id` or a topic exceed their capacity and with
+    /// [`MqttError::TooManyAutoSubscribes`] if there are more than [`MAX_AUTO_SUBSCRIBES`] topics
+    pub fn new_with_auto_subscribes<'a>(client_id: &str, credentials: Option<ClientCredentials>, auto_subscribes: impl Iterator<Item = &'a str>, qos: QoS) -> Result<Self, MqttError> {
+        let mut this = Self::new(client_id, credentials)?;
 
         for topic in auto_subscribes {
-            let mut topic_string = Topic::new();
-            topic_string.push_str(topic).unwrap();
-            let auto_subscribe = AutoSubscribe{
-                topic: topic_string,
-                qos
-            };
-            this.auto_subscribes.push(auto_subscribe).unwrap();
+            this.auto_subscribes.push(AutoSubscribe::new(topic, qos)?)
+                .map_err(|_| {
+                    warn!("more than {} auto subscribes", MAX_AUTO_SUBSCRIBES);
+                    MqttError::TooManyAutoSubscribes
+                })?;
         }
 
-
-        this
+        Ok(this)
     }
 }
 
+/// Default maximum number of publishes in flight, `u16::MAX` means effectively unlimited
+pub const DEFAULT_RECEIVE_MAXIMUM: u16 = u16::MAX;
+
+/// Default number of publish, subscribe and unsubscribe requests
+/// which can wait for their result at the same time
+pub const DEFAULT_MAX_PENDING_REQUESTS: usize = 8;
+
+/// Default capacity of the queue of outgoing publishes
+pub const DEFAULT_PUBLISH_QUEUE_SIZE: usize = 8;
+
+/// Default capacity of the queue of received QoS 1 and QoS 2 publishes
+/// which are not completely acknowledged yet
+pub const DEFAULT_RECEIVE_QUEUE_SIZE: usize = 8;
+
+/// Default capacity of the queue of subscribes and unsubscribes.
+/// The queue must be able to hold all auto subscribes.
+pub const DEFAULT_SUBSCRIBE_QUEUE_SIZE: usize = 4;
+
+/// Default capacity of the channels between [`client::MqttClient`] and [`io::MqttEventLoop`]
+pub const DEFAULT_CHANNEL_SIZE: usize = 4;
+
+/// Maximum number of auto subscribes in the [`ClientConfig`]
+pub const MAX_AUTO_SUBSCRIBES: usize = 4;
+
+/// Default maximum length of a topic
 pub const MAX_TOPIC_SIZE: usize = 64;
+
+/// Default maximum length of a payload
 pub const MQTT_PAYLOAD_MAX_SIZE: usize = 1024;
 
-pub type Topic = heapless::String<MAX_TOPIC_SIZE>;
+pub type Topic<const T: usize = MAX_TOPIC_SIZE> = heapless::String<T>;
 
+/// A publish with a topic of at most `T` bytes and a payload of at most `P` bytes
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct MqttPublish {
-    pub topic: Topic,
-    pub payload: Buffer<[u8; MQTT_PAYLOAD_MAX_SIZE]>,
+pub struct MqttPublish<const T: usize = MAX_TOPIC_SIZE, const P: usize = MQTT_PAYLOAD_MAX_SIZE> {
+    pub topic: Topic<T>,
+    pub payload: Buffer<[u8; P]>,
     pub qos: QoS,
     pub retain: bool,
 }
 
-impl MqttPublish {
+impl <const T: usize, const P: usize> MqttPublish<T, P> {
 
-    pub fn new(topic: &str, payload: &[u8], qos: QoS, retain: bool) -> Self {
+    /// Fails with [`MqttError::TooLong`] if `topic` is longer than `T` or `payload` longer than `P`
+    pub fn new(topic: &str, payload: &[u8], qos: QoS, retain: bool) -> Result<Self, MqttError> {
         let mut s = Self {
-            topic: Topic::new(),
+            topic: copy_str(topic, "topic")?,
             payload: new_stack_buffer(),
             qos, retain
         };
-        s.topic.push_str(topic).unwrap();
-        s.payload.push(payload).unwrap();
 
-        s
+        if s.payload.push(payload).is_err() {
+            warn!("payload is longer than {}: {}", P, payload.len());
+            return Err(MqttError::TooLong);
+        }
+
+        Ok(s)
     }
 
 }
 
-impl <'a> TryFrom<&Publish<'a>> for MqttPublish {
+impl <'a, const T: usize, const P: usize> TryFrom<&Publish<'a>> for MqttPublish<T, P> {
     type Error = MqttError;
 
     fn try_from(value: &Publish<'a>) -> Result<Self, Self::Error> {
         let mut topic = Topic::new();
         if let Err(_) = topic.push_str(value.topic_name) {
-            warn!("Topic of received message is longer than {}: {}", MAX_TOPIC_SIZE, value.topic_name.len());
+            warn!("Topic of received message is longer than {}: {}", T, value.topic_name.len());
             return Err(MqttError::ReceivedMessageTooLong);
         }
 
         let mut payload = new_stack_buffer();
         if let Err(_e) = payload.push(&value.payload) {
-            warn!("Payload of received message is longer than {}: {}", MQTT_PAYLOAD_MAX_SIZE, value.payload.len());
+            warn!("Payload of received message is longer than {}: {}", P, value.payload.len());
+            return Err(MqttError::ReceivedMessageTooLong);
         }
 
         let qos = value.qospid.qos();
@@ -218,7 +287,7 @@ impl <'a> TryFrom<&Publish<'a>> for MqttPublish {
     }
 }
 
-impl  MqttPublish {
+impl <const T: usize, const P: usize> MqttPublish<T, P> {
     pub(crate) fn create_publish<'a>(&'a self, pid: Pid, dup: bool) -> Publish<'a> {
         let qospid = match self.qos {
             QoS::AtMostOnce => QosPid::AtMostOnce,
@@ -246,23 +315,45 @@ pub enum MqttEvent {
 
     PublishResult(UniqueID, Result<(), MqttError>),
     SubscribeResult(UniqueID, Result<QoS, MqttError>),
-    UnsubscribeResult(UniqueID, Result<(), MqttError>)
+    UnsubscribeResult(UniqueID, Result<(), MqttError>),
+
+    /// Result of [`client::MqttClient::disconnect_graceful`] with the number of abandoned requests
+    DisconnectResult(UniqueID, Result<usize, MqttError>)
+}
+
+impl MqttEvent {
+    /// Returns the id of the request this event is the result of
+    pub fn request_id(&self) -> Option<UniqueID> {
+        match self {
+            MqttEvent::PublishResult(id, _) | 
+                MqttEvent::SubscribeResult(id, _) | 
+                MqttEvent::UnsubscribeResult(id, _) |
+                MqttEvent::DisconnectResult(id, _) => Some(*id),
+            MqttEvent::Connected | MqttEvent::InitialSubscribesDone => None,
+        }
+    }
 }
 
 
 
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-enum MqttRequest {
+enum MqttRequest<const T: usize, const P: usize> {
+
+    Publish(MqttPublish<T, P>, UniqueID),
 
-    Publish(MqttPublish, UniqueID),
+    /// Publish without payload, the waiting request streams the payload of the given length
+    PublishStream(MqttPublish<T, P>, usize, UniqueID),
 
-    Subscribe(Topic, UniqueID),
+    Subscribe(Topic<T>, UniqueID),
 
-    Unsubscribe(Topic, UniqueID),
+    Unsubscribe(Topic<T>, UniqueID),
 
     Disconnect,
 
+    /// Disconnect after the requests in progress are done or the timeout expired
+    DisconnectGraceful(time::Duration, UniqueID),
+
 }
 
 
@@ -8,15 +8,16 @@ use embassy_sync::blocking_mutex;
 use embassy_sync::blocking_mutex::raw::RawMutex;
 use embassy_sync::signal::Signal;
 use heapless::Vec;
-use mqttrs::{encode_slice, Connack, Connect, Error, Packet, Protocol};
+use mqttrs::{encode_slice, Connack, Connect, Error, Packet, Pid, Protocol};
 use pid::PidSource;
 use ping::PingState;
 use publish::PublishQueue;
 use receives::ReceivedPublishQueue;
 use sub::SubQueue;
 
-use crate::io::AsyncSender;
-use crate::{time, ClientConfig, MqttError, MqttEvent, MqttPublish};
+use crate::completion::WaitingRequests;
+use crate::io::{AsyncSender, PublishHandler};
+use crate::{time, ClientConfig, MqttError, MqttEvent, MAX_AUTO_SUBSCRIBES};
 
 pub(crate) const KEEP_ALIVE: usize = 60;
 
@@ -28,6 +29,7 @@ pub(crate) mod receives;
 pub(crate) mod publish;
 pub(crate) mod sub;
 pub(crate) mod pid;
+pub(crate) mod retry;
 
 #[derive(Debug, Clone, PartialEq)]
 pub(crate) enum ConnectionState {
@@ -43,37 +45,48 @@ pub(crate) enum ConnectionState {
 
 }
 
-pub(crate) struct State<M: RawMutex> {
+/// `P`: capacity of the outgoing publish queue
+/// `I`: capacity of the queue for received QoS 1 / 2 publishes
+/// `S`: capacity of the subscribe / unsubscribe queue
+/// `T`: maximum topic length
+/// `PL`: maximum payload length
+pub(crate) struct State<M: RawMutex, const P: usize, const I: usize, const S: usize, const T: usize, const PL: usize> {
 
     connection: blocking_mutex::Mutex<M, RefCell<ConnectionState>>,
-    config: ClientConfig,
+    config: ClientConfig<T>,
     ping: blocking_mutex::Mutex<M, RefCell<PingState>>,
 
-    pub(crate) publishes: PublishQueue,
-    pub(crate) received_publishes: ReceivedPublishQueue,
-    pub(crate) subscribes: SubQueue,
+    pub(crate) publishes: PublishQueue<P, T, PL>,
+    pub(crate) received_publishes: ReceivedPublishQueue<I>,
+    pub(crate) subscribes: SubQueue<S, T>,
 
     // Signal is sent, when a request is added
     // TODO update to emassy_sync::watch::Watch is update is there
     pub(crate) on_requst_added: Signal<M, usize>,
 
+    /// Signal is sent after the queues were processed
+    pub(crate) on_queues_processed: Signal<M, ()>,
+
     pub(crate) pid_source: PidSource
 
 }
 
-impl <M: RawMutex> State<M> {
+impl <M: RawMutex, const P: usize, const I: usize, const S: usize, const T: usize, const PL: usize> State<M, P, I, S, T, PL> {
 
-    pub fn new(config: ClientConfig) -> Self {
+    pub fn new(config: ClientConfig<T>) -> Self {
+        let retry_policy = config.retry_policy;
+        let receive_maximum = config.receive_maximum;
         Self {
             connection: blocking_mutex::Mutex::new(RefCell::new(ConnectionState::InitialState)),
             config,
             ping: blocking_mutex::Mutex::new(RefCell::new(PingState::PingSuccess(time::now()))),
 
-            publishes: PublishQueue::new(),
+            publishes: PublishQueue::new(retry_policy, receive_maximum),
             received_publishes: ReceivedPublishQueue::new(),
-            subscribes: SubQueue::new(),
+            subscribes: SubQueue::new(retry_policy),
 
             on_requst_added: Signal::new(),
+            on_queues_processed: Signal::new(),
 
             pid_source: PidSource::new()
         }
@@ -81,6 +94,21 @@ impl <M: RawMutex> State<M> {
 
     pub fn reset(&self) {
         self.set_connection_state(ConnectionState::InitialState);
+        self.received_publishes.on_reconnect();
+        self.publishes.on_reconnect();
+        self.subscribes.on_reconnect();
+    }
+
+    /// Returns true if no publish, subscribe or acknowledgement of a received publish is in progress
+    pub(crate) fn is_drained(&self) -> bool {
+        self.publishes.is_empty() && self.subscribes.is_empty() && self.received_publishes.is_empty()
+    }
+
+    /// Generates a pid which is not used by any publish, subscribe or unsubscribe in flight
+    pub(crate) fn next_pid(&self) -> Pid {
+        self.pid_source.next_free_pid(|pid| {
+            self.publishes.contains_pid(pid) || self.subscribes.contains_pid(pid)
+        })
     }
 
     fn set_connection_state(&self, new_state: ConnectionState) {
@@ -151,10 +179,20 @@ impl <M: RawMutex> State<M> {
                 self.set_connection_state(ConnectionState::Connected);
                 info!("connction to broker established");
 
+                if ! connack.session_present {
+                    self.received_publishes.on_session_lost();
+                }
+
                 // Add autosubscribe requests
+                // The pids are generated before, because the subscribe queue is locked while adding
+                let mut pids = self.config.auto_subscribes.iter()
+                    .map(|_| self.next_pid())
+                    .collect::<Vec<Pid, MAX_AUTO_SUBSCRIBES>>()
+                    .into_iter();
+
                 self.subscribes.add_auto_subscribes(
                     &self.config.auto_subscribes,
-                    || self.pid_source.next_pid()
+                    || pids.next().unwrap()
                 );
 
                 self.on_requst_added.signal(5);
@@ -181,7 +219,7 @@ impl <M: RawMutex> State<M> {
         });
     }
 
-    pub(crate) fn send_packets(&self, send_buffer: &mut impl BufferWriter, control_sender: & impl AsyncSender<MqttEvent>) -> Result<(), MqttError> {
+    pub(crate) fn send_packets(&self, send_buffer: &mut impl BufferWriter, control_sender: & impl AsyncSender<MqttEvent>, requests: &impl WaitingRequests) -> Result<(), MqttError> {
 
         let state = self.connection.lock(|inner| inner.borrow().clone());
 
@@ -192,7 +230,7 @@ impl <M: RawMutex> State<M> {
             ConnectionState::ConnectSent => Ok(()),
 
             // Send ping, subscribes, publishes, ...
-            ConnectionState::Connected => self.send_packets_connected(send_buffer, control_sender),
+            ConnectionState::Connected => self.send_packets_connected(send_buffer, control_sender, requests),
 
             ConnectionState::Failed(mqtt_error) => Err(mqtt_error.clone()),
         }
@@ -214,7 +252,7 @@ impl <M: RawMutex> State<M> {
         })
     }
 
-    fn send_packets_connected(&self, send_buffer: &mut impl BufferWriter, control_sender: &impl AsyncSender<MqttEvent>) -> Result<(), MqttError> {
+    fn send_packets_connected(&self, send_buffer: &mut impl BufferWriter, control_sender: &impl AsyncSender<MqttEvent>, requests: &impl WaitingRequests) -> Result<(), MqttError> {
 
         let is_critical = self.ping.lock(|inner|{
             inner.borrow().is_critical_delay()
@@ -230,16 +268,17 @@ impl <M: RawMutex> State<M> {
         self.received_publishes.process(send_buffer)?;
 
         // Subscribe & unsubscribe
-        self.subscribes.process(send_buffer)?;
+        self.subscribes.process(send_buffer, control_sender)?;
 
         // Publish and republish packets
-        self.publishes.process(send_buffer, control_sender)?;
+        self.publishes.process(send_buffer, control_sender, requests)?;
 
+        self.on_queues_processed.signal(());
         Ok(())
     }
 
     /// Processes incoming packets
-    pub(crate) async fn process_packet(&self, p: &Packet<'_>, send_buffer: &mut impl BufferWriter, reveived_publishes: &impl AsyncSender<MqttPublish>) -> Result<Vec<MqttEvent, 16>, MqttError> {
+    pub(crate) async fn process_packet(&self, p: &Packet<'_>, send_buffer: &mut impl BufferWriter, publish_handler: &impl PublishHandler) -> Result<Vec<MqttEvent, 16>, MqttError> {
 
         match p {
             
@@ -249,9 +288,13 @@ impl <M: RawMutex> State<M> {
             },
             
             Packet::Publish(publish) => {
-                let publish = self.received_publishes.process_publish(publish).await;
-                if let Some(publish) = publish {
-                    reveived_publishes.send(publish).await;
+                // Rejected before it is registered, so it is not acknowledged
+                if ! publish_handler.accepts(publish) {
+                    return Ok(Vec::new());
+                }
+
+                if self.received_publishes.process_publish(publish) {
+                    publish_handler.on_publish(publish).await;
                 }
 
                 Ok(Vec::new())
@@ -325,9 +368,10 @@ mod tests {
     use buffer::{new_stack_buffer, Buffer, BufferReader, ReadWrite};
     use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
     use heapless::{String, Vec};
-    use mqttrs::{decode_slice_with_len, Connack, ConnectReturnCode, Packet, PacketType, QoS};
+    use mqttrs::{decode_slice_with_len, Connack, ConnectReturnCode, Packet, PacketType, Publish, QoS, QosPid};
 
-    use crate::{io::AsyncSender, state::{ConnectionState, State, KEEP_ALIVE}, time, ClientConfig, MqttError, MqttEvent};
+    use crate::{completion::CompletionTable, io::{AsyncSender, PublishHandler}, state::{ConnectionState, State, KEEP_ALIVE}, time, ClientConfig, MqttError, MqttEvent};
+    use crate::{DEFAULT_PUBLISH_QUEUE_SIZE, DEFAULT_RECEIVE_QUEUE_SIZE, DEFAULT_SUBSCRIBE_QUEUE_SIZE, MAX_TOPIC_SIZE, MQTT_PAYLOAD_MAX_SIZE};
 
     use super::ping::PingState;
 
@@ -343,10 +387,17 @@ mod tests {
         }
     }
 
+    impl PublishHandler for PanicSender {
+        async fn on_publish(&self, _publish: &Publish<'_>) {
+            panic!("called on_publish() on PanicSender");
+        }
+    }
+
     struct Test {
-        state: State<CriticalSectionRawMutex>,
+        state: State<CriticalSectionRawMutex, DEFAULT_PUBLISH_QUEUE_SIZE, DEFAULT_RECEIVE_QUEUE_SIZE, DEFAULT_SUBSCRIBE_QUEUE_SIZE, MAX_TOPIC_SIZE, MQTT_PAYLOAD_MAX_SIZE>,
         send_buffer: Buffer<[u8; 1024]>,
-        control_ch: Channel<CriticalSectionRawMutex, MqttEvent, 16>
+        control_ch: Channel<CriticalSectionRawMutex, MqttEvent, 16>,
+        completions: CompletionTable<CriticalSectionRawMutex, 4>
     }
 
     impl Test {
@@ -354,7 +405,8 @@ mod tests {
             Self {
                 state: State::new(config),
                 send_buffer: new_stack_buffer(),
-                control_ch: Channel::new()
+                control_ch: Channel::new(),
+                completions: CompletionTable::new()
             }
         }
 
@@ -388,13 +440,15 @@ mod tests {
         let mut config = ClientConfig{
             client_id: String::new(),
             credentials: None,
-            auto_subscribes: Vec::new()
+            auto_subscribes: Vec::new(),
+            retry_policy: Default::default(),
+            receive_maximum: crate::DEFAULT_RECEIVE_MAXIMUM
         };
 
         config.client_id.push_str("1234567890").unwrap();
 
         let mut test = Test::new(config);
-        test.state.send_packets(&mut test.send_buffer.create_writer(), &test.control_ch).unwrap();
+        test.state.send_packets(&mut test.send_buffer.create_writer(), &test.control_ch, &test.completions).unwrap();
         assert_eq!(test.state.get_connection_state(), ConnectionState::ConnectSent);
 
         let ping_required = test.state.on_ping_required();
@@ -431,7 +485,9 @@ mod tests {
         let mut config = ClientConfig{
             client_id: String::new(),
             credentials: None,
-            auto_subscribes: Vec::new()
+            auto_subscribes: Vec::new(),
+            retry_policy: Default::default(),
+            receive_maximum: crate::DEFAULT_RECEIVE_MAXIMUM
         };
 
         config.client_id.push_str("1234567890").unwrap();
@@ -440,7 +496,7 @@ mod tests {
 
         assert_eq!(test.state.get_connection_state(), ConnectionState::InitialState);
 
-        test.state.send_packets(&mut test.send_buffer.create_writer(), &test.control_ch).unwrap();
+        test.state.send_packets(&mut test.send_buffer.create_writer(), &test.control_ch, &test.completions).unwrap();
 
         assert_eq!(test.state.get_connection_state(), ConnectionState::ConnectSent);
 
@@ -466,6 +522,38 @@ mod tests {
         assert_eq!(test.state.get_connection_state(), ConnectionState::Connected);
     }
 
+    #[tokio::test]
+    async fn test_reconnect_session_lost() {
+        let config = ClientConfig::new("1234567890", None).unwrap();
+        let mut test = Test::new(config);
+
+        let pid = mqttrs::Pid::try_from(1).unwrap();
+        let publish = Publish {
+            dup: false,
+            qospid: QosPid::ExactlyOnce(pid),
+            retain: false,
+            payload: b"test",
+            topic_name: "test-topic"
+        };
+        assert!(test.state.received_publishes.process_publish(&publish));
+
+        // The broker kept the session: the pid is still waiting for the pubrel
+        test.state.reset();
+        test.process_packet(&Packet::Connack(Connack {
+            session_present: true,
+            code: ConnectReturnCode::Accepted
+        })).await.unwrap();
+        assert!(! test.state.received_publishes.process_publish(&publish));
+
+        // The broker lost the session and may reuse the pid for a new publish
+        test.state.reset();
+        test.process_packet(&Packet::Connack(Connack {
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await.unwrap();
+        assert!(test.state.received_publishes.process_publish(&publish));
+    }
+
     #[tokio::test]
     async fn test_ping() {
         let start_time = Instant::now();
@@ -474,19 +562,21 @@ mod tests {
         let config = ClientConfig{
             client_id: String::new(),
             credentials: None,
-            auto_subscribes: Vec::new()
+            auto_subscribes: Vec::new(),
+            retry_policy: Default::default(),
+            receive_maximum: crate::DEFAULT_RECEIVE_MAXIMUM
         };
 
         let mut test = Test::new(config);
         test.state.set_connection_state(ConnectionState::Connected);
 
-        test.state.send_packets(&mut test.send_buffer.create_writer(), &test.control_ch).unwrap();
+        test.state.send_packets(&mut test.send_buffer.create_writer(), &test.control_ch, &test.completions).unwrap();
         test.state.send_ping(&mut test.send_buffer.create_writer()).unwrap();
         test.expect_no_packet();
 
         time::test_time::advance_time(Duration::from_secs(40));
 
-        test.state.send_packets(&mut test.send_buffer.create_writer(), &test.control_ch).unwrap();
+        test.state.send_packets(&mut test.send_buffer.create_writer(), &test.control_ch, &test.completions).unwrap();
         test.state.send_ping(&mut test.send_buffer.create_writer()).unwrap();
         test.expect_packet(|p| {
             if Packet::Pingreq != *p {
@@ -528,11 +618,11 @@ mod tests {
             None, 
             [ "test1", "test2" ].into_iter(), 
             QoS::AtLeastOnce
-        );
+        ).unwrap();
 
         let mut test = Test::new(config);
 
-        test.state.send_packets(&mut test.send_buffer.create_writer(), &test.control_ch).unwrap();
+        test.state.send_packets(&mut test.send_buffer.create_writer(), &test.control_ch, &test.completions).unwrap();
         test.expect_packet(|p|{
             assert_eq!(p.get_type(), PacketType::Connect, "expected connect packet");
         });
@@ -542,7 +632,7 @@ mod tests {
             code: ConnectReturnCode::Accepted 
         })).await.unwrap();
 
-        test.state.send_packets(&mut test.send_buffer.create_writer(), &test.control_ch).unwrap();
+        test.state.send_packets(&mut test.send_buffer.create_writer(), &test.control_ch, &test.completions).unwrap();
         test.expect_packet(|p|{
             if let Packet::Subscribe(s) = p {
                 assert_eq!(1, s.topics.len());
@@ -554,7 +644,7 @@ mod tests {
             }
         });
 
-        test.state.send_packets(&mut test.send_buffer.create_writer(), &test.control_ch).unwrap();
+        test.state.send_packets(&mut test.send_buffer.create_writer(), &test.control_ch, &test.completions).unwrap();
         test.expect_packet(|p|{
             if let Packet::Subscribe(s) = p {
                 assert_eq!(1, s.topics.len());
@@ -566,7 +656,7 @@ mod tests {
             }
         });
 
-        test.state.send_packets(&mut test.send_buffer.create_writer(), &test.control_ch).unwrap();
+        test.state.send_packets(&mut test.send_buffer.create_writer(), &test.control_ch, &test.completions).unwrap();
         test.expect_no_packet();
     }
 
@@ -18,13 +18,27 @@ impl PidSource {
 
     /// Genrates the next unique pid for the packet
     ///
+    #[cfg(test)]
     pub(crate) fn next_pid(&self) -> Pid {
+        self.next_free_pid(|_| false)
+    }
+
+    /// Generates the next pid which is not in use.
+    /// After wrapping around, pids of unacknowledged requests are skipped.
+    pub(crate) fn next_free_pid(&self, in_use: impl Fn(Pid) -> bool) -> Pid {
         self.counter.lock(|pid|{
             let mut pid = pid.borrow_mut();
 
-            let result = pid.clone();
-            *pid = result + 1;
-            result
+            for _ in 0..u16::MAX {
+                let result = *pid;
+                *pid = result + 1;
+
+                if ! in_use(result) {
+                    return result;
+                }
+            }
+
+            panic!("Internal logic error: no free packet identifier left");
         })
     }
 }
@@ -92,4 +106,34 @@ mod tests {
         }
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_wrap_around() {
+        let pid_source = PidSource::new();
+        let first = pid_source.next_pid();
+
+        for _ in 1..u16::MAX {
+            pid_source.next_pid();
+        }
+
+        // Pid 0 is not allowed, so the pid wraps around to the first pid
+        assert_eq!(pid_source.next_pid(), first);
+    }
+
+    #[test]
+    fn test_wrap_around_skips_pids_in_use() {
+        let pid_source = PidSource::new();
+        let in_use = [Pid::new(), Pid::new() + 1, Pid::new() + 3];
+
+        let first = pid_source.next_free_pid(|pid| in_use.contains(&pid));
+        assert_eq!(first, Pid::new() + 2);
+
+        for _ in 0..(u16::MAX - 4) {
+            pid_source.next_free_pid(|pid| in_use.contains(&pid));
+        }
+
+        // After wrapping around only unused pids are returned
+        assert_eq!(pid_source.next_free_pid(|pid| in_use.contains(&pid)), Pid::new() + 2);
+        assert_eq!(pid_source.next_free_pid(|pid| in_use.contains(&pid)), Pid::new() + 4);
+    }
+}
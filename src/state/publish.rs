@@ -1,13 +1,14 @@
+use core::cell::Cell;
 
 use buffer::BufferWriter;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use crate::time::{Duration, Instant};
-use mqttrs::{encode_slice, Error, Packet, Pid};
+use crate::time::Instant;
+use mqttrs::{encode_slice, Error, Packet, Pid, Publish, QoS};
 use queue_vec::{split::WithQueuedVecInner, QueuedVec};
 
-use crate::{io::AsyncSender, time, MqttError, MqttEvent, MqttPublish, UniqueID};
+use crate::{completion::WaitingRequests, io::AsyncSender, time, MqttError, MqttEvent, MqttPublish, UniqueID};
 
-const MAX_CONCURRENT_PUBLISHES: usize = 8;
+use super::retry::RetryPolicy;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -30,24 +31,32 @@ enum RequestState {
     AwaitPubcomp(Instant),
 
     /// The publish is done
-    Done
-}
+    Done,
+
+    /// The broker did not acknowledge the publish within the retry policy
+    TimedOut,
 
-const REPUBLISH_DURATION: Duration = Duration::from_secs(5);
+    /// The request streaming the payload is gone before the publish could be sent
+    Abandoned
+}
 
 impl RequestState {
 
 
-    fn should_publish(&self, now: Instant) -> bool {
+    fn should_publish(&self, now: Instant, policy: &RetryPolicy, attempts: u8) -> bool {
         match self {
             RequestState::Initial => true,
             RequestState::AwaitPuback(instant) | 
                 RequestState::AwaitPubrec(instant) | 
-                RequestState::AwaitPubcomp(instant) => (now - *instant) > REPUBLISH_DURATION,
-            RequestState::Done => false,
+                RequestState::AwaitPubcomp(instant) => policy.should_retransmit(*instant, attempts, now),
+            RequestState::Done | RequestState::TimedOut | RequestState::Abandoned => false,
         }
     }
 
+    fn is_awaiting(&self) -> bool {
+        self.is_await_puback() || self.is_await_pubrec() || self.is_await_pubcomp()
+    }
+
     fn is_await_puback(&self) -> bool {
         if let Self::AwaitPuback(_) = self {
             true
@@ -76,42 +85,74 @@ impl RequestState {
 #[cfg(test)]
 mod request_state_test {
 
-    use crate::{state::publish::RequestState, time};
+    use crate::{state::{publish::RequestState, retry::RetryPolicy}, time};
+    use crate::time::Duration;
 
     #[test]
     fn test_should_publish() {
         let now = time::now();
+        let policy = RetryPolicy::default();
         
-        assert_eq!(RequestState::Initial.should_publish(now.clone()), true);
+        assert_eq!(RequestState::Initial.should_publish(now.clone(), &policy, 0), true);
+        assert!(! RequestState::Done.should_publish(now, &policy, 0));
+        assert!(! RequestState::TimedOut.should_publish(now, &policy, 0));
+
+        let sent = now - policy.interval - Duration::from_secs(1);
+        assert!(! RequestState::AwaitPuback(now).should_publish(now, &policy, 0));
+        assert!(RequestState::AwaitPuback(sent).should_publish(now, &policy, 0));
 
-        // TODO add more tests
+        let policy = RetryPolicy::on_reconnect(None);
+        assert!(! RequestState::AwaitPubcomp(sent).should_publish(now, &policy, 0));
     }
 
 }
 
-struct PublishRequest {
-    request: MqttPublish,
+/// Where the payload of a publish comes from
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum PayloadSource {
+    /// The payload is part of the [`MqttPublish`]
+    Owned,
+
+    /// The waiting request streams the payload of the given length through the send buffer
+    Streamed(usize)
+}
+
+struct PublishRequest<const T: usize, const PL: usize> {
+    request: MqttPublish<T, PL>,
     pid: Pid,
     state: RequestState,
-    external_id: UniqueID
+    external_id: UniqueID,
+
+    /// Number of retransmissions in the current state
+    attempts: u8,
+
+    /// The connection was reestablished: the last packet must be sent again
+    retransmit_pending: bool,
+
+    payload: PayloadSource
 }
 
-impl PublishRequest {
-    fn new(request: MqttPublish, pid: Pid, external_id: UniqueID) -> Self {
+impl <const T: usize, const PL: usize> PublishRequest<T, PL> {
+    fn new(request: MqttPublish<T, PL>, pid: Pid, external_id: UniqueID, payload: PayloadSource) -> Self {
 
         Self {
             request,
             pid,
             state: RequestState::Initial,
-            external_id
+            external_id,
+            attempts: 0,
+            retransmit_pending: false,
+            payload
         }
     }
 
+    fn should_send(&self, now: Instant, policy: &RetryPolicy) -> bool {
+        self.state.should_publish(now, policy, self.attempts) 
+            || (self.retransmit_pending && self.state.is_awaiting())
+    }
+
     fn on_publish_success(&mut self) {
-       // TODO How should the state change if republishing a Packet in QoS 2 (ExactlyOnce)?
-       //   a. Go back to AwaitPubrec
-       //   b. Keep State
-       
        match self.state {
             RequestState::Initial => match self.request.qos {
                 mqttrs::QoS::AtMostOnce => {
@@ -124,39 +165,257 @@ impl PublishRequest {
                     self.state = RequestState::AwaitPubrec(time::now())
                 },
             },
+            // Republishing restarts waiting for the acknowledgement
+            RequestState::AwaitPuback(_) => {
+                self.state = RequestState::AwaitPuback(time::now());
+                self.on_retransmit();
+            },
+            RequestState::AwaitPubrec(_) => {
+                self.state = RequestState::AwaitPubrec(time::now());
+                self.on_retransmit();
+            },
             _ => {}
         }
     }
+
+    fn on_retransmit(&mut self) {
+        self.attempts = self.attempts.saturating_add(1);
+        self.retransmit_pending = false;
+    }
 }
 
-pub(crate) struct PublishQueue {
-    publishes: QueuedVec<CriticalSectionRawMutex, PublishRequest, MAX_CONCURRENT_PUBLISHES>,
+/// Encodes fixed and variable header of the `publish` for a payload of `payload_len` bytes.
+/// The payload of `publish` is ignored.
+/// Returns the length of the header or None if `buf` is too small.
+fn encode_publish_header(publish: &Publish<'_>, payload_len: usize, buf: &mut [u8]) -> Result<Option<usize>, MqttError> {
+    let pid = publish.qospid.pid();
+    let topic = publish.topic_name.as_bytes();
+
+    let topic_len = u16::try_from(topic.len())
+        .map_err(|_| MqttError::CodecError)?;
+
+    let mut remaining_len = 2 + topic.len() + payload_len;
+    if pid.is_some() {
+        remaining_len += 2;
+    }
+    if remaining_len > MAX_REMAINING_LENGTH {
+        error!("publish too large to encode: remaining length {}", remaining_len);
+        return Err(MqttError::CodecError);
+    }
+
+    let mut header = [0u8; 5];
+    header[0] = 0b0011_0000
+        | ((publish.dup as u8) << 3)
+        | ((publish.qospid.qos() as u8) << 1)
+        | publish.retain as u8;
+
+    // Variable length encoding of the remaining length
+    let mut n = 1;
+    loop {
+        let mut byte = (remaining_len % 128) as u8;
+        remaining_len /= 128;
+        if remaining_len > 0 {
+            byte |= 0x80;
+        }
+        header[n] = byte;
+        n += 1;
+        if remaining_len == 0 {
+            break;
+        }
+    }
+
+    let header_len = n + 2 + topic.len() + if pid.is_some() { 2 } else { 0 };
+    if buf.len() < header_len {
+        return Ok(None);
+    }
+
+    buf[..n].copy_from_slice(&header[..n]);
+    buf[n..n + 2].copy_from_slice(&topic_len.to_be_bytes());
+    buf[n + 2..n + 2 + topic.len()].copy_from_slice(topic);
+    if let Some(pid) = pid {
+        buf[header_len - 2..header_len].copy_from_slice(&pid.get().to_be_bytes());
+    }
+
+    Ok(Some(header_len))
+}
+
+/// Largest remaining length of a MQTT packet
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+pub(crate) struct PublishQueue<const N: usize, const T: usize, const PL: usize> {
+    publishes: QueuedVec<CriticalSectionRawMutex, PublishRequest<T, PL>, N>,
+    retry_policy: RetryPolicy,
+
+    /// Maximum number of unacknowledged QoS 1 and QoS 2 publishes
+    receive_maximum: usize,
+
+    /// Request id, payload length and header length of the streamed publish whose header was written last
+    stream: Cell<Option<(UniqueID, usize, usize)>>
 }
 
-impl PublishQueue {
+impl <const N: usize, const T: usize, const PL: usize> PublishQueue<N, T, PL> {
 
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(retry_policy: RetryPolicy, receive_maximum: u16) -> Self {
         Self {
             publishes: QueuedVec::new(),
+            retry_policy,
+            receive_maximum: receive_maximum.max(1) as usize,
+            stream: Cell::new(None)
         }
     }
 
     /// Adds a `MqttPublish` to the publish queue
-    pub(crate) async fn push_publish(&self, publish: MqttPublish, id: UniqueID, pid: Pid) {
-        let request = PublishRequest::new(publish, pid, id);
+    pub(crate) async fn push_publish(&self, publish: MqttPublish<T, PL>, id: UniqueID, pid: Pid) {
+        let request = PublishRequest::new(publish, pid, id, PayloadSource::Owned);
+        self.publishes.push(request).await;
+    }
+
+    /// Adds a `MqttPublish` whose payload of `len` bytes is streamed by the request `id` to the publish queue
+    pub(crate) async fn push_publish_streamed(&self, publish: MqttPublish<T, PL>, len: usize, id: UniqueID, pid: Pid) {
+        let request = PublishRequest::new(publish, pid, id, PayloadSource::Streamed(len));
         self.publishes.push(request).await;
     }
 
+    /// Returns request id, payload length and header length of the streamed publish whose header was written last.
+    /// Its payload must follow the header in the send buffer.
+    pub(crate) fn take_stream(&self) -> Option<(UniqueID, usize, usize)> {
+        self.stream.take()
+    }
+
+    /// Called after the streamed payload of the request `id` was written to the send buffer.
+    /// The result of a QoS 0 publish is sent right away.
+    pub(crate) fn on_stream_done(&self, id: UniqueID, control_sender: &impl AsyncSender<MqttEvent>) {
+        self.publishes.operate(|publishes| {
+            if let Some(publish) = publishes.iter_mut().find(|el| el.external_id == id) {
+                publish.on_publish_success();
+            }
+        });
+
+        self.cleanup(control_sender);
+    }
+
+    /// Called after the request `id` stopped streaming and the streamed publish was taken back from the send buffer.
+    pub(crate) fn on_stream_aborted(&self, id: UniqueID, control_sender: &impl AsyncSender<MqttEvent>) {
+        self.publishes.operate(|publishes| {
+            if let Some(publish) = publishes.iter_mut().find(|el| el.external_id == id) {
+                Self::abandon_streamed(publish);
+            }
+        });
+
+        self.cleanup(control_sender);
+    }
+
+    /// Returns true if no publish is in progress
+    pub(crate) fn is_empty(&self) -> bool {
+        self.publishes.operate(|publishes| publishes.is_empty())
+    }
+
+    /// Removes all publishes and returns their results.
+    /// Publishes which are not done fail with [`MqttError::Abandoned`].
+    pub(crate) fn abandon(&self) -> heapless::Vec<MqttEvent, N> {
+        self.stream.set(None);
+        self.publishes.operate(|publishes| {
+            let results = publishes.iter()
+                .map(|el| {
+                    let result = match el.state {
+                        RequestState::Done => Ok(()),
+                        RequestState::TimedOut => Err(MqttError::Timeout),
+                        _ => Err(MqttError::Abandoned)
+                    };
+                    MqttEvent::PublishResult(el.external_id, result)
+                })
+                .collect();
+
+            publishes.clear();
+            results
+        })
+    }
+
+    /// Returns true if a publish with the `pid` is in the queue
+    pub(crate) fn contains_pid(&self, pid: Pid) -> bool {
+        self.publishes.operate(|publishes| {
+            publishes.iter().any(|el| el.pid == pid)
+        })
+    }
+
+    /// Removes the publish with the request `id` if it was not sent yet.
+    /// Returns true if the publish was removed.
+    pub(crate) fn withdraw(&self, id: UniqueID) -> bool {
+        self.publishes.operate(|publishes| {
+            let pos = publishes.iter()
+                .position(|el| el.external_id == id && el.state == RequestState::Initial);
+
+            match pos {
+                Some(pos) => {
+                    publishes.remove(pos);
+                    true
+                },
+                None => false,
+            }
+        })
+    }
+
+    /// Must be called when the connection to the broker is established again.
+    /// All unacknowledged publishes and pubrels are sent again.
+    pub(crate) fn on_reconnect(&self) {
+        self.publishes.operate(|publishes|{
+            for publish in publishes.iter_mut() {
+                if publish.state.is_awaiting() {
+                    publish.retransmit_pending = true;
+                }
+            }
+        })
+    }
+
     /// Publish and republish packets
-    pub(crate) fn process(&self, send_buffer: &mut impl BufferWriter, control_sender: &impl AsyncSender<MqttEvent>) -> Result<(), MqttError> {
+    pub(crate) fn process(&self, send_buffer: &mut impl BufferWriter, control_sender: &impl AsyncSender<MqttEvent>, requests: &impl WaitingRequests) -> Result<(), MqttError> {
         self.publishes.operate(|publishes|{
+            let now = time::now();
+
+            let mut in_flight = publishes.iter()
+                .filter(|el| el.state.is_awaiting())
+                .count();
+
+            // Once a new publish is held back, all following new publishes are held
+            // back as well to keep the order of delivery
+            let mut hold_back = false;
 
             for publish in publishes.iter_mut() {
-                // TODO answer quetsion:
-                //   Should the loop `break;` if a publish cannot be written to buffer 
-                //   beause of insufficient space?
-                if publish.state.should_publish(time::now()) {
-                    self.publish(publish, send_buffer)?;
+                // Nothing must be written between the header and the payload of a streamed publish
+                if self.stream.get().is_some() {
+                    break;
+                }
+
+                if ! publish.should_send(now, &self.retry_policy) {
+                    continue;
+                }
+
+                if publish.state == RequestState::Initial {
+                    if hold_back {
+                        continue;
+                    }
+
+                    let needs_ack = publish.request.qos != QoS::AtMostOnce;
+                    if needs_ack && in_flight >= self.receive_maximum {
+                        trace!("in-flight window full: hold back publish {}", publish.pid);
+                        hold_back = true;
+                        continue;
+                    }
+
+                    if ! self.publish(publish, send_buffer, requests)? {
+                        hold_back = true;
+                    } else if needs_ack {
+                        in_flight += 1;
+                    }
+                } else if publish.state.is_awaiting() && self.retry_policy.attempts_exhausted(publish.attempts) {
+                    warn!("publish {} not acknowledged after {} retransmissions", publish.pid, publish.attempts);
+                    publish.state = RequestState::TimedOut;
+                } else if publish.state.is_await_pubcomp() {
+                    if self.send_pubrel(publish, send_buffer)? {
+                        publish.on_retransmit();
+                    }
+                } else {
+                    self.publish(publish, send_buffer, requests)?;
                 }
             }
             Ok(())
@@ -169,21 +428,29 @@ impl PublishQueue {
     /// Remove done requests and inform the sender 
     fn cleanup(&self, control_sender: &impl AsyncSender<MqttEvent>) {
         self.publishes.retain(|el| {
-            if el.state == RequestState::Done {
-                match control_sender.try_send(MqttEvent::PublishResult(el.external_id, Ok(()))) {
-                    Ok(()) => false,
-                    Err(_) => true,
-                }
-            } else {
-                true
+            let result = match el.state {
+                RequestState::Done => Ok(()),
+                RequestState::TimedOut => Err(MqttError::Timeout),
+                RequestState::Abandoned => Err(MqttError::Abandoned),
+                _ => return true
+            };
+
+            match control_sender.try_send(MqttEvent::PublishResult(el.external_id, result)) {
+                Ok(()) => false,
+                Err(_) => true,
             }
         });
 
 
     }
 
-    fn publish(&self, publish: &mut PublishRequest, send_buffer: &mut impl BufferWriter) -> Result<bool, MqttError> {
+    fn publish(&self, publish: &mut PublishRequest<T, PL>, send_buffer: &mut impl BufferWriter, requests: &impl WaitingRequests) -> Result<bool, MqttError> {
         let dup = publish.state != RequestState::Initial;
+
+        if let PayloadSource::Streamed(len) = publish.payload {
+            return self.publish_streamed(publish, len, dup, send_buffer, requests);
+        }
+
         let packet = publish.request.create_publish(publish.pid, dup);
         let packet = Packet::Publish(packet);
         
@@ -206,6 +473,42 @@ impl PublishQueue {
         }
     }
 
+    /// Writes the header of the publish whose payload is streamed by the waiting request.
+    /// The payload is written later, see [`Self::take_stream`].
+    fn publish_streamed(&self, publish: &mut PublishRequest<T, PL>, len: usize, dup: bool, send_buffer: &mut impl BufferWriter, requests: &impl WaitingRequests) -> Result<bool, MqttError> {
+        if ! requests.is_waiting(publish.external_id) {
+            Self::abandon_streamed(publish);
+            return Ok(true);
+        }
+
+        let packet = publish.request.create_publish(publish.pid, dup);
+        match encode_publish_header(&packet, len, send_buffer)? {
+            Some(header_len) => {
+                send_buffer.commit(header_len).unwrap();
+                self.stream.set(Some((publish.external_id, len, header_len)));
+                debug!("header of streamed packet {} written to send buffer; payload len = {}", publish.pid, len);
+                Ok(true)
+            },
+            None => {
+                warn!("send buffer to full to publish: send_buffer_available = {}", send_buffer.len());
+                Ok(false)
+            },
+        }
+    }
+
+    /// Gives up sending the publish whose payload cannot be streamed anymore
+    fn abandon_streamed(publish: &mut PublishRequest<T, PL>) {
+        // A publish already on the wire keeps its pid until the broker acknowledges it,
+        // unless it must be sent again after a reconnect
+        if publish.state == RequestState::Initial || publish.retransmit_pending {
+            warn!("publish {} abandoned: the request streaming the payload is gone", publish.pid);
+            publish.state = RequestState::Abandoned;
+        } else {
+            debug!("publish {} not retransmitted: the request streaming the payload is gone", publish.pid);
+            publish.on_publish_success();
+        }
+    }
+
     pub(crate) fn process_puback(&self, puback_pid: &Pid) -> Option<MqttEvent> {
         self.publishes.operate(|publishes|{
 
@@ -230,7 +533,8 @@ impl PublishQueue {
         })
     }
 
-    fn send_pubrel(&self, request: &mut PublishRequest, send_buffer: &mut impl BufferWriter) -> Result<(), MqttError> {
+    /// Writes a pubrel to the send buffer and returns if it was written
+    fn send_pubrel(&self, request: &mut PublishRequest<T, PL>, send_buffer: &mut impl BufferWriter) -> Result<bool, MqttError> {
 
         let packet = Packet::Pubrel(request.pid.clone());
 
@@ -238,11 +542,11 @@ impl PublishQueue {
             Ok(n) => {
                 send_buffer.commit(n).unwrap();
                 request.state = RequestState::AwaitPubcomp(time::now());
-                Ok(())
+                Ok(true)
             },
             Err(mqttrs::Error::WriteZero) => {
                 warn!("cannot encode pubrel to buffer: not enaugh space");
-                Ok(())
+                Ok(false)
             },
             Err(e) => {
                 error!("error encoding pubrel packet: {}", e);
@@ -259,6 +563,9 @@ impl PublishQueue {
 
             if let Some(request) = op {
                 if request.state.is_await_pubrec() || request.state.is_await_pubcomp() {
+                    // The pubrel phase starts: count retransmissions again
+                    request.attempts = 0;
+                    request.retransmit_pending = false;
                     self.send_pubrel(request, send_buffer)?;
                     debug!("pubrec processed for packet {}", request.pid);
                 } else {
@@ -304,31 +611,43 @@ mod tests {
 
     use buffer::{new_stack_buffer, Buffer, BufferReader, ReadWrite};
     use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
-    use mqttrs::{decode_slice_with_len, Packet, Pid, Publish, QoS};
+    use mqttrs::{decode_slice_with_len, encode_slice, Packet, Pid, Publish, QoS, QosPid};
 
-    use crate::{time, MqttEvent, MqttPublish, UniqueID};
+    use crate::completion::CompletionTable;
+    use crate::{time, MqttError, MqttEvent, MqttPublish, UniqueID, DEFAULT_PUBLISH_QUEUE_SIZE, DEFAULT_RECEIVE_MAXIMUM, MAX_TOPIC_SIZE, MQTT_PAYLOAD_MAX_SIZE};
     use crate::time::Duration;
 
-    use super::PublishQueue;
+    use super::{encode_publish_header, PublishQueue};
+    use super::super::retry::RetryPolicy;
 
     struct Test<const N: usize> {
         send_buffer: Buffer<[u8; N]>,
         control_ch: Channel<CriticalSectionRawMutex, MqttEvent, 16>,
-        queue: PublishQueue
+        completions: CompletionTable<CriticalSectionRawMutex, 4>,
+        queue: PublishQueue<DEFAULT_PUBLISH_QUEUE_SIZE, MAX_TOPIC_SIZE, MQTT_PAYLOAD_MAX_SIZE>
     }
 
     impl <const N: usize> Test<N> {
         fn new() -> Self {
+            Self::with_policy(RetryPolicy::default())
+        }
+
+        fn with_policy(policy: RetryPolicy) -> Self {
+            Self::with_config(policy, DEFAULT_RECEIVE_MAXIMUM)
+        }
+
+        fn with_config(policy: RetryPolicy, receive_maximum: u16) -> Self {
             Self {
                 send_buffer: new_stack_buffer(),
                 control_ch: Channel::new(),
-                queue: PublishQueue::new()
+                completions: CompletionTable::new(),
+                queue: PublishQueue::new(policy, receive_maximum)
             }
         }
 
         async fn process(&mut self) {
             let mut writer = self.send_buffer.create_writer();
-            self.queue.process(&mut writer, &self.control_ch).unwrap();
+            self.queue.process(&mut writer, &self.control_ch, &self.completions).unwrap();
         }
 
         async fn send_publish(&self, topic: &str, payload: &str, qos: QoS, retain: bool) -> UniqueID {
@@ -336,7 +655,7 @@ mod tests {
             payload_buffer.push(payload.as_bytes()).unwrap();
             
             let publish = MqttPublish::new(
-                topic, &[1, 2, 3, 4, 5], qos, retain);
+                topic, &[1, 2, 3, 4, 5], qos, retain).unwrap();
 
             let id = UniqueID(43234);
             let pid = 34u16.try_into().unwrap();
@@ -491,5 +810,233 @@ mod tests {
         }
 
     }
-}
 
+    #[tokio::test]
+    async fn test_publish_timeout() {
+        time::test_time::set_static_now();
+
+        let policy = RetryPolicy {
+            max_attempts: Some(1),
+            ..Default::default()
+        };
+        let mut test = Test::<1024>::with_policy(policy);
+
+        let uid = test.send_publish("hello/world", "hello world", QoS::AtLeastOnce, false).await;
+
+        // Initial publish and one retransmission
+        for dup in [false, true] {
+            test.process().await;
+            test.read_publish(|p| assert_eq!(p.dup, dup));
+            time::test_time::advance_time(Duration::from_secs(6));
+        }
+
+        test.process().await;
+        assert_eq!(test.send_buffer.remaining_len(), 0);
+        assert_eq!(test.control_ch.try_receive().unwrap(), MqttEvent::PublishResult(uid, Err(MqttError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_publish_on_reconnect() {
+        time::test_time::set_static_now();
+
+        let mut test = Test::<1024>::with_policy(RetryPolicy::on_reconnect(None));
+        test.send_publish("hello/world", "hello world", QoS::AtLeastOnce, false).await;
+
+        test.process().await;
+        test.read_publish(|p| assert!(! p.dup));
+
+        // No retransmission without a reconnect
+        time::test_time::advance_time(Duration::from_secs(600));
+        test.process().await;
+        assert_eq!(test.send_buffer.remaining_len(), 0);
+
+        test.queue.on_reconnect();
+        test.process().await;
+        test.read_publish(|p| assert!(p.dup));
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_window() {
+        time::test_time::set_static_now();
+
+        let mut test = Test::<1024>::with_config(RetryPolicy::default(), 2);
+
+        let requests = [
+            ("a", QoS::AtLeastOnce, 1u16),
+            ("b", QoS::ExactlyOnce, 2),
+            ("c", QoS::AtLeastOnce, 3),
+            ("d", QoS::AtMostOnce, 4),
+        ];
+        for (topic, qos, pid) in requests {
+            let publish = MqttPublish::new(topic, &[1, 2, 3], qos, false).unwrap();
+            test.queue.push_publish(publish, UniqueID(pid as u64), pid.try_into().unwrap()).await;
+        }
+
+        // Only two publishes fit into the window, the following ones are held back
+        test.process().await;
+        test.read_publish(|p| assert_eq!(p.topic_name, "a"));
+        test.read_publish(|p| assert_eq!(p.topic_name, "b"));
+        assert_eq!(test.send_buffer.remaining_len(), 0);
+
+        test.process().await;
+        assert_eq!(test.send_buffer.remaining_len(), 0);
+
+        // An acknowledgement opens the window in order of the queue
+        test.queue.process_puback(&1.try_into().unwrap()).unwrap();
+        test.process().await;
+        test.read_publish(|p| assert_eq!(p.topic_name, "c"));
+        test.read_publish(|p| assert_eq!(p.topic_name, "d"));
+        assert_eq!(test.send_buffer.remaining_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_withdraw() {
+        let mut test = Test::<1024>::new();
+
+        let uid = test.send_publish("hello/world", "hello world", QoS::AtLeastOnce, false).await;
+        assert!(test.queue.withdraw(uid));
+        assert!(! test.queue.withdraw(uid));
+
+        test.process().await;
+        assert_eq!(test.send_buffer.remaining_len(), 0);
+
+        // A publish written to the send buffer cannot be withdrawn
+        let uid = test.send_publish("hello/world", "hello world", QoS::AtLeastOnce, false).await;
+        test.process().await;
+        assert!(! test.queue.withdraw(uid));
+    }
+
+    #[test]
+    fn test_encode_publish_header() {
+        let payload = [7u8; 300];
+
+        for (qospid, len) in [(QosPid::AtMostOnce, 0), (QosPid::AtLeastOnce(Pid::try_from(3).unwrap()), 10), (QosPid::ExactlyOnce(Pid::try_from(300).unwrap()), 300)] {
+            let publish = Publish {
+                dup: true,
+                qospid,
+                retain: true,
+                topic_name: "hello/world",
+                payload: &payload[..len]
+            };
+
+            let mut expected = [0u8; 512];
+            let n = encode_slice(&Packet::Publish(publish.clone()), &mut expected).unwrap();
+
+            let mut buf = [0u8; 512];
+            let header_len = encode_publish_header(&publish, len, &mut buf).unwrap().unwrap();
+            buf[header_len..header_len + len].copy_from_slice(&payload[..len]);
+
+            assert_eq!(&buf[..header_len + len], &expected[..n]);
+
+            // Too small for the header
+            assert_eq!(encode_publish_header(&publish, len, &mut buf[..header_len - 1]), Ok(None));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streamed_publish() {
+        time::test_time::set_static_now();
+
+        let mut test = Test::<1024>::new();
+        let completions = CompletionTable::<CriticalSectionRawMutex, 4>::new();
+
+        let id = UniqueID(1);
+        let mut reservation = completions.reserve(id).await;
+        reservation.on_sent();
+
+        let publish = MqttPublish::new("hello/world", &[], QoS::AtLeastOnce, false).unwrap();
+        test.queue.push_publish_streamed(publish.clone(), 3, id, Pid::try_from(1).unwrap()).await;
+
+        // Only the header is written, the payload follows
+        test.queue.process(&mut test.send_buffer.create_writer(), &test.control_ch, &completions).unwrap();
+
+        let mut header = [0u8; 64];
+        let packet = publish.create_publish(Pid::try_from(1).unwrap(), false);
+        let header_len = encode_publish_header(&packet, 3, &mut header).unwrap().unwrap();
+        assert_eq!(test.queue.take_stream(), Some((id, 3, header_len)));
+        assert_eq!(test.send_buffer.data(), &header[..header_len]);
+        test.send_buffer.reset();
+        test.queue.on_stream_done(id, &test.control_ch);
+
+        // The header is written again for the retransmission
+        test.queue.on_reconnect();
+        test.queue.process(&mut test.send_buffer.create_writer(), &test.control_ch, &completions).unwrap();
+        assert!(matches!(test.queue.take_stream(), Some((stream_id, 3, _)) if stream_id == id));
+        test.send_buffer.reset();
+        test.queue.on_stream_done(id, &test.control_ch);
+
+        // Without the reservation the publish is not retransmitted but still waits for the puback
+        drop(reservation);
+        time::test_time::advance_time(Duration::from_secs(60));
+        test.queue.process(&mut test.send_buffer.create_writer(), &test.control_ch, &completions).unwrap();
+        assert_eq!(test.send_buffer.remaining_len(), 0);
+        assert_eq!(test.queue.take_stream(), None);
+        assert!(test.control_ch.try_receive().is_err());
+        assert!(test.queue.contains_pid(Pid::try_from(1).unwrap()));
+
+        // After a reconnect it cannot be sent again
+        test.queue.on_reconnect();
+        test.queue.process(&mut test.send_buffer.create_writer(), &test.control_ch, &completions).unwrap();
+        assert_eq!(test.send_buffer.remaining_len(), 0);
+        assert_eq!(test.queue.take_stream(), None);
+        assert_eq!(test.control_ch.try_receive(), Ok(MqttEvent::PublishResult(id, Err(MqttError::Abandoned))));
+        assert!(! test.queue.contains_pid(Pid::try_from(1).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_streamed_publish_acked_after_request_is_gone() {
+        time::test_time::set_static_now();
+
+        let mut test = Test::<1024>::new();
+        let completions = CompletionTable::<CriticalSectionRawMutex, 4>::new();
+
+        let id = UniqueID(1);
+        let pid = Pid::try_from(1).unwrap();
+        let mut reservation = completions.reserve(id).await;
+        reservation.on_sent();
+
+        let publish = MqttPublish::new("hello/world", &[], QoS::AtLeastOnce, false).unwrap();
+        test.queue.push_publish_streamed(publish.clone(), 3, id, pid).await;
+        test.queue.process(&mut test.send_buffer.create_writer(), &test.control_ch, &completions).unwrap();
+        assert!(matches!(test.queue.take_stream(), Some((stream_id, 3, _)) if stream_id == id));
+        test.queue.on_stream_done(id, &test.control_ch);
+
+        drop(reservation);
+        time::test_time::advance_time(Duration::from_secs(60));
+        test.queue.process(&mut test.send_buffer.create_writer(), &test.control_ch, &completions).unwrap();
+        assert!(test.queue.contains_pid(pid));
+
+        assert_eq!(test.queue.process_puback(&pid), Some(MqttEvent::PublishResult(id, Ok(()))));
+        assert!(! test.queue.contains_pid(pid));
+
+        // Not sent at all if the request is gone before
+        let publish_2 = UniqueID(2);
+        test.queue.push_publish_streamed(publish, 3, publish_2, Pid::try_from(2).unwrap()).await;
+        test.queue.process(&mut test.send_buffer.create_writer(), &test.control_ch, &completions).unwrap();
+        assert_eq!(test.queue.take_stream(), None);
+        assert_eq!(test.control_ch.try_receive(), Ok(MqttEvent::PublishResult(publish_2, Err(MqttError::Abandoned))));
+    }
+
+    #[tokio::test]
+    async fn test_streamed_publish_aborted() {
+        time::test_time::set_static_now();
+
+        let mut test = Test::<1024>::new();
+        let completions = CompletionTable::<CriticalSectionRawMutex, 4>::new();
+
+        let id = UniqueID(1);
+        let pid = Pid::try_from(1).unwrap();
+        let mut reservation = completions.reserve(id).await;
+        reservation.on_sent();
+
+        let publish = MqttPublish::new("hello/world", &[], QoS::AtLeastOnce, false).unwrap();
+        test.queue.push_publish_streamed(publish, 3, id, pid).await;
+        test.queue.process(&mut test.send_buffer.create_writer(), &test.control_ch, &completions).unwrap();
+        assert!(test.queue.take_stream().is_some());
+
+        // The publish never reached the broker, so its pid is released
+        test.queue.on_stream_aborted(id, &test.control_ch);
+        assert_eq!(test.control_ch.try_receive(), Ok(MqttEvent::PublishResult(id, Err(MqttError::Abandoned))));
+        assert!(! test.queue.contains_pid(pid));
+    }
+}
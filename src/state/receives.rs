@@ -2,13 +2,14 @@
 use buffer::BufferWriter;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use network::mqtt::{MqttPacketError, WriteMqttPacketMut};
-use crate::time::Instant;
+use crate::time::{Duration, Instant};
 use mqttrs::{Packet, Pid, Publish, QosPid};
 use queue_vec::{split::WithQueuedVecInner, QueuedVec};
 
-use crate::{time, MqttError, MqttPublish};
+use crate::{time, MqttError};
 
-const MAX_CONCURRENT_PUBLISHES: usize = 8;
+/// Duration after which a pubrec is sent again if no pubrel arrived
+const REPUBREC_DURATION: Duration = Duration::from_secs(5);
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -59,14 +60,27 @@ impl ReceivedPublish {
                 }
             }
             
-            //TODO resend pubrec
-            ReceiveState::AwaitPubrel(_instant) => {},
+            ReceiveState::AwaitPubrel(instant) => {
+                if (time::now() - instant) > REPUBREC_DURATION {
+                    let pid = self.qospid.pid().expect("When state is AwaitPubrel there must be a pid");
+                    debug!("no pubrel received for {}: resend pubrec", pid);
+                    self.send_pubrec(pid, send_buffer);
+                }
+            },
 
             ReceiveState::SendPubcomp => self.send_pubcomp(self.qospid.pid().expect("When state is SendPubcomp there must be a pid"), send_buffer),
             ReceiveState::Done => {},
         }
     }
 
+    /// Called after a reconnect: acknowledgements that were written to the send buffer before
+    /// may never have reached the broker.
+    fn on_reconnect(&mut self) {
+        if let ReceiveState::AwaitPubrel(_) = self.state {
+            self.state = ReceiveState::SendPubrec;
+        }
+    }
+
     fn send_pubcomp(&mut self, pid: Pid, send_buffer: &mut impl BufferWriter) {
         let result = send_buffer.write_mqtt_packet_sync(&Packet::Pubcomp(pid));
         match result {
@@ -127,11 +141,15 @@ impl <'a> From<&Publish<'a>> for ReceivedPublish {
     }
 }
 
-pub(crate) struct ReceivedPublishQueue {
-    publishes: QueuedVec<CriticalSectionRawMutex, ReceivedPublish, MAX_CONCURRENT_PUBLISHES>,
+/// Received publishes whose acknowledgement is not finished yet.
+/// 
+/// If the queue is full, new QoS 1 / QoS 2 publishes are neither delivered nor acknowledged,
+/// so the broker will deliver them again later.
+pub(crate) struct ReceivedPublishQueue<const N: usize> {
+    publishes: QueuedVec<CriticalSectionRawMutex, ReceivedPublish, N>,
 }
 
-impl ReceivedPublishQueue {
+impl <const N: usize> ReceivedPublishQueue<N> {
 
     pub(crate) fn new() -> Self {
         Self {
@@ -139,6 +157,13 @@ impl ReceivedPublishQueue {
         }
     }
 
+    /// Returns true if all received publishes are acknowledged completely
+    pub(crate) fn is_empty(&self) -> bool {
+        self.publishes.operate(|publishes| {
+            publishes.iter().all(|el| el.state == ReceiveState::Done)
+        })
+    }
+
     /// Publish and republish packets
     pub(crate) fn process(&self, send_buffer: &mut impl BufferWriter) -> Result<(), MqttError> {
         self.publishes.operate(|publishes|{
@@ -154,8 +179,33 @@ impl ReceivedPublishQueue {
         Ok(())
     }
 
+    /// Must be called when the connection to the broker is established again.
+    /// Pubrecs are sent again for all QoS 2 publishes that did not receive a pubrel yet.
+    pub(crate) fn on_reconnect(&self) {
+        self.publishes.operate(|publishes|{
+            for publish in publishes.iter_mut() {
+                publish.on_reconnect();
+            }
+            publishes.retain(|el| el.state != ReceiveState::Done);
+        })
+    }
+
+    /// Must be called if the broker did not keep the session:
+    /// it forgot the publishes in progress and may reuse their pids for new publishes.
+    pub(crate) fn on_session_lost(&self) {
+        self.publishes.operate(|publishes| {
+            if ! publishes.is_empty() {
+                debug!("session lost: forgetting {} received publishes", publishes.len());
+            }
+            publishes.clear();
+        })
+    }
+
     /**
      * Processes a received pubrel
+     * 
+     * A pubrel for an unknown pid is answered with a pubcomp too: the pubcomp sent before
+     * may have been lost, e. g. because of a reconnect.
      */
     pub(crate) fn process_pubrel(&self, pid: Pid) {
         self.publishes.operate(|publishes|{
@@ -165,45 +215,87 @@ impl ReceivedPublishQueue {
             if let Some(publish) = publish {
                 debug!("received pubrel for {}", pid);
                 publish.state = ReceiveState::SendPubcomp;
+            } else {
+                debug!("received pubrel for unknown {}: send pubcomp anyway", pid);
+                let mut publish = ReceivedPublish::new(QosPid::ExactlyOnce(pid));
+                publish.state = ReceiveState::SendPubcomp;
+                
+                if Self::try_push(publishes, publish).is_err() {
+                    warn!("cannot answer pubrel for {}: too many received publishes in progress", pid);
+                }
             }
         })
     }
 
+    /// Adds the publish to the queue. Removes done publishes if there is no space left.
+    fn try_push(publishes: &mut heapless::Vec<ReceivedPublish, N>, publish: ReceivedPublish) -> Result<(), ReceivedPublish> {
+        if publishes.is_full() {
+            publishes.retain(|el| el.state != ReceiveState::Done);
+        }
+        publishes.push(publish)
+    }
+
     /**
      * Process a received publish
+     * Returns true if the publish must be delivered to the application
      */
-    pub(crate) async fn process_publish(&self, publish: &Publish<'_>) -> Option<MqttPublish>{
-        let p = match MqttPublish::try_from(publish) {
-            Ok(p) => p,
-            Err(e) => {
-                error!("could not transform &Publish<'_> to MqttPublish: {}", e);
-                return None;
-            },
-        };
-
+    pub(crate) fn process_publish(&self, publish: &Publish<'_>) -> bool {
         match publish.qospid {
-            QosPid::AtMostOnce => Some(p),
-            QosPid::AtLeastOnce(pid) | QosPid::ExactlyOnce(pid) => {
-                if publish.dup && self.check_duplicate_publish(pid, &publish.topic_name) {
-                    None
+            QosPid::AtMostOnce => true,
+            QosPid::AtLeastOnce(pid) => {
+                if publish.dup && self.check_duplicate_publish(pid, publish.topic_name) {
+                    false
+                } else {
+                    self.push_received(publish)
+                }
+            },
+            // Until the pubrel arrives every publish with the same pid is a duplicate,
+            // regardless of the dup flag
+            QosPid::ExactlyOnce(pid) => {
+                if self.check_duplicate_publish(pid, publish.topic_name) {
+                    false
                 } else {
-                    self.publishes.push(ReceivedPublish::new(publish.qospid)).await;
-                    Some(p)
+                    self.push_received(publish)
                 }
             }
         }
     }
 
+    /// Removes a publish which was not received completely, so it is not acknowledged
+    pub(crate) fn forget_publish(&self, qospid: QosPid) {
+        if let Some(pid) = qospid.pid() {
+            self.publishes.operate(|publishes| {
+                publishes.retain(|el| ! (el.qospid.pid() == Some(pid) && el.state == ReceiveState::Initial));
+            });
+        }
+    }
+
+    /// Adds the publish to the queue, returns true if it must be delivered
+    /// If the queue is full, the publish is not delivered and not acknowledged.
+    fn push_received(&self, publish: &Publish<'_>) -> bool {
+        self.publishes.operate(|publishes| {
+            match Self::try_push(publishes, ReceivedPublish::new(publish.qospid)) {
+                Ok(()) => true,
+                Err(_) => {
+                    warn!("cannot accept publish to {}: too many received publishes in progress, waiting for redelivery", publish.topic_name);
+                    false
+                }
+            }
+        })
+    }
+
     /**
      * Checks if the provided pid is a duplicate
-     * if so sets the state to [`ReceiveState::SendPubrec`]
-     * otherwise does nothing
+     * if so and the pubrec was sent already, sends the pubrec again.
+     * The state never moves backwards: a pubcomp in progress is not replaced by a pubrec.
      */
     fn check_duplicate_publish(&self, pid: Pid, topic: &str) -> bool {
         self.publishes.operate(|publishes|{
             for p in publishes {
-                if p.qospid.pid() == Some(pid) {
-                    p.state = ReceiveState::SendPubrec;
+                if p.qospid.pid() == Some(pid) && p.state != ReceiveState::Done {
+                    if let ReceiveState::AwaitPubrel(_) = p.state {
+                        p.state = ReceiveState::SendPubrec;
+                    }
                     debug!("received publish dup: pid = {}, topic = {}", pid, topic);
                     return true
                 }
@@ -219,16 +311,29 @@ impl ReceivedPublishQueue {
 
 #[cfg(test)]
 mod tests {
-    use buffer::{new_stack_buffer, ReadWrite};
+    use buffer::{new_stack_buffer, Buffer, ReadWrite};
     use mqttrs::{Packet, Pid, Publish, QosPid};
     use network::mqtt::ReadMqttPacket;
 
+    use crate::time;
+    use crate::time::Duration;
+
+    use crate::DEFAULT_RECEIVE_QUEUE_SIZE;
+
     use super::ReceivedPublishQueue;
 
+    fn expect_packet(send_buffer: &mut Buffer<[u8; 1024]>, expected: Packet<'static>) {
+        let reader = send_buffer.create_reader();
+        let p = reader.read_packet()
+            .unwrap()
+            .expect("expected to read a packet");
+        assert_eq!(p, expected);
+    }
+
     #[tokio::test]
     #[ntest::timeout(1000)]
     async fn test_receive_qos_0() {
-        let queue = ReceivedPublishQueue::new();
+        let queue = ReceivedPublishQueue::<DEFAULT_RECEIVE_QUEUE_SIZE>::new();
         let mut send_buffer = new_stack_buffer::<1024>();
 
         let publish = Publish{
@@ -239,8 +344,7 @@ mod tests {
             topic_name: "test-topic"
         };
 
-        let event = queue.process_publish(&publish).await;
-        assert!(event.is_some());
+        assert!(queue.process_publish(&publish));
 
         queue.process(&mut send_buffer.create_writer()).unwrap();
 
@@ -250,7 +354,7 @@ mod tests {
     #[tokio::test]
     #[ntest::timeout(1000)]
     async fn test_receive_qos_1() {
-        let queue = ReceivedPublishQueue::new();
+        let queue = ReceivedPublishQueue::<DEFAULT_RECEIVE_QUEUE_SIZE>::new();
         let mut send_buffer = new_stack_buffer::<1024>();
 
         let publish = Publish{
@@ -261,8 +365,7 @@ mod tests {
             topic_name: "test-topic"
         };
 
-        let event = queue.process_publish(&publish).await;
-        assert!(event.is_some());
+        assert!(queue.process_publish(&publish));
 
         queue.process(&mut send_buffer.create_writer()).unwrap();
 
@@ -281,7 +384,7 @@ mod tests {
     #[tokio::test]
     #[ntest::timeout(1000)]
     async fn test_receive_qos_2() {
-        let queue = ReceivedPublishQueue::new();
+        let queue = ReceivedPublishQueue::<DEFAULT_RECEIVE_QUEUE_SIZE>::new();
         let mut send_buffer = new_stack_buffer::<1024>();
 
         let pid = Pid::try_from(34).unwrap();
@@ -294,8 +397,7 @@ mod tests {
             topic_name: "test-topic"
         };
 
-        let event = queue.process_publish(&publish).await;
-        assert!(event.is_some());
+        assert!(queue.process_publish(&publish));
 
         queue.process(&mut send_buffer.create_writer()).unwrap();
 
@@ -329,7 +431,7 @@ mod tests {
     #[tokio::test]
     #[ntest::timeout(1000)]
     async fn test_receive_qos_2_dup() {
-        let queue = ReceivedPublishQueue::new();
+        let queue = ReceivedPublishQueue::<DEFAULT_RECEIVE_QUEUE_SIZE>::new();
         let mut send_buffer = new_stack_buffer::<1024>();
 
         let pid = Pid::try_from(34).unwrap();
@@ -343,8 +445,7 @@ mod tests {
         };
 
         // Send first publish
-        let event = queue.process_publish(&publish).await;
-        assert!(event.is_some());
+        assert!(queue.process_publish(&publish));
 
         queue.process(&mut send_buffer.create_writer()).unwrap();
 
@@ -362,8 +463,7 @@ mod tests {
 
         // send second, duplicate publish
         publish.dup = true;
-        let event = queue.process_publish(&publish).await;
-        assert!(event.is_none());
+        assert!(! queue.process_publish(&publish));
         queue.process(&mut send_buffer.create_writer()).unwrap();
 
         let reader = send_buffer.create_reader();
@@ -378,4 +478,148 @@ mod tests {
         }
     }
 
-}
\ No newline at end of file
+    #[tokio::test]
+    #[ntest::timeout(1000)]
+    async fn test_receive_qos_2_dup_after_pubrel() {
+        let queue = ReceivedPublishQueue::<DEFAULT_RECEIVE_QUEUE_SIZE>::new();
+        let mut send_buffer = new_stack_buffer::<1024>();
+
+        let pid = Pid::try_from(34).unwrap();
+
+        let mut publish = Publish{
+            dup: false,
+            qospid: QosPid::ExactlyOnce(pid),
+            retain: false,
+            payload: "test".as_bytes(),
+            topic_name: "test-topic"
+        };
+
+        assert!(queue.process_publish(&publish));
+        queue.process(&mut send_buffer.create_writer()).unwrap();
+        expect_packet(&mut send_buffer, Packet::Pubrec(pid));
+
+        // A late duplicate must not move the publish back from pubcomp to pubrec
+        queue.process_pubrel(pid);
+        publish.dup = true;
+        assert!(! queue.process_publish(&publish));
+
+        queue.process(&mut send_buffer.create_writer()).unwrap();
+        expect_packet(&mut send_buffer, Packet::Pubcomp(pid));
+        assert!(! send_buffer.has_remaining_len());
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(1000)]
+    async fn test_receive_qos_2_resend_pubrec() {
+        time::test_time::set_static_now();
+
+        let queue = ReceivedPublishQueue::<DEFAULT_RECEIVE_QUEUE_SIZE>::new();
+        let mut send_buffer = new_stack_buffer::<1024>();
+
+        let pid = Pid::try_from(34).unwrap();
+
+        let publish = Publish{
+            dup: false,
+            qospid: QosPid::ExactlyOnce(pid),
+            retain: false,
+            payload: "test".as_bytes(),
+            topic_name: "test-topic"
+        };
+
+        assert!(queue.process_publish(&publish));
+
+        queue.process(&mut send_buffer.create_writer()).unwrap();
+        expect_packet(&mut send_buffer, Packet::Pubrec(pid));
+
+        // No resend before the timeout
+        time::test_time::advance_time(Duration::from_secs(1));
+        queue.process(&mut send_buffer.create_writer()).unwrap();
+        assert!(! send_buffer.has_remaining_len());
+
+        time::test_time::advance_time(Duration::from_secs(10));
+        queue.process(&mut send_buffer.create_writer()).unwrap();
+        expect_packet(&mut send_buffer, Packet::Pubrec(pid));
+
+        // A redelivery without dup flag must not be delivered again
+        assert!(! queue.process_publish(&publish));
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(1000)]
+    async fn test_receive_qos_2_reconnect() {
+        let queue = ReceivedPublishQueue::<DEFAULT_RECEIVE_QUEUE_SIZE>::new();
+        let mut send_buffer = new_stack_buffer::<1024>();
+
+        let pid = Pid::try_from(34).unwrap();
+
+        let publish = Publish{
+            dup: false,
+            qospid: QosPid::ExactlyOnce(pid),
+            retain: false,
+            payload: "test".as_bytes(),
+            topic_name: "test-topic"
+        };
+
+        assert!(queue.process_publish(&publish), "publish must be delivered");
+        queue.process(&mut send_buffer.create_writer()).unwrap();
+
+        // The pubrec is lost with the connection
+        send_buffer.reset();
+        queue.on_reconnect();
+
+        queue.process(&mut send_buffer.create_writer()).unwrap();
+        expect_packet(&mut send_buffer, Packet::Pubrec(pid));
+
+        queue.process_pubrel(pid);
+        queue.process(&mut send_buffer.create_writer()).unwrap();
+        expect_packet(&mut send_buffer, Packet::Pubcomp(pid));
+
+        // The pubcomp is lost too: the broker sends the pubrel again
+        send_buffer.reset();
+        queue.on_reconnect();
+        queue.process_pubrel(pid);
+        queue.process(&mut send_buffer.create_writer()).unwrap();
+        expect_packet(&mut send_buffer, Packet::Pubcomp(pid));
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(1000)]
+    async fn test_receive_overflow() {
+        let queue = ReceivedPublishQueue::<DEFAULT_RECEIVE_QUEUE_SIZE>::new();
+
+        for i in 0..DEFAULT_RECEIVE_QUEUE_SIZE {
+            let publish = Publish{
+                dup: false,
+                qospid: QosPid::ExactlyOnce(Pid::try_from(i as u16 + 1).unwrap()),
+                retain: false,
+                payload: "test".as_bytes(),
+                topic_name: "test-topic"
+            };
+            assert!(queue.process_publish(&publish), "publish must be delivered");
+        }
+
+        let publish = Publish{
+            dup: false,
+            qospid: QosPid::ExactlyOnce(Pid::try_from(100).unwrap()),
+            retain: false,
+            payload: "test".as_bytes(),
+            topic_name: "test-topic"
+        };
+
+        // The queue is full: the publish is neither delivered nor acknowledged
+        assert!(! queue.process_publish(&publish));
+
+        let mut send_buffer = new_stack_buffer::<1024>();
+        queue.process(&mut send_buffer.create_writer()).unwrap();
+
+        for _ in 0..DEFAULT_RECEIVE_QUEUE_SIZE {
+            let reader = send_buffer.create_reader();
+            let p = reader.read_packet()
+                .unwrap()
+                .expect("expected to read pubrec");
+            assert_ne!(p, Packet::Pubrec(Pid::try_from(100).unwrap()));
+        }
+        assert!(! send_buffer.has_remaining_len());
+    }
+
+}
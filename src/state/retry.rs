@@ -0,0 +1,158 @@
+use core::cmp::{max, min};
+
+use crate::time::{Duration, Instant};
+
+/// Lower limit of the retransmission interval, so a zero interval or backoff
+/// does not retransmit on every turn of the event loop
+pub const MIN_RETRANSMIT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Defines when unacknowledged packets are sent again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RetransmitMode {
+    /// Retransmit after the interval of the [`RetryPolicy`] and after a reconnect
+    Interval,
+
+    /// Retransmit only after a reconnect as demanded by MQTT 3.1.1
+    OnReconnect
+}
+
+/// Retransmission policy for unacknowledged publishes, subscribes and unsubscribes
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RetryPolicy {
+    pub mode: RetransmitMode,
+
+    /// The time to wait for an acknowledgement before the first retransmission
+    pub interval: Duration,
+
+    /// The interval is multiplied by this factor after every retransmission
+    /// A factor of 1 keeps the interval constant, 0 is treated as 1.
+    pub backoff: u32,
+
+    /// Upper limit of the interval when using a backoff
+    pub max_interval: Duration,
+
+    /// Number of retransmissions before the request fails with [`crate::MqttError::Timeout`]
+    /// `None` retries forever
+    pub max_attempts: Option<u8>
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            mode: RetransmitMode::Interval,
+            interval: Duration::from_secs(5),
+            backoff: 1,
+            max_interval: Duration::from_secs(60),
+            max_attempts: None
+        }
+    }
+}
+
+impl RetryPolicy {
+
+    /// Creates a policy that only retransmits after a reconnect
+    pub fn on_reconnect(max_attempts: Option<u8>) -> Self {
+        Self {
+            mode: RetransmitMode::OnReconnect,
+            max_attempts,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the time to wait for an acknowledgement after `attempts` retransmissions
+    /// The interval is at least [`MIN_RETRANSMIT_INTERVAL`].
+    pub(crate) fn retransmit_interval(&self, attempts: u8) -> Duration {
+        let max_interval = max(self.max_interval, MIN_RETRANSMIT_INTERVAL);
+        let backoff = max(self.backoff, 1);
+
+        let mut interval = min(max(self.interval, MIN_RETRANSMIT_INTERVAL), max_interval);
+        for _ in 0..attempts {
+            if interval >= max_interval {
+                break;
+            }
+            interval = min(interval * backoff, max_interval);
+        }
+        interval
+    }
+
+    /// Returns true if a packet sent at `sent` must be retransmitted at `now`
+    /// Retransmissions because of a reconnect are not covered.
+    pub(crate) fn should_retransmit(&self, sent: Instant, attempts: u8, now: Instant) -> bool {
+        match self.mode {
+            RetransmitMode::Interval => (now - sent) > self.retransmit_interval(attempts),
+            RetransmitMode::OnReconnect => false,
+        }
+    }
+
+    /// Returns true if no more retransmission is allowed after `attempts` retransmissions
+    pub(crate) fn attempts_exhausted(&self, attempts: u8) -> bool {
+        match self.max_attempts {
+            Some(max) => attempts >= max,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::time::{self, Duration};
+
+    use super::{RetransmitMode, RetryPolicy, MIN_RETRANSMIT_INTERVAL};
+
+    #[test]
+    fn test_retransmit_interval_backoff() {
+        let policy = RetryPolicy {
+            mode: RetransmitMode::Interval,
+            interval: Duration::from_secs(2),
+            backoff: 2,
+            max_interval: Duration::from_secs(10),
+            max_attempts: Some(5)
+        };
+
+        assert_eq!(policy.retransmit_interval(0), Duration::from_secs(2));
+        assert_eq!(policy.retransmit_interval(1), Duration::from_secs(4));
+        assert_eq!(policy.retransmit_interval(2), Duration::from_secs(8));
+        assert_eq!(policy.retransmit_interval(3), Duration::from_secs(10));
+        assert_eq!(policy.retransmit_interval(200), Duration::from_secs(10));
+
+        assert!(! policy.attempts_exhausted(4));
+        assert!(policy.attempts_exhausted(5));
+    }
+
+    #[test]
+    fn test_retransmit_interval_minimum() {
+        let policy = RetryPolicy {
+            mode: RetransmitMode::Interval,
+            interval: Duration::from_secs(0),
+            backoff: 0,
+            max_interval: Duration::from_secs(0),
+            max_attempts: None
+        };
+
+        assert_eq!(policy.retransmit_interval(0), MIN_RETRANSMIT_INTERVAL);
+        assert_eq!(policy.retransmit_interval(3), MIN_RETRANSMIT_INTERVAL);
+
+        let policy = RetryPolicy {
+            interval: Duration::from_secs(1),
+            backoff: 0,
+            ..Default::default()
+        };
+        assert_eq!(policy.retransmit_interval(2), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_should_retransmit() {
+        let now = time::now();
+        let sent = now - Duration::from_secs(6);
+
+        let policy = RetryPolicy::default();
+        assert!(policy.should_retransmit(sent, 0, now));
+        assert!(! policy.should_retransmit(now, 0, now));
+        assert!(! policy.attempts_exhausted(u8::MAX));
+
+        let policy = RetryPolicy::on_reconnect(None);
+        assert!(! policy.should_retransmit(sent, 0, now));
+    }
+}
@@ -3,15 +3,15 @@ use core::cell::RefCell;
 
 use buffer::BufferWriter;
 use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
-use crate::{time::{Duration, Instant}, AutoSubscribe};
+use crate::{io::AsyncSender, time::Instant, AutoSubscribe};
 use heapless::{FnvIndexMap, String, Vec};
 use mqttrs::{encode_slice, Packet, Pid, QoS, Suback, Subscribe, SubscribeReturnCodes, SubscribeTopic, Unsubscribe};
 use queue_vec::split::{QueuedVecInner, WithQueuedVecInner};
 
-use crate::{time, MqttError, MqttEvent, Topic, UniqueID};
+use crate::{time, MqttError, MqttEvent, Topic, UniqueID, MAX_AUTO_SUBSCRIBES};
+
+use super::retry::RetryPolicy;
 
-const RESUBSCRIBE_DURATION: Duration = Duration::from_secs(5);
-pub const MAX_CONCURRENT_REQUESTS: usize = 4;
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -35,15 +35,16 @@ impl RequestType {
 pub enum RequestState {
     Initial,
     AwaitAck(Instant),
-    Done
+    Done,
+    TimedOut
 }
 
 impl RequestState {
-    fn should_publish(&self, now: Instant) -> bool {
+    fn should_publish(&self, now: Instant, policy: &RetryPolicy, attempts: u8) -> bool {
         match self {
             Self::Initial => true,
-            Self::AwaitAck(instant) => (now - *instant) > RESUBSCRIBE_DURATION,
-            Self::Done => false
+            Self::AwaitAck(instant) => policy.should_retransmit(*instant, attempts, now),
+            Self::Done | Self::TimedOut => false
         }
     }
 
@@ -58,39 +59,59 @@ impl RequestState {
 
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct Request {
+pub struct Request<const T: usize> {
     request_type: RequestType,
-    topic: Topic,
+    topic: Topic<T>,
     pid: Pid,
     external_id: UniqueID,
     state: RequestState,
-    initial: bool
+    initial: bool,
+
+    /// Number of retransmissions
+    attempts: u8,
+
+    /// The connection was reestablished: the request must be sent again
+    retransmit_pending: bool
 }
 
-impl Request {
-    fn subscribe(topic: Topic, pid: Pid, external_id: UniqueID, qos: QoS, initial: bool) -> Self {
+impl <const T: usize> Request<T> {
+    fn subscribe(topic: Topic<T>, pid: Pid, external_id: UniqueID, qos: QoS, initial: bool) -> Self {
         Self {
             topic, pid, external_id,
             request_type: RequestType::Subscribe(qos),
             state: RequestState::Initial,
-            initial
+            initial,
+            attempts: 0,
+            retransmit_pending: false
         }
     }
 
-    fn unsubscribe(topic: Topic, pid: Pid, external_id: UniqueID) -> Self {
+    fn unsubscribe(topic: Topic<T>, pid: Pid, external_id: UniqueID) -> Self {
         Self {
             topic, pid, external_id,
             request_type: RequestType::Unsubscribe,
             state: RequestState::Initial,
-            initial: false // There is no initial unsubscribe
+            initial: false, // There is no initial unsubscribe
+            attempts: 0,
+            retransmit_pending: false
         }
     }
 
+    fn should_send(&self, now: Instant, policy: &RetryPolicy) -> bool {
+        self.state.should_publish(now, policy, self.attempts) 
+            || (self.retransmit_pending && self.state.is_await_ack())
+    }
+
     fn on_send_success(&mut self) {  
         match self.state {
              RequestState::Initial => {
                 self.state = RequestState::AwaitAck(time::now());
              },
+             RequestState::AwaitAck(_) => {
+                self.state = RequestState::AwaitAck(time::now());
+                self.attempts = self.attempts.saturating_add(1);
+                self.retransmit_pending = false;
+             },
              _ => {}
          }
     }
@@ -148,7 +169,7 @@ impl Request {
 }
 
 struct InitialSubscribes {
-    initial_subscriptions_pending: FnvIndexMap<Pid, bool, MAX_CONCURRENT_REQUESTS>,
+    initial_subscriptions_pending: FnvIndexMap<Pid, bool, MAX_AUTO_SUBSCRIBES>,
 }
 
 impl InitialSubscribes {
@@ -183,12 +204,13 @@ impl InitialSubscribes {
 
 }
 
-pub(crate) struct SubQueue {
-    inner: Mutex<CriticalSectionRawMutex, RefCell<QueuedVecInner<InitialSubscribes, Request, MAX_CONCURRENT_REQUESTS>>>
+pub(crate) struct SubQueue<const N: usize, const T: usize> {
+    inner: Mutex<CriticalSectionRawMutex, RefCell<QueuedVecInner<InitialSubscribes, Request<T>, N>>>,
+    retry_policy: RetryPolicy
 }
 
-impl WithQueuedVecInner<InitialSubscribes, Request, MAX_CONCURRENT_REQUESTS> for SubQueue {
-    fn with_queued_vec_inner<F, O>(&self, operation: F) -> O where F: FnOnce(&mut QueuedVecInner<InitialSubscribes, Request, MAX_CONCURRENT_REQUESTS>) -> O {
+impl <const N: usize, const T: usize> WithQueuedVecInner<InitialSubscribes, Request<T>, N> for SubQueue<N, T> {
+    fn with_queued_vec_inner<F, O>(&self, operation: F) -> O where F: FnOnce(&mut QueuedVecInner<InitialSubscribes, Request<T>, N>) -> O {
         self.inner.lock(|inner| {
             let mut inner = inner.borrow_mut();
             operation(&mut inner)
@@ -196,19 +218,20 @@ impl WithQueuedVecInner<InitialSubscribes, Request, MAX_CONCURRENT_REQUESTS> for
     }
 }
 
-impl SubQueue {
-    pub(crate) fn new() -> Self {
+impl <const N: usize, const T: usize> SubQueue<N, T> {
+    pub(crate) fn new(retry_policy: RetryPolicy) -> Self {
         Self {
-            inner: Mutex::new(RefCell::new(QueuedVecInner::new(InitialSubscribes::new())))
+            inner: Mutex::new(RefCell::new(QueuedVecInner::new(InitialSubscribes::new()))),
+            retry_policy
         }
     }
 
-    pub(crate) async fn push_subscribe(&self, topic: Topic, pid: Pid, external_id: UniqueID, qos: QoS) {
+    pub(crate) async fn push_subscribe(&self, topic: Topic<T>, pid: Pid, external_id: UniqueID, qos: QoS) {
         let req = Request::subscribe(topic, pid, external_id, qos, false);
         self.push(req).await;
     }
 
-    pub(crate) async fn push_unsubscribe(&self, topic: Topic, pid: Pid, external_id: UniqueID) {
+    pub(crate) async fn push_unsubscribe(&self, topic: Topic<T>, pid: Pid, external_id: UniqueID) {
         let req = Request::unsubscribe(topic, pid, external_id);
         self.push(req).await;
     }
@@ -217,7 +240,7 @@ impl SubQueue {
      * Adds the subscription requests from the auto subscribe client option. 
      * Current design decision: current requests are removed!
      */
-    pub(super) fn add_auto_subscribes<F: FnMut() -> Pid>(&self, auto_subscribes: &[AutoSubscribe], mut pid_source: F) {
+    pub(super) fn add_auto_subscribes<F: FnMut() -> Pid>(&self, auto_subscribes: &[AutoSubscribe<T>], mut pid_source: F) {
 
         self.inner.lock(|inner| {
             let mut inner = inner.borrow_mut();
@@ -247,20 +270,114 @@ impl SubQueue {
         })
     } 
 
+    /// Returns true if no subscribe or unsubscribe is in progress
+    pub(crate) fn is_empty(&self) -> bool {
+        self.operate(|requests| requests.iter().all(|el| el.state == RequestState::Done))
+    }
+
+    /// Removes all requests and returns the results of the requests of the client.
+    /// Requests which are not done fail with [`MqttError::Abandoned`].
+    pub(crate) fn abandon(&self) -> Vec<MqttEvent, N> {
+        self.operate(|requests| {
+            let results = requests.iter()
+                .filter(|el| ! el.initial && el.state != RequestState::Done)
+                .map(|el| {
+                    let error = if el.state == RequestState::TimedOut {
+                        MqttError::Timeout
+                    } else {
+                        MqttError::Abandoned
+                    };
+
+                    match el.request_type {
+                        RequestType::Subscribe(_) => MqttEvent::SubscribeResult(el.external_id, Err(error)),
+                        RequestType::Unsubscribe => MqttEvent::UnsubscribeResult(el.external_id, Err(error)),
+                    }
+                })
+                .collect();
+
+            requests.clear();
+            results
+        })
+    }
+
+    /// Returns true if a subscribe or unsubscribe with the `pid` is in the queue
+    pub(crate) fn contains_pid(&self, pid: Pid) -> bool {
+        self.operate(|requests| {
+            requests.iter().any(|el| el.pid == pid)
+        })
+    }
+
+    /// Removes the subscribe or unsubscribe with the request `id` if it was not sent yet.
+    /// Returns true if the request was removed.
+    pub(crate) fn withdraw(&self, id: UniqueID) -> bool {
+        self.operate(|requests| {
+            let pos = requests.iter()
+                .position(|el| el.external_id == id && el.state == RequestState::Initial);
+
+            match pos {
+                Some(pos) => {
+                    requests.remove(pos);
+                    true
+                },
+                None => false,
+            }
+        })
+    }
+
+    /// Must be called when the connection to the broker is established again.
+    /// All unacknowledged subscribes and unsubscribes are sent again.
+    pub(crate) fn on_reconnect(&self) {
+        self.operate(|requests|{
+            for request in requests.iter_mut() {
+                if request.state.is_await_ack() {
+                    request.retransmit_pending = true;
+                }
+            }
+        })
+    }
+
     /// Sends subscribe and unsubscribe
-    pub(crate) fn process(&self, send_buffer: &mut impl BufferWriter) -> Result<(), MqttError> {
+    pub(crate) fn process(&self, send_buffer: &mut impl BufferWriter, control_sender: &impl AsyncSender<MqttEvent>) -> Result<(), MqttError> {
         self.operate(|requests|{
+            let now = time::now();
 
             for request in requests.iter_mut() {
                 // TODO answer quetsion:
                 //   Should the loop `break;` if a publish cannot be written to buffer 
                 //   beause of insufficient space?
-                if request.state.should_publish(time::now()) {
+                if ! request.should_send(now, &self.retry_policy) {
+                    continue;
+                }
+
+                if request.state.is_await_ack() && self.retry_policy.attempts_exhausted(request.attempts) {
+                    warn!("{} packet {} not acknowledged after {} retransmissions", request.request_type, request.pid, request.attempts);
+                    request.state = RequestState::TimedOut;
+                } else {
                     request.send(send_buffer)?;
                 }
             }
-            Ok(())
-        })
+            Ok::<(), MqttError>(())
+        })?;
+
+        self.cleanup(control_sender);
+
+        Ok(())
+    }
+
+    /// Remove timed out requests and inform the sender
+    fn cleanup(&self, control_sender: &impl AsyncSender<MqttEvent>) {
+        self.retain(|el| {
+            if el.state != RequestState::TimedOut {
+                return true;
+            }
+
+            let event = match el.request_type {
+                RequestType::Subscribe(_) => MqttEvent::SubscribeResult(el.external_id, Err(MqttError::Timeout)),
+                RequestType::Unsubscribe => MqttEvent::UnsubscribeResult(el.external_id, Err(MqttError::Timeout)),
+            };
+
+            control_sender.try_send(event).is_err()
+        });
     }
 
     pub(crate) fn process_suback(&self, suback: &Suback) -> Vec<MqttEvent, 2> {
@@ -338,20 +455,23 @@ mod tests {
     use mqttrs::{Packet, Pid, QoS, Suback, SubscribeReturnCodes};
     use network::mqtt::ReadMqttPacket;
 
-    use crate::{state::{pid::PidSource, sub::SubQueue}, AutoSubscribe, MqttEvent, Topic, UniqueID};
+    use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Channel};
+
+    use crate::{state::{pid::PidSource, retry::RetryPolicy, sub::SubQueue}, AutoSubscribe, MqttError, MqttEvent, Topic, UniqueID, time::{self, Duration}, DEFAULT_SUBSCRIBE_QUEUE_SIZE, MAX_TOPIC_SIZE};
 
 
     #[tokio::test]
     #[ntest::timeout(5000)]
     async fn test_subscribe () {
 
-        let subs = SubQueue::new();
+        let subs = SubQueue::<DEFAULT_SUBSCRIBE_QUEUE_SIZE, MAX_TOPIC_SIZE>::new(RetryPolicy::default());
+        let control = Channel::<NoopRawMutex, MqttEvent, 4>::new();
 
         let mut send_buffer = new_stack_buffer::<1024>();
 
         {
             let mut writer = send_buffer.create_writer();
-            subs.process(&mut writer).unwrap();
+            subs.process(&mut writer, &control).unwrap();
         }
 
         assert_eq!(send_buffer.remaining_len(), 0);
@@ -365,7 +485,7 @@ mod tests {
 
         {
             let mut writer = send_buffer.create_writer();
-            subs.process(&mut writer).unwrap();
+            subs.process(&mut writer, &control).unwrap();
         }
 
         {
@@ -387,18 +507,19 @@ mod tests {
     #[ntest::timeout(5000)]
     async fn test_auto_subscribe () {
 
-        let subs = SubQueue::new();
+        let subs = SubQueue::<DEFAULT_SUBSCRIBE_QUEUE_SIZE, MAX_TOPIC_SIZE>::new(RetryPolicy::default());
+        let control = Channel::<NoopRawMutex, MqttEvent, 4>::new();
         let mut send_buffer = new_stack_buffer::<1024>();
 
         let pid = Pid::new() + 16;
         let auto_subscribes = [
-            AutoSubscribe::new("some/default/topic", QoS::ExactlyOnce)
+            AutoSubscribe::new("some/default/topic", QoS::ExactlyOnce).unwrap()
         ];
         subs.add_auto_subscribes(&auto_subscribes, || pid);
 
         {
             let mut writer = send_buffer.create_writer();
-            subs.process(&mut writer).unwrap();
+            subs.process(&mut writer, &control).unwrap();
         }
 
         {
@@ -448,19 +569,20 @@ mod tests {
     #[ntest::timeout(5000)]
     async fn test_multi_auto_subscribe () {
 
-        let subs = SubQueue::new();
+        let subs = SubQueue::<DEFAULT_SUBSCRIBE_QUEUE_SIZE, MAX_TOPIC_SIZE>::new(RetryPolicy::default());
+        let control = Channel::<NoopRawMutex, MqttEvent, 4>::new();
         let mut send_buffer = new_stack_buffer::<1024>();
         let pid_src = PidSource::new();
 
         let auto_subscribes = [
-            AutoSubscribe::new("some/default/topic/1", QoS::ExactlyOnce),
-            AutoSubscribe::new("some/default/topic/2", QoS::AtLeastOnce)
+            AutoSubscribe::new("some/default/topic/1", QoS::ExactlyOnce).unwrap(),
+            AutoSubscribe::new("some/default/topic/2", QoS::AtLeastOnce).unwrap()
         ];
         subs.add_auto_subscribes(&auto_subscribes, || pid_src.next_pid());
 
         {
             let mut writer = send_buffer.create_writer();
-            subs.process(&mut writer).unwrap();
+            subs.process(&mut writer, &control).unwrap();
         }
 
         let mut pid_1 = None;
@@ -536,5 +658,55 @@ mod tests {
         }
     }
 
-    
-}
\ No newline at end of file
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_subscribe_timeout() {
+        time::test_time::set_static_now();
+
+        let policy = RetryPolicy {
+            max_attempts: Some(1),
+            ..Default::default()
+        };
+        let subs = SubQueue::<DEFAULT_SUBSCRIBE_QUEUE_SIZE, MAX_TOPIC_SIZE>::new(policy);
+        let control = Channel::<NoopRawMutex, MqttEvent, 4>::new();
+        let mut send_buffer = new_stack_buffer::<1024>();
+
+        let pid = Pid::new() + 3;
+        {
+            let mut topic = Topic::new();
+            topic.push_str("test/timeout").unwrap();
+            subs.push_subscribe(topic, pid, UniqueID(7), QoS::AtLeastOnce).await;
+        }
+
+        // Initial subscribe and one retransmission
+        for _ in 0..2 {
+            {
+                let mut writer = send_buffer.create_writer();
+                subs.process(&mut writer, &control).unwrap();
+            }
+            let reader = send_buffer.create_reader();
+            let p = reader.read_packet().unwrap()
+                .expect("expected a subscribe packet but got none");
+            assert!(matches!(p, Packet::Subscribe(_)));
+            drop(reader);
+
+            time::test_time::advance_time(Duration::from_secs(6));
+        }
+
+        {
+            let mut writer = send_buffer.create_writer();
+            subs.process(&mut writer, &control).unwrap();
+        }
+        assert_eq!(send_buffer.remaining_len(), 0);
+        assert_eq!(control.try_receive().unwrap(), MqttEvent::SubscribeResult(UniqueID(7), Err(MqttError::Timeout)));
+
+        // The request is removed
+        {
+            let mut writer = send_buffer.create_writer();
+            subs.process(&mut writer, &control).unwrap();
+        }
+        assert_eq!(send_buffer.remaining_len(), 0);
+        assert!(control.try_receive().is_err());
+    }
+}
@@ -0,0 +1,637 @@
+use core::cell::Cell;
+
+use buffer::Buffer;
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::{raw::RawMutex, Mutex}, channel::Channel, mutex::{self, MutexGuard}, signal::Signal};
+use embedded_io_async::{Error, ErrorKind, ErrorType, Read, Seek, SeekFrom};
+use heapless::Vec;
+use mqttrs::{Pid, Publish, QosPid};
+use network::{NetwordSendReceive, NetworkConnection, NetworkError, NetworkOperation};
+
+use crate::{MqttError, UniqueID};
+
+/// Maximum number of payload bytes handed from the client to the event loop at once
+pub(crate) const STREAM_CHUNK_SIZE: usize = 128;
+
+/// Part of a streamed payload
+pub(crate) struct Chunk {
+    /// Transfer the chunk belongs to
+    generation: u32,
+    pub(crate) data: Vec<u8, STREAM_CHUNK_SIZE>
+}
+
+/// Transfers the payload of a streaming publish from the client to the event loop.
+///
+/// The event loop starts a transfer each time it writes the publish, the client then
+/// reads the payload from the beginning. Each transfer has its own generation,
+/// so chunks of an interrupted transfer are discarded.
+pub(crate) struct PayloadStream<M: RawMutex> {
+    /// Only one streaming publish is in progress at a time
+    lock: mutex::Mutex<M, ()>,
+
+    chunks: Channel<M, Chunk, 1>,
+
+    /// The event loop requests the payload for the transfer with the generation
+    start: Signal<M, u32>,
+
+    /// The client of the request stopped streaming
+    aborted: Signal<M, UniqueID>,
+
+    generation: Mutex<M, Cell<u32>>
+}
+
+impl <M: RawMutex> PayloadStream<M> {
+
+    pub(crate) fn new() -> Self {
+        Self {
+            lock: mutex::Mutex::new(()),
+            chunks: Channel::new(),
+            start: Signal::new(),
+            aborted: Signal::new(),
+            generation: Mutex::new(Cell::new(0))
+        }
+    }
+
+    /// Waits until no other streaming publish is in progress
+    pub(crate) async fn lock(&self) -> MutexGuard<'_, M, ()> {
+        self.lock.lock().await
+    }
+
+    /// Sends the `len` bytes of the payload of the request `id` from `source` to the event loop
+    /// each time the event loop requests them. Returns only if the payload cannot be read.
+    pub(crate) async fn serve<S: Read + Seek>(&self, id: UniqueID, source: &mut S, len: usize) -> MqttError {
+        // The event loop must not wait for chunks which never arrive
+        let _abort = AbortOnDrop { stream: self, id };
+
+        let start = match source.stream_position().await {
+            Ok(start) => start,
+            Err(e) => {
+                error!("cannot get position of payload source: {}", e.kind());
+                return MqttError::InvalidPayload;
+            }
+        };
+
+        let mut generation = self.start.wait().await;
+        loop {
+            match select(self.start.wait(), self.send_payload(source, start, len, generation)).await {
+                Either::First(restart) => {
+                    debug!("streaming payload of request {} restarted", id);
+                    generation = restart;
+                },
+                Either::Second(Ok(())) => {
+                    // Wait for retransmissions
+                    generation = self.start.wait().await;
+                },
+                Either::Second(Err(e)) => return e,
+            }
+        }
+    }
+
+    async fn send_payload<S: Read + Seek>(&self, source: &mut S, start: u64, len: usize, generation: u32) -> Result<(), MqttError> {
+        source.seek(SeekFrom::Start(start)).await
+            .map_err(|e| {
+                error!("cannot seek payload source: {}", e.kind());
+                MqttError::InvalidPayload
+            })?;
+
+        let mut remaining = len;
+        while remaining > 0 {
+            let mut chunk = Chunk {
+                generation,
+                data: Vec::new()
+            };
+            chunk.data.resize(remaining.min(STREAM_CHUNK_SIZE), 0).unwrap();
+
+            let n = source.read(&mut chunk.data).await
+                .map_err(|e| {
+                    error!("cannot read payload source: {}", e.kind());
+                    MqttError::InvalidPayload
+                })?;
+
+            if n == 0 {
+                error!("payload source ended {} bytes before the announced length", remaining);
+                return Err(MqttError::InvalidPayload);
+            }
+
+            chunk.data.truncate(n);
+            self.chunks.send(chunk).await;
+            remaining -= n;
+        }
+
+        Ok(())
+    }
+
+    /// Requests the payload from the client and returns the generation of the transfer.
+    /// An abort signalled before is kept, the client may have stopped before the transfer started.
+    pub(crate) fn start(&self) -> u32 {
+        let generation = self.generation.lock(|inner| {
+            let generation = inner.get().wrapping_add(1);
+            inner.set(generation);
+            generation
+        });
+
+        self.start.signal(generation);
+        generation
+    }
+
+    /// Receives the next chunk of the transfer `generation`
+    pub(crate) async fn receive(&self, generation: u32) -> Chunk {
+        loop {
+            let chunk = self.chunks.receive().await;
+            if chunk.generation == generation {
+                return chunk;
+            }
+            trace!("discarding chunk of interrupted transfer {}", chunk.generation);
+        }
+    }
+
+    /// Waits until the client of the request `id` stops streaming
+    pub(crate) async fn aborted(&self, id: UniqueID) {
+        while self.aborted.wait().await != id {}
+    }
+}
+
+struct AbortOnDrop<'a, M: RawMutex> {
+    stream: &'a PayloadStream<M>,
+    id: UniqueID
+}
+
+impl <'a, M: RawMutex> Drop for AbortOnDrop<'a, M> {
+    fn drop(&mut self) {
+        self.stream.aborted.signal(self.id);
+    }
+}
+
+/// Returns the position `pos` refers to in a payload of `len` bytes read up to `position`
+fn seek_position(position: usize, len: usize, pos: SeekFrom) -> Result<usize, ErrorKind> {
+    let (base, offset) = match pos {
+        SeekFrom::Start(offset) => return usize::try_from(offset).map_err(|_| ErrorKind::InvalidInput),
+        SeekFrom::End(offset) => (len, offset),
+        SeekFrom::Current(offset) => (position, offset),
+    };
+
+    isize::try_from(offset).ok()
+        .and_then(|offset| base.checked_add_signed(offset))
+        .ok_or(ErrorKind::InvalidInput)
+}
+
+/// Payload source of [`crate::client::MqttClient::publish_borrowed`] reading a borrowed slice
+pub(crate) struct SlicePayload<'p> {
+    data: &'p [u8],
+    position: usize
+}
+
+impl <'p> SlicePayload<'p> {
+    pub(crate) fn new(data: &'p [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+}
+
+impl <'p> ErrorType for SlicePayload<'p> {
+    type Error = ErrorKind;
+}
+
+impl <'p> Read for SlicePayload<'p> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let data = self.data.get(self.position..).unwrap_or_default();
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl <'p> Seek for SlicePayload<'p> {
+    async fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        self.position = seek_position(self.position, self.data.len(), pos)?;
+        Ok(self.position as u64)
+    }
+}
+
+/// Payload source of [`crate::client::MqttClient::publish_with`] filled by a function
+/// which writes the part of the payload starting at an offset
+pub(crate) struct WriterPayload<F: FnMut(usize, &mut [u8])> {
+    write: F,
+    len: usize,
+    position: usize
+}
+
+impl <F: FnMut(usize, &mut [u8])> WriterPayload<F> {
+    pub(crate) fn new(len: usize, write: F) -> Self {
+        Self { write, len, position: 0 }
+    }
+}
+
+impl <F: FnMut(usize, &mut [u8])> ErrorType for WriterPayload<F> {
+    type Error = ErrorKind;
+}
+
+impl <F: FnMut(usize, &mut [u8])> Read for WriterPayload<F> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let n = self.len.saturating_sub(self.position).min(buf.len());
+        if n > 0 {
+            (self.write)(self.position, &mut buf[..n]);
+        }
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl <F: FnMut(usize, &mut [u8])> Seek for WriterPayload<F> {
+    async fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        self.position = seek_position(self.position, self.len, pos)?;
+        Ok(self.position as u64)
+    }
+}
+
+/// Header of a received publish, the payload follows in the receive buffer
+pub(crate) struct PublishHeader<'a> {
+    /// The publish without payload
+    pub(crate) publish: Publish<'a>,
+
+    /// Length of fixed and variable header
+    pub(crate) header_len: usize,
+
+    pub(crate) payload_len: usize
+}
+
+/// Decodes the header of the publish at the start of `data` without the payload.
+/// Returns `None` if `data` does not start with a publish or the header is not complete yet.
+pub(crate) fn decode_publish_header(data: &[u8]) -> Result<Option<PublishHeader<'_>>, MqttError> {
+    if data.is_empty() || data[0] >> 4 != 3 {
+        return Ok(None);
+    }
+
+    let flags = data[0] & 0x0F;
+
+    let mut remaining_len = 0;
+    let mut pos = 1;
+    loop {
+        let Some(&byte) = data.get(pos) else {
+            return Ok(None);
+        };
+
+        remaining_len |= ((byte & 0x7F) as usize) << (7 * (pos - 1));
+        pos += 1;
+
+        if byte & 0x80 == 0 {
+            break;
+        }
+
+        if pos > 4 {
+            error!("invalid remaining length of received publish");
+            return Err(MqttError::CodecError);
+        }
+    }
+    let fixed_header_len = pos;
+
+    let Some(topic_len) = data.get(pos..pos + 2) else {
+        return Ok(None);
+    };
+    let topic_len = u16::from_be_bytes([topic_len[0], topic_len[1]]) as usize;
+    pos += 2;
+
+    let Some(topic_name) = data.get(pos..pos + topic_len) else {
+        return Ok(None);
+    };
+    let topic_name = core::str::from_utf8(topic_name)
+        .map_err(|_| {
+            error!("topic of received publish is not valid utf-8");
+            MqttError::CodecError
+        })?;
+    pos += topic_len;
+
+    let qospid = match (flags >> 1) & 0x03 {
+        0 => QosPid::AtMostOnce,
+        qos @ (1 | 2) => {
+            let Some(pid) = data.get(pos..pos + 2) else {
+                return Ok(None);
+            };
+            let pid = Pid::try_from(u16::from_be_bytes([pid[0], pid[1]]))
+                .map_err(|_| {
+                    error!("received publish with pid 0");
+                    MqttError::CodecError
+                })?;
+            pos += 2;
+
+            if qos == 1 {
+                QosPid::AtLeastOnce(pid)
+            } else {
+                QosPid::ExactlyOnce(pid)
+            }
+        },
+        _ => {
+            error!("received publish with invalid QoS");
+            return Err(MqttError::CodecError);
+        }
+    };
+
+    let variable_header_len = pos - fixed_header_len;
+    if remaining_len < variable_header_len {
+        error!("remaining length of received publish is shorter than its header");
+        return Err(MqttError::CodecError);
+    }
+
+    Ok(Some(PublishHeader {
+        publish: Publish {
+            dup: flags & 0x08 != 0,
+            qospid,
+            retain: flags & 0x01 != 0,
+            topic_name,
+            payload: &[]
+        },
+        header_len: pos,
+        payload_len: remaining_len - variable_header_len
+    }))
+}
+
+/// Checks if `topic` matches the topic filter `filter` with the wildcards `+` and `#`
+pub(crate) fn topic_matches(filter: &str, topic: &str) -> bool {
+    // Topics starting with $ are not matched by wildcards at the first level
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {},
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => {},
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Reads the payload of a streamed publish from the receive buffer
+/// and from the network once the receive buffer is empty
+pub(crate) struct PayloadReader<'a, N: NetworkConnection, const RB: usize> {
+    connection: &'a mut N,
+    recv_buffer: &'a mut Buffer<[u8; RB]>,
+    remaining: usize
+}
+
+impl <'a, N: NetworkConnection, const RB: usize> PayloadReader<'a, N, RB> {
+
+    pub(crate) fn new(connection: &'a mut N, recv_buffer: &'a mut Buffer<[u8; RB]>, len: usize) -> Self {
+        Self {
+            connection,
+            recv_buffer,
+            remaining: len
+        }
+    }
+
+    /// Discards the part of the payload which was not read
+    pub(crate) async fn skip_remaining(&mut self) -> Result<(), MqttError> {
+        while self.remaining > 0 {
+            self.fill().await?;
+
+            let n = self.recv_buffer.remaining_len().min(self.remaining);
+            self.recv_buffer.skip(n).unwrap();
+            self.remaining -= n;
+        }
+
+        Ok(())
+    }
+
+    /// Receives from the network if the receive buffer is empty
+    async fn fill(&mut self) -> Result<(), MqttError> {
+        if self.recv_buffer.has_remaining_len() {
+            return Ok(());
+        }
+
+        let n = self.connection.receive(self.recv_buffer).await
+            .map_err(MqttError::ConnectionFailed)?;
+
+        if n == 0 {
+            warn!("connection closed with {} bytes of streamed payload left", self.remaining);
+            return Err(MqttError::ConnectionFailed(NetworkError::Closed(NetworkOperation::Read)));
+        }
+
+        Ok(())
+    }
+}
+
+impl <'a, N: NetworkConnection, const RB: usize> ErrorType for PayloadReader<'a, N, RB> {
+    type Error = MqttError;
+}
+
+impl <'a, N: NetworkConnection, const RB: usize> Read for PayloadReader<'a, N, RB> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.fill().await?;
+
+        let data = self.recv_buffer.data();
+        let n = data.len().min(buf.len()).min(self.remaining);
+        buf[..n].copy_from_slice(&data[..n]);
+
+        self.recv_buffer.skip(n).unwrap();
+        self.remaining -= n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::select::{select, Either};
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+    use embedded_io_async::{ErrorKind, ErrorType, Read, Seek, SeekFrom};
+    use mqttrs::{encode_slice, Packet, Pid, Publish, QosPid};
+
+    use crate::{MqttError, UniqueID};
+
+    use super::{decode_publish_header, topic_matches, PayloadStream, SlicePayload, WriterPayload, STREAM_CHUNK_SIZE};
+
+    struct Source {
+        data: [u8; 300],
+        position: usize,
+        len: usize
+    }
+
+    impl ErrorType for Source {
+        type Error = ErrorKind;
+    }
+
+    impl Read for Source {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let n = buf.len().min(self.len - self.position);
+            buf[..n].copy_from_slice(&self.data[self.position..self.position + n]);
+            self.position += n;
+            Ok(n)
+        }
+    }
+
+    impl Seek for Source {
+        async fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+            match pos {
+                SeekFrom::Start(offset) => self.position = offset as usize,
+                SeekFrom::Current(offset) => self.position = (self.position as i64 + offset) as usize,
+                SeekFrom::End(_) => return Err(ErrorKind::Unsupported),
+            }
+            Ok(self.position as u64)
+        }
+    }
+
+    fn source(len: usize) -> Source {
+        let mut data = [0; 300];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+
+        // The payload starts at the position of the source
+        Source { data, position: 10, len }
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_restart_transfer() {
+        let stream = PayloadStream::<CriticalSectionRawMutex>::new();
+        let id = UniqueID(1);
+        let mut source = source(300);
+
+        let event_loop = async {
+            let first = stream.start();
+            let chunk = stream.receive(first).await;
+            assert_eq!(&chunk.data[..], &source_data()[10..10 + STREAM_CHUNK_SIZE]);
+
+            // Retransmission reads the payload again from the start position
+            let second = stream.start();
+            let mut received = std::vec::Vec::new();
+            while received.len() < 200 {
+                received.extend_from_slice(&stream.receive(second).await.data);
+            }
+            assert_eq!(&received[..], &source_data()[10..210]);
+        };
+
+        match select(event_loop, stream.serve(id, &mut source, 200)).await {
+            Either::First(()) => {},
+            Either::Second(e) => panic!("serving payload failed: {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_source_too_short() {
+        let stream = PayloadStream::<CriticalSectionRawMutex>::new();
+        let id = UniqueID(1);
+        let mut source = source(100);
+
+        let generation = stream.start();
+        let serve = async {
+            assert_eq!(stream.serve(id, &mut source, 200).await, MqttError::InvalidPayload);
+        };
+
+        let event_loop = async {
+            let mut received = 0;
+            while let Either::First(chunk) = select(stream.receive(generation), stream.aborted(id)).await {
+                received += chunk.data.len();
+            }
+            assert_eq!(received, 90);
+        };
+
+        tokio::join!(serve, event_loop);
+    }
+
+    #[tokio::test]
+    #[ntest::timeout(5000)]
+    async fn test_abort_before_start() {
+        let stream = PayloadStream::<CriticalSectionRawMutex>::new();
+        let id = UniqueID(1);
+        let mut source = source(100);
+
+        // The client stops before the event loop starts the transfer
+        {
+            let serve = stream.serve(id, &mut source, 50);
+            tokio::pin!(serve);
+            assert!(embassy_futures::poll_once(serve.as_mut()).is_pending());
+        }
+
+        let generation = stream.start();
+        match select(stream.receive(generation), stream.aborted(id)).await {
+            Either::First(_) => panic!("no chunk expected"),
+            Either::Second(()) => {},
+        }
+    }
+
+    #[tokio::test]
+    async fn test_payload_sources() {
+        let data = source_data();
+
+        let mut slice = SlicePayload::new(&data[..100]);
+        let mut writer = WriterPayload::new(100, |offset, buf: &mut [u8]| {
+            buf.copy_from_slice(&data[offset..offset + buf.len()]);
+        });
+
+        let mut buf = [0u8; 64];
+        assert_eq!(slice.read(&mut buf).await, Ok(64));
+        assert_eq!(&buf[..], &data[..64]);
+        assert_eq!(slice.read(&mut buf).await, Ok(36));
+        assert_eq!(&buf[..36], &data[64..100]);
+        assert_eq!(slice.read(&mut buf).await, Ok(0));
+
+        assert_eq!(writer.seek(SeekFrom::End(-10)).await, Ok(90));
+        assert_eq!(writer.read(&mut buf).await, Ok(10));
+        assert_eq!(&buf[..10], &data[90..100]);
+        assert_eq!(writer.read(&mut buf).await, Ok(0));
+
+        assert_eq!(slice.seek(SeekFrom::Start(10)).await, Ok(10));
+        assert_eq!(slice.seek(SeekFrom::Current(-5)).await, Ok(5));
+        assert_eq!(slice.seek(SeekFrom::Current(-6)).await, Err(ErrorKind::InvalidInput));
+        assert_eq!(slice.read(&mut buf[..3]).await, Ok(3));
+        assert_eq!(&buf[..3], &data[5..8]);
+
+        assert_eq!(writer.seek(SeekFrom::Start(0)).await, Ok(0));
+        assert_eq!(writer.read(&mut buf).await, Ok(64));
+        assert_eq!(&buf[..], &data[..64]);
+    }
+
+    fn source_data() -> [u8; 300] {
+        source(300).data
+    }
+
+    #[test]
+    fn test_decode_publish_header() {
+        let payload = [7u8; 300];
+        let publish = Publish {
+            dup: true,
+            qospid: QosPid::ExactlyOnce(Pid::try_from(42).unwrap()),
+            retain: true,
+            topic_name: "config/blob",
+            payload: &payload
+        };
+
+        let mut buf = [0; 400];
+        let len = encode_slice(&Packet::Publish(publish.clone()), &mut buf).unwrap();
+
+        // 1 byte packet type, 2 bytes remaining length, 2 + 11 bytes topic, 2 bytes pid
+        let header = decode_publish_header(&buf[..18]).unwrap().unwrap();
+        assert_eq!(header.header_len, 18);
+        assert_eq!(header.payload_len, 300);
+        assert_eq!(header.header_len + header.payload_len, len);
+        assert_eq!(header.publish, Publish { payload: &[], ..publish });
+
+        assert!(decode_publish_header(&buf[..17]).unwrap().is_none());
+
+        let len = encode_slice(&Packet::Pingresp, &mut buf).unwrap();
+        assert!(decode_publish_header(&buf[..len]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("config/blob", "config/blob"));
+        assert!(topic_matches("config/#", "config/blob/part"));
+        assert!(topic_matches("config/#", "config"));
+        assert!(topic_matches("+/blob", "config/blob"));
+        assert!(topic_matches("#", "config/blob"));
+
+        assert!(! topic_matches("config/blob", "config/other"));
+        assert!(! topic_matches("+/blob", "config/blob/part"));
+        assert!(! topic_matches("config/+", "config"));
+        assert!(! topic_matches("#", "$SYS/uptime"));
+    }
+}
@@ -2,10 +2,14 @@
 /// functions dynamicly
 
 #[cfg(feature = "embassy")]
-pub(crate) use embassy_time::{Duration, Instant};
+pub use embassy_time::Duration;
+#[cfg(feature = "embassy")]
+pub(crate) use embassy_time::Instant;
 
 #[cfg(feature = "std")]
-pub(crate) use std::time::{Duration, Instant};
+pub use std::time::Duration;
+#[cfg(feature = "std")]
+pub(crate) use std::time::Instant;
 
 pub(crate) fn now() -> Instant {
     #[cfg(not(test))]
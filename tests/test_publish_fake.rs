@@ -2,11 +2,12 @@
 
 use std::{cell::RefCell, pin::Pin};
 
-use network::{fake::{new_connection, ClientConnection, ConnectionRessources, ReadAtomic, ServerConnection}, mqtt::WriteMqttPacket};
+use network::{fake::{new_connection, ClientConnection, ConnectionRessources, Fault, FaultScript, FaultStep, FaultyConnection, ReadAtomic, ServerConnection}, mqtt::WriteMqttPacket, NetworkOperation};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embedded_io_async::Read;
-use mqttrs::{decode_slice, Connack, ConnectReturnCode, Packet, PacketType, QoS};
-use embassy_mqtt::{client::MqttClient, io::MqttEventLoop, ClientConfig, ClientCredentials};
+use embedded_io_async::{Read, Write};
+use mqttrs::{decode_slice, encode_slice, Connack, ConnectReturnCode, Packet, PacketType, Pid, Publish, QoS, QosPid};
+
+use embassy_mqtt::{client::MqttClient, io::{MqttEventLoop, PublishHandler, StreamedPublish}, ClientConfig, ClientCredentials, Duration, MqttError, MqttEvent, DEFAULT_RECEIVE_MAXIMUM};
 use heapless::Vec;
 
 struct Test <'a, const N: usize> {
@@ -21,13 +22,15 @@ impl <'a, const N: usize> Test<'a, N> {
         let mut config = ClientConfig {
             client_id: heapless::String::new(), 
             credentials, 
-            auto_subscribes: Vec::new()
+            auto_subscribes: Vec::new(),
+            retry_policy: Default::default(),
+            receive_maximum: DEFAULT_RECEIVE_MAXIMUM
         };
 
         config.client_id.push_str(client_id).unwrap();
 
         let (client, server) = new_connection(resources);
-        let event_loop = MqttEventLoop::<CriticalSectionRawMutex, N>::new(config);
+        let event_loop = MqttEventLoop::<CriticalSectionRawMutex, N>::new(config).unwrap();
 
         Self {
             server,
@@ -69,10 +72,12 @@ async fn test_connect() {
     let config = ClientConfig{
         client_id,
         credentials: None,
-        auto_subscribes: Vec::new()
+        auto_subscribes: Vec::new(),
+        retry_policy: Default::default(),
+        receive_maximum: DEFAULT_RECEIVE_MAXIMUM
     };
 
-    let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(config);
+    let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(config).unwrap();
 
     let work_future = async {
         event_loop.run(client).await.unwrap();
@@ -145,3 +150,1157 @@ async fn test_publish() {
     };
 }
 
+
+#[tokio::test]
+#[ntest::timeout(1000)]
+async fn test_publish_with_idle_event_listener() {
+    let resources = ConnectionRessources::<256>::new();
+
+    let test = Test::create("1234567890", None, &resources);
+
+    let client = test.create_client();
+    // Never reads the events, more results arrive than the event channel holds
+    let _events = client.events().unwrap();
+
+    let work_future = test.run();
+
+    let client_future = async {
+        for _ in 0..8 {
+            client.publish("topic", "a test payload".as_bytes(), QoS::AtLeastOnce, false).await.unwrap();
+        }
+        client.disconnect().await;
+    };
+
+    let server_future = async {
+
+        test.read_packet(|p| {
+            assert_eq!(p.get_type(), PacketType::Connect);
+        }).await;
+
+        test.write_packet(Packet::Connack(Connack{
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await;
+
+        for _ in 0..8 {
+            let pid = test.read_packet(|p|{
+                if let Packet::Publish(p) = p {
+                    p.qospid.pid().unwrap()
+                } else {
+                    panic!("expected publish");
+                }
+            }).await;
+
+            test.write_packet(Packet::Puback(pid)).await;
+        }
+    };
+
+    tokio::join! {
+        server_future,
+        client_future,
+        work_future
+    };
+}
+
+#[tokio::test]
+#[ntest::timeout(2000)]
+async fn test_publish_with_timeout() {
+    let resources = ConnectionRessources::<256>::new();
+    
+    let test = Test::create("1234567890", None, &resources);
+
+    let client = test.create_client();
+
+    let work_future = test.run();
+
+    let client_future = async {
+        let result = client.publish_with_timeout("topic", "no ack".as_bytes(), QoS::AtLeastOnce, false, Duration::from_millis(100)).await;
+        assert_eq!(result, Err(MqttError::Timeout));
+
+        // The result of the timed out publish must not disturb the next request
+        client.publish("topic", "second".as_bytes(), QoS::AtMostOnce, false).await.unwrap();
+        client.disconnect().await;
+    };
+
+    let server_future = async {
+
+        test.read_packet(|p| {
+            assert_eq!(p.get_type(), PacketType::Connect);
+        }).await;
+
+        test.write_packet(Packet::Connack(Connack{
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await;
+
+        // The broker never acknowledges the first publish
+        test.read_packet(|p|{
+            if let Packet::Publish(p) = p {
+                assert_eq!(p.payload, b"no ack");
+            } else {
+                panic!("expected publish");
+            }
+        }).await;
+
+        test.read_packet(|p|{
+            if let Packet::Publish(p) = p {
+                assert_eq!(p.payload, b"second");
+            } else {
+                panic!("expected publish");
+            }
+        }).await;
+    };
+
+    tokio::join! {
+        server_future,
+        client_future,
+        work_future
+    };
+}
+
+#[tokio::test]
+#[ntest::timeout(1000)]
+async fn test_try_publish_queue_full() {
+    let resources = ConnectionRessources::<256>::new();
+    
+    let test = Test::create("1234567890", None, &resources);
+    let client = test.create_client();
+
+    // The event loop is not running, so the request queue fills up
+    let mut ids = Vec::<_, 8>::new();
+    loop {
+        match client.try_publish("topic", b"payload", QoS::AtMostOnce, false) {
+            Ok(id) => ids.push(id).unwrap(),
+            Err(e) => {
+                assert_eq!(e, MqttError::BufferFull);
+                break;
+            }
+        }
+    }
+
+    assert!(! ids.is_empty());
+    assert!(ids.iter().enumerate().all(|(i, id)| ! ids[i + 1..].contains(id)));
+}
+
+#[tokio::test]
+#[ntest::timeout(1000)]
+async fn test_publish_detached() {
+    let resources = ConnectionRessources::<256>::new();
+    
+    let test = Test::create("1234567890", None, &resources);
+
+    let client = test.create_client();
+
+    let work_future = test.run();
+
+    let client_future = async {
+        let mut events = client.events().unwrap();
+        let id = client.publish_detached("topic", b"payload", QoS::AtLeastOnce, false).await.unwrap();
+
+        let event = events.on(|event| event.request_id() == Some(id)).await;
+        assert_eq!(event, MqttEvent::PublishResult(id, Ok(())));
+
+        client.disconnect().await;
+    };
+
+    let server_future = async {
+
+        test.read_packet(|p| {
+            assert_eq!(p.get_type(), PacketType::Connect);
+        }).await;
+
+        test.write_packet(Packet::Connack(Connack{
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await;
+
+        let pid = test.read_packet(|p|{
+            if let Packet::Publish(p) = p {
+                p.qospid.pid().unwrap()
+            } else {
+                panic!("expected publish");
+            }
+        }).await;
+
+        test.write_packet(Packet::Puback(pid)).await;
+    };
+
+    tokio::join! {
+        server_future,
+        client_future,
+        work_future
+    };
+}
+
+#[tokio::test]
+#[ntest::timeout(1000)]
+async fn test_custom_capacities() {
+    let resources = ConnectionRessources::<256>::new();
+    let (mut client, server) = new_connection(&resources);
+    let client = Pin::new(&mut client);
+
+    let config = ClientConfig::new("1234567890", None).unwrap();
+
+    // Small receive buffer and a request channel with a single slot
+    let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 512, 64, 2, 2, 2, 2, 1>::new(config).unwrap();
+    let mqtt_client = event_loop.client();
+
+    mqtt_client.try_publish("topic", b"first", QoS::AtMostOnce, false).unwrap();
+    assert_eq!(mqtt_client.try_publish("topic", b"second", QoS::AtMostOnce, false), Err(MqttError::BufferFull));
+
+    let work_future = async {
+        event_loop.run(client).await.unwrap();
+    };
+
+    let server_future = async {
+        server.read_mqtt_packet(|p| assert_eq!(p.get_type(), PacketType::Connect)).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Connack(Connack{
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await.unwrap();
+
+        server.read_mqtt_packet(|p| assert_eq!(p.get_type(), PacketType::Publish)).await.unwrap();
+
+        // Space for the next request is free again
+        mqtt_client.try_publish("topic", b"second", QoS::AtMostOnce, false).unwrap();
+        mqtt_client.disconnect().await;
+    };
+
+    tokio::join! {
+        server_future,
+        work_future
+    };
+}
+
+#[tokio::test]
+#[ntest::timeout(1000)]
+async fn test_small_publish_capacities() {
+    let resources = ConnectionRessources::<256>::new();
+    let (mut client, server) = new_connection(&resources);
+    let client = Pin::new(&mut client);
+
+    let config = ClientConfig::new("1234567890", None).unwrap();
+
+    // Topics up to 16 and payloads up to 8 bytes
+    let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 512, 512, 2, 2, 2, 2, 2, 16, 8>::new(config).unwrap();
+    let mqtt_client = event_loop.client();
+
+    let work_future = async {
+        event_loop.run(client).await.unwrap();
+    };
+
+    let client_future = async {
+        let publish = mqtt_client.receive().await;
+        assert_eq!(publish.topic.as_str(), "short/topic");
+        assert_eq!(publish.payload.data(), b"payload");
+
+        mqtt_client.disconnect().await;
+    };
+
+    let server_future = async {
+        server.read_mqtt_packet(|p| assert_eq!(p.get_type(), PacketType::Connect)).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Connack(Connack{
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await.unwrap();
+
+        // Dropped, the topic does not fit
+        server.write_mqtt_packet(&Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "a/topic/longer/than/16",
+            payload: b"payload"
+        })).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "short/topic",
+            payload: b"payload"
+        })).await.unwrap();
+    };
+
+    tokio::join! {
+        server_future,
+        client_future,
+        work_future
+    };
+}
+
+#[tokio::test]
+#[ntest::timeout(1000)]
+async fn test_receive_qos_1_topic_too_long() {
+    let resources = ConnectionRessources::<256>::new();
+    let (mut client, server) = new_connection(&resources);
+    let client = Pin::new(&mut client);
+
+    let config = ClientConfig::new("1234567890", None).unwrap();
+
+    // Topics up to 16 and payloads up to 8 bytes
+    let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 512, 512, 2, 2, 2, 2, 2, 16, 8>::new(config).unwrap();
+    let mqtt_client = event_loop.client();
+
+    let work_future = async {
+        event_loop.run(client).await.unwrap();
+    };
+
+    let client_future = async {
+        let publish = mqtt_client.receive().await;
+        assert_eq!(publish.topic.as_str(), "short/topic");
+    };
+
+    let server_future = async {
+        server.read_mqtt_packet(|p| assert_eq!(p.get_type(), PacketType::Connect)).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Connack(Connack{
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await.unwrap();
+
+        // Neither delivered nor acknowledged, the topic does not fit
+        server.write_mqtt_packet(&Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtLeastOnce(Pid::try_from(1).unwrap()),
+            retain: false,
+            topic_name: "a/topic/longer/than/16",
+            payload: b"payload"
+        })).await.unwrap();
+
+        // Neither delivered nor acknowledged, the payload does not fit
+        server.write_mqtt_packet(&Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtLeastOnce(Pid::try_from(2).unwrap()),
+            retain: false,
+            topic_name: "short/topic",
+            payload: b"a long payload"
+        })).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtLeastOnce(Pid::try_from(3).unwrap()),
+            retain: false,
+            topic_name: "short/topic",
+            payload: b"payload"
+        })).await.unwrap();
+
+        server.read_mqtt_packet(|p| assert_eq!(*p, Packet::Puback(Pid::try_from(3).unwrap()))).await.unwrap();
+
+        mqtt_client.disconnect().await;
+    };
+
+    tokio::join! {
+        server_future,
+        client_future,
+        work_future
+    };
+}
+
+#[test]
+fn test_config_too_long() {
+    let long_topic = "v1/devices/me/attributes/response/+/with/a/topic/longer/than/64/bytes";
+    assert!(long_topic.len() > 64);
+
+    let config = ClientConfig::<128>::new_with_auto_subscribes("1234567890", None, [long_topic].into_iter(), QoS::AtLeastOnce).unwrap();
+    assert_eq!(config.auto_subscribes[0].topic.as_str(), long_topic);
+
+    let result = ClientConfig::<64>::new_with_auto_subscribes("1234567890", None, [long_topic].into_iter(), QoS::AtLeastOnce);
+    assert!(matches!(result, Err(MqttError::TooLong)));
+
+    let result = ClientConfig::<64>::new_with_auto_subscribes("1234567890", None, ["a", "b", "c", "d", "e"].into_iter(), QoS::AtLeastOnce);
+    assert!(matches!(result, Err(MqttError::TooManyAutoSubscribes)));
+
+    assert!(matches!(ClientConfig::<64>::new(&"x".repeat(129), None), Err(MqttError::TooLong)));
+    assert!(matches!(ClientCredentials::new("user", &"x".repeat(129)), Err(MqttError::TooLong)));
+}
+
+#[tokio::test]
+async fn test_publish_too_long() {
+    let resources = ConnectionRessources::<256>::new();
+    let test = Test::create("1234567890", None, &resources);
+    let client = test.create_client();
+
+    let long_topic = "x".repeat(65);
+    assert_eq!(client.publish(&long_topic, b"payload", QoS::AtMostOnce, false).await, Err(MqttError::TooLong));
+    assert_eq!(client.subscribe(&long_topic).await, Err(MqttError::TooLong));
+
+    let long_payload = [0u8; 1025];
+    assert_eq!(client.try_publish("topic", &long_payload, QoS::AtMostOnce, false), Err(MqttError::TooLong));
+}
+
+#[test]
+fn test_events_all_slots_in_use() {
+    let resources = ConnectionRessources::<256>::new();
+    let test = Test::create("1234567890", None, &resources);
+    let client = test.create_client();
+
+    let mut subscriptions = std::vec::Vec::new();
+    while let Ok(events) = client.events() {
+        subscriptions.push(events);
+    }
+
+    assert!(!subscriptions.is_empty());
+    assert!(matches!(client.events(), Err(MqttError::TooManyListeners)));
+
+    subscriptions.pop();
+    assert!(client.events().is_ok());
+}
+
+#[tokio::test]
+#[ntest::timeout(1000)]
+async fn test_run_with_handler() {
+    let resources = ConnectionRessources::<256>::new();
+    let (mut client, server) = new_connection(&resources);
+    let client = Pin::new(&mut client);
+
+    let config = ClientConfig::new("1234567890", None).unwrap();
+
+    // Payloads are handed to the handler without a copy, so they may exceed the payload capacity
+    let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 256, 256, 2, 2, 2, 2, 2, 16, 4>::new(config).unwrap();
+    let mqtt_client = event_loop.client();
+
+    let received = RefCell::new(std::vec::Vec::new());
+    let handler = |publish: &Publish<'_>| {
+        received.borrow_mut().push((publish.topic_name.to_owned(), publish.payload.to_vec()));
+    };
+
+    let work_future = async {
+        event_loop.run_with_handler(client, &handler).await.unwrap();
+    };
+
+    let server_future = async {
+        server.read_mqtt_packet(|p| assert_eq!(p.get_type(), PacketType::Connect)).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Connack(Connack{
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "first",
+            payload: b"a long payload"
+        })).await.unwrap();
+
+        let pid = Pid::try_from(7).unwrap();
+        server.write_mqtt_packet(&Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtLeastOnce(pid),
+            retain: false,
+            topic_name: "second",
+            payload: b"qos 1"
+        })).await.unwrap();
+
+        server.read_mqtt_packet(|p| assert_eq!(p, &Packet::Puback(pid))).await.unwrap();
+
+        mqtt_client.disconnect().await;
+    };
+
+    tokio::join! {
+        server_future,
+        work_future
+    };
+
+    assert_eq!(received.into_inner(), [
+        ("first".to_owned(), b"a long payload".to_vec()),
+        ("second".to_owned(), b"qos 1".to_vec())
+    ]);
+}
+
+#[tokio::test]
+#[ntest::timeout(1000)]
+async fn test_publish_borrowed() {
+    let resources = ConnectionRessources::<256>::new();
+    let (mut client, server) = new_connection(&resources);
+    let client = Pin::new(&mut client);
+
+    let config = ClientConfig::new("1234567890", None).unwrap();
+
+    // Borrowed payloads are not limited by the payload capacity
+    let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 256, 256, 2, 2, 2, 2, 2, 16, 0>::new(config).unwrap();
+    let mqtt_client = event_loop.client();
+
+    let payload = [42u8; 100];
+
+    let work_future = async {
+        event_loop.run(client).await.unwrap();
+    };
+
+    let client_future = async {
+        mqtt_client.publish_borrowed("borrowed", &payload, QoS::AtLeastOnce, false).await.unwrap();
+
+        mqtt_client.publish_with("written", 5, QoS::AtMostOnce, false, |offset, buf| {
+            buf.copy_from_slice(&b"hello"[offset..offset + buf.len()]);
+        }).await.unwrap();
+
+        mqtt_client.disconnect().await;
+    };
+
+    let server_future = async {
+        server.read_mqtt_packet(|p| assert_eq!(p.get_type(), PacketType::Connect)).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Connack(Connack{
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await.unwrap();
+
+        let pid = server.read_mqtt_packet(|p| {
+            if let Packet::Publish(p) = p {
+                assert_eq!(p.topic_name, "borrowed");
+                assert_eq!(p.payload, &payload);
+                p.qospid.pid().unwrap()
+            } else {
+                panic!("expected publish");
+            }
+        }).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Puback(pid)).await.unwrap();
+
+        server.read_mqtt_packet(|p| {
+            if let Packet::Publish(p) = p {
+                assert_eq!(p.topic_name, "written");
+                assert_eq!(p.payload, b"hello");
+            } else {
+                panic!("expected publish");
+            }
+        }).await.unwrap();
+    };
+
+    tokio::join! {
+        server_future,
+        client_future,
+        work_future
+    };
+}
+
+/// Payload source reading at most 10 bytes at once
+struct SlowSource<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl <'a> embedded_io_async::ErrorType for SlowSource<'a> {
+    type Error = embedded_io_async::ErrorKind;
+}
+
+impl <'a> Read for SlowSource<'a> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let n = buf.len().min(10).min(self.data.len() - self.position);
+        buf[..n].copy_from_slice(&self.data[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl <'a> embedded_io_async::Seek for SlowSource<'a> {
+    async fn seek(&mut self, pos: embedded_io_async::SeekFrom) -> Result<u64, Self::Error> {
+        match pos {
+            embedded_io_async::SeekFrom::Start(offset) => self.position = offset as usize,
+            embedded_io_async::SeekFrom::Current(offset) => self.position = (self.position as i64 + offset) as usize,
+            embedded_io_async::SeekFrom::End(offset) => self.position = (self.data.len() as i64 + offset) as usize
+        }
+        Ok(self.position as u64)
+    }
+}
+
+#[tokio::test]
+#[ntest::timeout(1000)]
+async fn test_publish_stream() {
+    let resources = ConnectionRessources::<2048>::new();
+    let (mut client, server) = new_connection(&resources);
+    let client = Pin::new(&mut client);
+
+    let config = ClientConfig::new("1234567890", None).unwrap();
+
+    // The streamed payload is larger than the send buffer
+    let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 64, 256, 2, 2, 2, 2, 2, 16, 0>::new(config).unwrap();
+    let mqtt_client = event_loop.client();
+
+    let payload: std::vec::Vec<u8> = (0..1000).map(|i| i as u8).collect();
+
+    let work_future = async {
+        event_loop.run(client).await.unwrap();
+    };
+
+    let client_future = async {
+        let mut source = SlowSource { data: &payload, position: 0 };
+        mqtt_client.publish_stream("streamed", payload.len(), QoS::AtLeastOnce, false, &mut source).await.unwrap();
+
+        mqtt_client.publish_borrowed("after", b"stream", QoS::AtMostOnce, false).await.unwrap();
+
+        mqtt_client.disconnect().await;
+    };
+
+    let server_future = async {
+        server.read_mqtt_packet(|p| assert_eq!(p.get_type(), PacketType::Connect)).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Connack(Connack{
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await.unwrap();
+
+        let pid = server.read_mqtt_packet(|p| {
+            if let Packet::Publish(p) = p {
+                assert_eq!(p.topic_name, "streamed");
+                assert_eq!(p.payload, &payload[..]);
+                p.qospid.pid().unwrap()
+            } else {
+                panic!("expected publish");
+            }
+        }).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Puback(pid)).await.unwrap();
+
+        server.read_mqtt_packet(|p| {
+            if let Packet::Publish(p) = p {
+                assert_eq!(p.topic_name, "after");
+                assert_eq!(p.payload, b"stream");
+            } else {
+                panic!("expected publish");
+            }
+        }).await.unwrap();
+    };
+
+    tokio::join! {
+        server_future,
+        client_future,
+        work_future
+    };
+}
+
+/// Payload source failing on the first read
+struct FailingSource;
+
+impl embedded_io_async::ErrorType for FailingSource {
+    type Error = embedded_io_async::ErrorKind;
+}
+
+impl Read for FailingSource {
+    async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Err(embedded_io_async::ErrorKind::Other)
+    }
+}
+
+impl embedded_io_async::Seek for FailingSource {
+    async fn seek(&mut self, _pos: embedded_io_async::SeekFrom) -> Result<u64, Self::Error> {
+        Ok(0)
+    }
+}
+
+#[tokio::test]
+#[ntest::timeout(1000)]
+async fn test_publish_stream_source_fails() {
+    let resources = ConnectionRessources::<2048>::new();
+    let (mut client, server) = new_connection(&resources);
+    let client = Pin::new(&mut client);
+
+    let config = ClientConfig::new("1234567890", None).unwrap();
+    let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 64, 256, 2, 2, 2, 2, 2, 16, 0>::new(config).unwrap();
+    let mqtt_client = event_loop.client();
+
+    let work_future = async {
+        event_loop.run(client).await.unwrap();
+    };
+
+    let client_future = async {
+        let result = mqtt_client.publish_stream("streamed", 100, QoS::AtLeastOnce, false, &mut FailingSource).await;
+        assert_eq!(result, Err(MqttError::InvalidPayload));
+
+        // Only the publish failed, the connection is kept
+        mqtt_client.publish_borrowed("after", b"stream", QoS::AtMostOnce, false).await.unwrap();
+
+        mqtt_client.disconnect().await;
+    };
+
+    let server_future = async {
+        server.read_mqtt_packet(|p| assert_eq!(p.get_type(), PacketType::Connect)).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Connack(Connack{
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await.unwrap();
+
+        server.read_mqtt_packet(|p| {
+            if let Packet::Publish(p) = p {
+                assert_eq!(p.topic_name, "after");
+                assert_eq!(p.payload, b"stream");
+            } else {
+                panic!("expected publish");
+            }
+        }).await.unwrap();
+    };
+
+    tokio::join! {
+        server_future,
+        client_future,
+        work_future
+    };
+}
+
+/// Payload source which reads nothing until it is opened
+struct GatedSource<'a> {
+    gate: &'a embassy_sync::signal::Signal<CriticalSectionRawMutex, ()>,
+    source: SlowSource<'a>
+}
+
+impl <'a> embedded_io_async::ErrorType for GatedSource<'a> {
+    type Error = embedded_io_async::ErrorKind;
+}
+
+impl <'a> Read for GatedSource<'a> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if ! self.gate.signaled() {
+            self.gate.wait().await;
+            self.gate.signal(());
+        }
+        self.source.read(buf).await
+    }
+}
+
+impl <'a> embedded_io_async::Seek for GatedSource<'a> {
+    async fn seek(&mut self, pos: embedded_io_async::SeekFrom) -> Result<u64, Self::Error> {
+        self.source.seek(pos).await
+    }
+}
+
+#[tokio::test]
+#[ntest::timeout(1000)]
+async fn test_publish_stream_receives_acks() {
+    let resources = ConnectionRessources::<2048>::new();
+    let (mut client, server) = new_connection(&resources);
+    let client = Pin::new(&mut client);
+
+    let config = ClientConfig::new("1234567890", None).unwrap();
+    let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 64, 256, 2, 2, 2, 2, 2, 16, 16>::new(config).unwrap();
+    let mqtt_client = event_loop.client();
+
+    let payload: std::vec::Vec<u8> = (0..300).map(|i| i as u8).collect();
+    let gate = embassy_sync::signal::Signal::new();
+
+    let work_future = async {
+        event_loop.run(client).await.unwrap();
+    };
+
+    let client_future = async {
+        let mut events = mqtt_client.events().unwrap();
+        let id = mqtt_client.publish_detached("first", b"acked", QoS::AtLeastOnce, false).await.unwrap();
+
+        let stream = async {
+            let mut source = GatedSource { gate: &gate, source: SlowSource { data: &payload, position: 0 } };
+            mqtt_client.publish_stream("streamed", payload.len(), QoS::AtMostOnce, false, &mut source).await.unwrap();
+        };
+
+        // The ack of the first publish arrives while the streamed publish waits for its payload
+        let ack = async {
+            let event = events.on(|event| event.request_id() == Some(id)).await;
+            assert_eq!(event, MqttEvent::PublishResult(id, Ok(())));
+            gate.signal(());
+        };
+
+        tokio::join!(stream, ack);
+        mqtt_client.disconnect().await;
+    };
+
+    let server_future = async {
+        server.read_mqtt_packet(|p| assert_eq!(p.get_type(), PacketType::Connect)).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Connack(Connack{
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await.unwrap();
+
+        let pid = server.read_mqtt_packet(|p| {
+            if let Packet::Publish(p) = p {
+                assert_eq!(p.topic_name, "first");
+                p.qospid.pid().unwrap()
+            } else {
+                panic!("expected publish");
+            }
+        }).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Puback(pid)).await.unwrap();
+
+        server.read_mqtt_packet(|p| {
+            if let Packet::Publish(p) = p {
+                assert_eq!(p.topic_name, "streamed");
+                assert_eq!(p.payload, &payload[..]);
+            } else {
+                panic!("expected publish");
+            }
+        }).await.unwrap();
+    };
+
+    tokio::join! {
+        server_future,
+        client_future,
+        work_future
+    };
+}
+
+/// Streams the publishes to `config/#` and keeps everything received
+#[derive(Default)]
+struct StreamingHandler {
+    streamed: RefCell<std::vec::Vec<(String, std::vec::Vec<u8>)>>,
+    received: RefCell<std::vec::Vec<String>>
+}
+
+impl PublishHandler for StreamingHandler {
+    async fn on_publish(&self, publish: &Publish<'_>) {
+        self.received.borrow_mut().push(publish.topic_name.to_owned());
+    }
+
+    fn stream_filters(&self) -> &[&str] {
+        &["config/#"]
+    }
+
+    async fn on_publish_stream(&self, publish: &StreamedPublish<'_>, payload: &mut impl Read<Error = MqttError>) {
+        let mut data = std::vec::Vec::new();
+        let mut chunk = [0; 50];
+        loop {
+            let n = payload.read(&mut chunk).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            data.extend_from_slice(&chunk[..n]);
+        }
+
+        assert_eq!(publish.len, data.len());
+        self.streamed.borrow_mut().push((publish.topic.to_owned(), data));
+    }
+}
+
+#[tokio::test]
+#[ntest::timeout(1000)]
+async fn test_receive_stream() {
+    let resources = ConnectionRessources::<2048>::new();
+    let (mut client, mut server) = new_connection(&resources);
+    let client = Pin::new(&mut client);
+
+    let config = ClientConfig::new("1234567890", None).unwrap();
+
+    // The streamed payload is larger than the receive buffer
+    let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 64, 64, 2, 2, 2, 2, 2, 16, 0>::new(config).unwrap();
+    let mqtt_client = event_loop.client();
+
+    let payload: std::vec::Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    let handler = StreamingHandler::default();
+
+    let work_future = async {
+        event_loop.run_with_handler(client, &handler).await.unwrap();
+    };
+
+    let server_future = async {
+        server.read_mqtt_packet(|p| assert_eq!(p.get_type(), PacketType::Connect)).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Connack(Connack{
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await.unwrap();
+
+        let pid = Pid::try_from(3).unwrap();
+        let mut bytes = [0; 1100];
+        let n = encode_slice(&Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtLeastOnce(pid),
+            retain: false,
+            topic_name: "config/blob",
+            payload: &payload
+        }), &mut bytes).unwrap();
+        server.write_all(&bytes[..n]).await.unwrap();
+
+        server.read_mqtt_packet(|p| assert_eq!(p, &Packet::Puback(pid))).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Publish(Publish {
+            dup: false,
+            qospid: QosPid::AtMostOnce,
+            retain: false,
+            topic_name: "other",
+            payload: b"small"
+        })).await.unwrap();
+
+        mqtt_client.disconnect().await;
+    };
+
+    tokio::join! {
+        server_future,
+        work_future
+    };
+
+    assert_eq!(handler.streamed.into_inner(), [("config/blob".to_owned(), payload)]);
+    assert_eq!(handler.received.into_inner(), ["other".to_owned()]);
+}
+
+#[tokio::test]
+#[ntest::timeout(1000)]
+async fn test_receive_stream_topic_too_long() {
+    let resources = ConnectionRessources::<2048>::new();
+    let (mut client, mut server) = new_connection(&resources);
+    let client = Pin::new(&mut client);
+
+    let config = ClientConfig::new("1234567890", None).unwrap();
+    let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 64, 64, 2, 2, 2, 2, 2, 16, 0>::new(config).unwrap();
+    let mqtt_client = event_loop.client();
+
+    let payload: std::vec::Vec<u8> = (0..200).map(|i| i as u8).collect();
+    let handler = StreamingHandler::default();
+
+    let work_future = async {
+        event_loop.run_with_handler(client, &handler).await.unwrap();
+    };
+
+    let server_future = async {
+        server.read_mqtt_packet(|p| assert_eq!(p.get_type(), PacketType::Connect)).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Connack(Connack{
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await.unwrap();
+
+        // Both publishes arrive at once, the first is rejected and not acknowledged
+        let mut bytes = [0; 600];
+        let mut n = 0;
+        for (pid, topic) in [(3, "config/too/long/blob"), (4, "config/blob")] {
+            n += encode_slice(&Packet::Publish(Publish {
+                dup: false,
+                qospid: QosPid::AtLeastOnce(Pid::try_from(pid).unwrap()),
+                retain: false,
+                topic_name: topic,
+                payload: &payload
+            }), &mut bytes[n..]).unwrap();
+        }
+        server.write_all(&bytes[..n]).await.unwrap();
+
+        server.read_mqtt_packet(|p| assert_eq!(p, &Packet::Puback(Pid::try_from(4).unwrap()))).await.unwrap();
+
+        mqtt_client.disconnect().await;
+    };
+
+    tokio::join! {
+        server_future,
+        work_future
+    };
+
+    assert_eq!(handler.streamed.into_inner(), [("config/blob".to_owned(), payload)]);
+}
+
+#[tokio::test]
+#[ntest::timeout(1000)]
+async fn test_disconnect_graceful() {
+    let resources = ConnectionRessources::<256>::new();
+
+    let test = Test::create("1234567890", None, &resources);
+    let client = test.create_client();
+
+    let work_future = test.run();
+
+    let client_future = async {
+        let mut events = client.events().unwrap();
+        let id = client.publish_detached("topic", b"acked", QoS::AtLeastOnce, false).await.unwrap();
+
+        let (event, abandoned) = tokio::join!(
+            events.on(|event| event.request_id() == Some(id)),
+            client.disconnect_graceful(Duration::from_secs(1))
+        );
+
+        assert_eq!(event, MqttEvent::PublishResult(id, Ok(())));
+        assert_eq!(abandoned, Ok(0));
+    };
+
+    let server_future = async {
+        test.read_packet(|p| assert_eq!(p.get_type(), PacketType::Connect)).await;
+
+        test.write_packet(Packet::Connack(Connack{
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await;
+
+        let pid = test.read_packet(|p| {
+            if let Packet::Publish(p) = p {
+                p.qospid.pid().unwrap()
+            } else {
+                panic!("expected publish");
+            }
+        }).await;
+
+        // The publish in progress is finished before disconnecting
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        test.write_packet(Packet::Puback(pid)).await;
+
+        test.read_packet(|p| assert_eq!(p, &Packet::Disconnect)).await;
+    };
+
+    tokio::join! {
+        server_future,
+        client_future,
+        work_future
+    };
+}
+
+#[tokio::test]
+#[ntest::timeout(1000)]
+async fn test_disconnect_graceful_timeout() {
+    let resources = ConnectionRessources::<256>::new();
+
+    let test = Test::create("1234567890", None, &resources);
+    let client = test.create_client();
+
+    let work_future = test.run();
+
+    let client_future = async {
+        let mut events = client.events().unwrap();
+        let id = client.publish_detached("topic", b"never acked", QoS::AtLeastOnce, false).await.unwrap();
+
+        let (event, abandoned) = tokio::join!(
+            events.on(|event| event.request_id() == Some(id)),
+            client.disconnect_graceful(Duration::from_millis(100))
+        );
+
+        assert_eq!(event, MqttEvent::PublishResult(id, Err(MqttError::Abandoned)));
+        assert_eq!(abandoned, Ok(1));
+    };
+
+    let server_future = async {
+        test.read_packet(|p| assert_eq!(p.get_type(), PacketType::Connect)).await;
+
+        test.write_packet(Packet::Connack(Connack{
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await;
+
+        test.read_packet(|p| assert_eq!(p.get_type(), PacketType::Publish)).await;
+        test.read_packet(|p| assert_eq!(p, &Packet::Disconnect)).await;
+    };
+
+    tokio::join! {
+        server_future,
+        client_future,
+        work_future
+    };
+}
+
+#[tokio::test]
+#[ntest::timeout(1000)]
+async fn test_disconnect_graceful_connection_fails() {
+    let resources = ConnectionRessources::<256>::new();
+    let (client, server) = new_connection(&resources);
+
+    // Reads fail once the server received the publish
+    let failing = std::cell::Cell::new(false);
+    let faults = |operation| (failing.get() && operation == NetworkOperation::Read).then_some(Fault::Reset);
+    let mut connection = FaultyConnection::new(client, faults, |d: Duration| tokio::time::sleep(d));
+    let connection = Pin::new(&mut connection);
+
+    let config = ClientConfig::new("1234567890", None).unwrap();
+    let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 256>::new(config).unwrap();
+    let mqtt_client = event_loop.client();
+
+    let work_future = async {
+        assert!(matches!(event_loop.run(connection).await, Err(MqttError::ConnectionFailed(_))));
+    };
+
+    let client_future = async {
+        let mut events = mqtt_client.events().unwrap();
+        let id = mqtt_client.publish_detached("topic", b"never acked", QoS::AtLeastOnce, false).await.unwrap();
+
+        let (event, result) = tokio::join!(
+            events.on(|event| event.request_id() == Some(id)),
+            mqtt_client.disconnect_graceful(Duration::from_secs(1))
+        );
+
+        assert_eq!(event, MqttEvent::PublishResult(id, Err(MqttError::Abandoned)));
+        assert!(matches!(result, Err(MqttError::ConnectionFailed(_))));
+    };
+
+    let server_future = async {
+        server.read_mqtt_packet(|p| assert_eq!(p.get_type(), PacketType::Connect)).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Connack(Connack{
+            session_present: false,
+            code: ConnectReturnCode::Accepted
+        })).await.unwrap();
+
+        server.read_mqtt_packet(|p| assert_eq!(p.get_type(), PacketType::Publish)).await.unwrap();
+
+        // Completes the read in progress, the next one fails
+        failing.set(true);
+        server.write_mqtt_packet(&Packet::Pingresp).await.unwrap();
+    };
+
+    tokio::join! {
+        server_future,
+        client_future,
+        work_future
+    };
+
+    // No DISCONNECT is sent on the failed connection
+    server.with_reader(|reader| assert!(reader.is_empty()));
+}
+
+#[tokio::test]
+#[ntest::timeout(2000)]
+async fn test_publish_retransmitted_after_reset() {
+    let resources = ConnectionRessources::<256>::new();
+    let (client, server) = new_connection(&resources);
+
+    // The connection is reset when the publish is written after the connect packet
+    let steps = [FaultStep::new(NetworkOperation::Write, 1, Fault::Reset)];
+    let mut connection = FaultyConnection::new(client, FaultScript::new(&steps), |d: Duration| tokio::time::sleep(d));
+    let connection = Pin::new(&mut connection);
+
+    let mut client_id = heapless::String::new();
+    client_id.push_str("1234567890").unwrap();
+    let config = ClientConfig{
+        client_id,
+        credentials: None,
+        auto_subscribes: Vec::new(),
+        retry_policy: Default::default(),
+        receive_maximum: DEFAULT_RECEIVE_MAXIMUM
+    };
+
+    let event_loop = MqttEventLoop::<CriticalSectionRawMutex, 256>::new(config).unwrap();
+    let mqtt_client = event_loop.client();
+
+    let work_future = async {
+        event_loop.run(connection).await.unwrap();
+    };
+
+    let client_future = async {
+        mqtt_client.publish("topic", b"retransmitted", QoS::AtLeastOnce, false).await.unwrap();
+        mqtt_client.disconnect().await;
+    };
+
+    let server_future = async {
+        for _ in 0..2 {
+            server.read_mqtt_packet(|p| assert_eq!(p.get_type(), PacketType::Connect)).await.unwrap();
+
+            server.write_mqtt_packet(&Packet::Connack(Connack{
+                session_present: true,
+                code: ConnectReturnCode::Accepted
+            })).await.unwrap();
+        }
+
+        let pid = server.read_mqtt_packet(|p| {
+            if let Packet::Publish(p) = p {
+                assert!(p.dup);
+                assert_eq!(p.payload, b"retransmitted");
+                p.qospid.pid().unwrap()
+            } else {
+                panic!("expected publish");
+            }
+        }).await.unwrap();
+
+        server.write_mqtt_packet(&Packet::Puback(pid)).await.unwrap();
+        server.read_mqtt_packet(|p| assert_eq!(p, &Packet::Disconnect)).await.unwrap();
+    };
+
+    tokio::join! {
+        server_future,
+        client_future,
+        work_future
+    };
+}
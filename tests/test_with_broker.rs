@@ -58,24 +58,24 @@ impl BrokerConfig {
         let credentials = match &self.username {
             Some(username) => {
                 let password = self.password.as_ref().unwrap();
-                Some(ClientCredentials::new(username, password))
+                Some(ClientCredentials::new(username, password).unwrap())
             },
             None => None,
         };
 
-        ClientConfig::new(client_id, credentials)
+        ClientConfig::new(client_id, credentials).unwrap()
     }
 
     fn new_client_config_with_auto_subscribe<'a>(&self, client_id: &str, auto_subscribes: impl Iterator<Item = &'a str>, qos: QoS) -> ClientConfig {
         let credentials = match &self.username {
             Some(username) => {
                 let password = self.password.as_ref().unwrap();
-                Some(ClientCredentials::new(username, password))
+                Some(ClientCredentials::new(username, password).unwrap())
             },
             None => None,
         };
 
-        ClientConfig::new_with_auto_subscribes(client_id, credentials, auto_subscribes, qos)
+        ClientConfig::new_with_auto_subscribes(client_id, credentials, auto_subscribes, qos).unwrap()
     }
 
     fn unwrap_port(&self) -> u16 {
@@ -94,7 +94,7 @@ async fn test_broker_publish_qos0() {
     let client_id = Uuid::new_v4().to_string();
     let mqtt_config = broker_config.new_client_config(&client_id);
     let event_loop = 
-        MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(mqtt_config);
+        MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(mqtt_config).unwrap();
 
     let client = event_loop.client();
 
@@ -130,7 +130,7 @@ async fn test_broker_publish_qos1() {
     let client_id = Uuid::new_v4().to_string();
     let mqtt_config = broker_config.new_client_config(&client_id);
     let event_loop = 
-        MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(mqtt_config);
+        MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(mqtt_config).unwrap();
 
     let client = event_loop.client();
 
@@ -166,7 +166,7 @@ async fn test_broker_publish_qos2() {
     let client_id = Uuid::new_v4().to_string();
     let mqtt_config = broker_config.new_client_config(&client_id);
     let event_loop = 
-        MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(mqtt_config);
+        MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(mqtt_config).unwrap();
 
     let client = event_loop.client();
 
@@ -202,7 +202,7 @@ async fn test_broker_subscribe_unsubscribe() {
     let client_id = Uuid::new_v4().to_string();
     let mqtt_config = broker_config.new_client_config(&client_id);
     let event_loop = 
-        MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(mqtt_config);
+        MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(mqtt_config).unwrap();
 
     let client = event_loop.client();
 
@@ -258,7 +258,7 @@ async fn test_broker_subscribe() {
     let client_id = Uuid::new_v4().to_string();
     let mqtt_config = broker_config.new_client_config(&client_id);
     let event_loop = 
-        MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(mqtt_config);
+        MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(mqtt_config).unwrap();
 
     let client = event_loop.client();
 
@@ -305,7 +305,7 @@ async fn test_broker_publish() {
     let client_id = Uuid::new_v4().to_string();
     let mqtt_config = broker_config.new_client_config(&client_id);
     let event_loop = 
-        MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(mqtt_config);
+        MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(mqtt_config).unwrap();
 
     let client = event_loop.client();
 
@@ -370,7 +370,7 @@ async fn test_auto_subscribe() {
         QoS::AtLeastOnce
     );
     let event_loop = 
-        MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(mqtt_config);
+        MqttEventLoop::<CriticalSectionRawMutex, 1024>::new(mqtt_config).unwrap();
 
     let client = event_loop.client();
 
@@ -391,7 +391,8 @@ async fn test_auto_subscribe() {
 
     let (publish_client, _, cancel_token) = create_sinple_client(&broker_config);
 
-    let initial_auto_subscribe_success_future = client.on(|event| *event == MqttEvent::InitialSubscribesDone);
+    let mut events = client.events().unwrap();
+    let initial_auto_subscribe_success_future = events.on(|event| *event == MqttEvent::InitialSubscribesDone);
     let publish_future = async {
         initial_auto_subscribe_success_future.await;
         tracing::debug!("TEST: publish: stop waiting, initial subscribes done");